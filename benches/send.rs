@@ -0,0 +1,130 @@
+//! Throughput of the send path, run with `cargo bench`.
+//!
+//! Scenarios can be tuned with environment variables, each a comma separated list:
+//!
+//! - `PACKET_IPC_BENCH_PACKETS`: packets per scenario, default 100000
+//! - `PACKET_IPC_BENCH_SIZES`: packet sizes in bytes, default 64,512,1500
+//! - `PACKET_IPC_BENCH_BATCHES`: packets per batch, default 1,64,1024
+//!
+//! Only scenarios whose name contains the first argument are run, e.g.
+//! `cargo bench -- send/raw`.
+
+use packet_ipc::testing::{Generator, SizeDistribution};
+use packet_ipc::{
+    AsIpcPacket, BatchBuilder, Client, ClientConfig, Packet, Server, ServerConfig, WireFormat,
+};
+use std::time::{Duration, Instant};
+
+const WIRE_FORMATS: &[(&str, WireFormat)] = &[
+    ("bincode", WireFormat::Bincode),
+    ("nanos", WireFormat::NanosTimestamps),
+    ("raw", WireFormat::Raw),
+];
+
+fn setting(var: &str, default: &[usize]) -> Vec<usize> {
+    match std::env::var(var) {
+        Ok(value) => value
+            .split(',')
+            .map(|v| {
+                v.trim()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{} must be a list of numbers", var))
+            })
+            .collect(),
+        Err(_) => default.to_vec(),
+    }
+}
+
+fn packets(count: usize, size: usize) -> Vec<Packet> {
+    Generator::new()
+        .sizes(SizeDistribution::Fixed(size))
+        .flows(64)
+        .limit(count as u64)
+        .collect()
+}
+
+fn report(name: &str, packets: usize, bytes: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "{:<40} {:>10.0} ns/packet {:>12.0} packets/s {:>10.1} MB/s",
+        name,
+        elapsed.as_nanos() as f64 / packets as f64,
+        packets as f64 / seconds,
+        bytes as f64 / seconds / 1_000_000.0
+    );
+}
+
+/// Encoding alone, the per packet cost on the capture thread.
+fn encode(name: &str, wire_format: WireFormat, packets: &[Packet]) {
+    let bytes = packets.iter().map(|p| p.data().len()).sum();
+    let started = Instant::now();
+    let mut batch = BatchBuilder::new().wire_format(wire_format);
+    for packet in packets {
+        batch.push(packet).expect("Failed to encode");
+    }
+    report(name, packets.len(), bytes, started.elapsed());
+}
+
+/// Sending to a client receiving on another thread, either copying packets out of each batch or
+/// borrowing them with views.
+fn send(name: &str, wire_format: WireFormat, packets: &[Packet], batch: usize, views: bool) {
+    let server = Server::new()
+        .expect("Failed to create server")
+        .with_config(ServerConfig::default().wire_format(wire_format));
+    let server_name = server.name().clone();
+    let client = std::thread::spawn(move || {
+        let config = ClientConfig::default().packet_views(views);
+        let mut client = Client::connect(server_name, config).expect("Failed to connect");
+        let mut received = 0;
+        if views {
+            while let Some(batch) = client.recv_views().expect("Failed to receive") {
+                received += batch.views().expect("Failed to decode").len();
+            }
+        } else {
+            while let Some(packets) = client.recv(usize::MAX).expect("Failed to receive") {
+                received += packets.len();
+            }
+        }
+        received
+    });
+    let mut connection = server.accept().expect("Failed to accept");
+
+    let bytes = packets.iter().map(|p| p.data().len()).sum();
+    let started = Instant::now();
+    for chunk in packets.chunks(batch) {
+        connection.send(chunk).expect("Failed to send");
+    }
+    connection.close().expect("Failed to close");
+    let received = client.join().expect("Client panicked");
+    report(name, packets.len(), bytes, started.elapsed());
+    assert_eq!(received, packets.len());
+}
+
+fn main() {
+    let filter = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_default();
+    let count = setting("PACKET_IPC_BENCH_PACKETS", &[100_000])[0];
+    let sizes = setting("PACKET_IPC_BENCH_SIZES", &[64, 512, 1500]);
+    let batches = setting("PACKET_IPC_BENCH_BATCHES", &[1, 64, 1024]);
+
+    for &size in &sizes {
+        let packets = packets(count, size);
+        for &(format, wire_format) in WIRE_FORMATS {
+            let name = format!("encode/{}/{}B", format, size);
+            if name.contains(&filter) {
+                encode(&name, wire_format, &packets);
+            }
+            for &batch in &batches {
+                for &views in &[false, true] {
+                    let receive = if views { "views" } else { "copy" };
+                    let name = format!("send/{}/{}/{}B/{}", format, receive, size, batch);
+                    if name.contains(&filter) {
+                        send(&name, wire_format, &packets, batch.max(1), views);
+                    }
+                }
+            }
+        }
+    }
+}
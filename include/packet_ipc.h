@@ -0,0 +1,122 @@
+/*
+ * C bindings for packet-ipc, available when built with the `capi` feature.
+ *
+ * Kept in sync with src/capi.rs; regenerate with
+ *   cbindgen --config cbindgen.toml --output include/packet_ipc.h
+ */
+
+#ifndef PACKET_IPC_H
+#define PACKET_IPC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/* Returned when a call completed successfully. */
+#define PACKET_IPC_OK 0
+/* Returned by receive calls once the connection has closed and all packets were received. */
+#define PACKET_IPC_CLOSED 1
+/* Returned when an argument was null or otherwise invalid. */
+#define PACKET_IPC_INVALID_ARGUMENT -1
+/* Returned when the underlying operation failed. */
+#define PACKET_IPC_ERROR -2
+
+/* Opaque handle to a connected client. */
+typedef struct PacketIpcClient PacketIpcClient;
+
+/* Opaque handle to a server's connection to a client. */
+typedef struct PacketIpcConnection PacketIpcConnection;
+
+/* Opaque handle to a server waiting for a client. */
+typedef struct PacketIpcServer PacketIpcServer;
+
+/* Describes a packet copied into a caller provided buffer. */
+typedef struct PacketIpcPacket {
+  /* Seconds since the unix epoch. */
+  uint64_t ts_sec;
+  /* Nanoseconds within the second. */
+  uint32_t ts_nsec;
+  /* Length of the packet data. */
+  uint32_t len;
+  /* Offset of the packet data in the caller's buffer. */
+  size_t offset;
+} PacketIpcPacket;
+
+/* Describes a packet for the server to send. */
+typedef struct PacketIpcSendPacket {
+  /* Seconds since the unix epoch. */
+  uint64_t ts_sec;
+  /* Nanoseconds within the second. */
+  uint32_t ts_nsec;
+  /* Packet data. */
+  const uint8_t *data;
+  /* Length of the packet data. */
+  size_t len;
+} PacketIpcSendPacket;
+
+/* Connect a client to the server named `server_name`, returning null on failure. */
+PacketIpcClient *packet_ipc_client_new(const char *server_name);
+
+/*
+ * Receive up to `max_packets` packets, blocking until at least one is available. Packet data is
+ * copied back to back into `buffer`, and each packet is described in `packets`. The number of
+ * packets received is written to `received`. Packets that do not fit in `buffer` are kept for
+ * the next call; a single packet larger than `buffer` is truncated to fit.
+ *
+ * Returns PACKET_IPC_OK when packets were received, or PACKET_IPC_CLOSED once the server has
+ * closed the connection and every packet has been received.
+ */
+int packet_ipc_client_recv(PacketIpcClient *client,
+                           PacketIpcPacket *packets,
+                           size_t max_packets,
+                           uint8_t *buffer,
+                           size_t buffer_len,
+                           size_t *received);
+
+/*
+ * Disconnect from the server. Packets already received can still be read with
+ * packet_ipc_client_recv, after which it returns PACKET_IPC_CLOSED.
+ */
+int packet_ipc_client_close(PacketIpcClient *client);
+
+/* Free a client, disconnecting it if still connected. */
+void packet_ipc_client_free(PacketIpcClient *client);
+
+/*
+ * Create a server, returning null on failure. Clients connect using the name returned by
+ * packet_ipc_server_name.
+ */
+PacketIpcServer *packet_ipc_server_new(void);
+
+/*
+ * Name clients use to connect to the server. The string is owned by the server and valid until
+ * it is accepted or freed.
+ */
+const char *packet_ipc_server_name(const PacketIpcServer *server);
+
+/*
+ * Block until a client connects, returning the connection or null on failure. The server is
+ * consumed either way and must not be used afterwards.
+ */
+PacketIpcConnection *packet_ipc_server_accept(PacketIpcServer *server);
+
+/* Free a server that was not accepted. */
+void packet_ipc_server_free(PacketIpcServer *server);
+
+/*
+ * Send `count` packets to the client as a single batch. Fails with PACKET_IPC_INVALID_ARGUMENT
+ * if a timestamp is out of range.
+ */
+int packet_ipc_send(PacketIpcConnection *connection,
+                    const PacketIpcSendPacket *packets,
+                    size_t count);
+
+/* Tell the client no more packets will be sent. */
+int packet_ipc_close(PacketIpcConnection *connection);
+
+/*
+ * Free a connection. Call packet_ipc_close first so the client knows the connection ended
+ * normally.
+ */
+void packet_ipc_connection_free(PacketIpcConnection *connection);
+
+#endif /* PACKET_IPC_H */
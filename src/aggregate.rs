@@ -0,0 +1,164 @@
+use crate::packet::AsIpcPacket;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime};
+
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+pub(crate) const ETHERTYPE_QINQ: u16 = 0x88a8;
+pub(crate) const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// The 5-tuple identifying a flow.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct FlowKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+}
+
+impl FlowKey {
+    /// Parse the flow key from an ethernet frame, returning `None` for non IP traffic or
+    /// truncated headers. Ports are zero for protocols other than TCP and UDP.
+    pub fn from_ethernet(frame: &[u8]) -> Option<FlowKey> {
+        let mut offset = 12;
+        let mut ethertype = read_u16(frame, offset)?;
+        while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+            offset += 4;
+            ethertype = read_u16(frame, offset)?;
+        }
+        let ip = frame.get(offset + 2..)?;
+        match ethertype {
+            ETHERTYPE_IPV4 => Self::from_ipv4(ip),
+            ETHERTYPE_IPV6 => Self::from_ipv6(ip),
+            _ => None,
+        }
+    }
+
+    fn from_ipv4(ip: &[u8]) -> Option<FlowKey> {
+        let header_len = (*ip.first()? as usize & 0x0f) * 4;
+        let addrs = ip.get(12..20)?;
+        let src = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+        let dst = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+        let protocol = ip[9];
+        Some(Self::with_ports(
+            src.into(),
+            dst.into(),
+            protocol,
+            ip.get(header_len..)?,
+        ))
+    }
+
+    fn from_ipv6(ip: &[u8]) -> Option<FlowKey> {
+        let addrs = ip.get(8..40)?;
+        let mut src = [0u8; 16];
+        let mut dst = [0u8; 16];
+        src.copy_from_slice(&addrs[..16]);
+        dst.copy_from_slice(&addrs[16..]);
+        Some(Self::with_ports(
+            Ipv6Addr::from(src).into(),
+            Ipv6Addr::from(dst).into(),
+            ip[6],
+            &ip[40..],
+        ))
+    }
+
+    fn with_ports(src: IpAddr, dst: IpAddr, protocol: u8, transport: &[u8]) -> FlowKey {
+        let (src_port, dst_port) = match protocol {
+            PROTOCOL_TCP | PROTOCOL_UDP => (
+                read_u16(transport, 0).unwrap_or(0),
+                read_u16(transport, 2).unwrap_or(0),
+            ),
+            _ => (0, 0),
+        };
+        FlowKey {
+            src,
+            dst,
+            src_port,
+            dst_port,
+            protocol,
+        }
+    }
+}
+
+/// Summary of the packets seen for a flow.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FlowRecord {
+    pub key: FlowKey,
+    pub packets: u64,
+    pub bytes: u64,
+    pub first: SystemTime,
+    pub last: SystemTime,
+}
+
+/// Folds packets into per flow records, which can be sent with `ConnectedIpc::send_flows`
+/// instead of the packets themselves.
+#[derive(Debug, Default)]
+pub struct FlowAggregator {
+    flows: HashMap<FlowKey, FlowRecord>,
+}
+
+impl FlowAggregator {
+    pub fn new() -> FlowAggregator {
+        FlowAggregator::default()
+    }
+
+    /// Add a packet to its flow. Returns false if the packet is not IP traffic.
+    pub fn add<T: AsIpcPacket + ?Sized>(&mut self, packet: &T) -> bool {
+        let key = match FlowKey::from_ethernet(packet.data()) {
+            None => return false,
+            Some(key) => key,
+        };
+        let ts = *packet.timestamp();
+        let len = packet.data().len() as u64;
+        let record = self.flows.entry(key).or_insert_with(|| FlowRecord {
+            key,
+            packets: 0,
+            bytes: 0,
+            first: ts,
+            last: ts,
+        });
+        record.packets += 1;
+        record.bytes += len;
+        record.first = record.first.min(ts);
+        record.last = record.last.max(ts);
+        true
+    }
+
+    /// Number of flows currently being aggregated.
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Remove and return flows with no packets for at least `idle` before `now`.
+    pub fn take_idle(&mut self, now: SystemTime, idle: Duration) -> Vec<FlowRecord> {
+        let idle_keys: Vec<_> = self
+            .flows
+            .values()
+            .filter(|r| now.duration_since(r.last).unwrap_or_default() >= idle)
+            .map(|r| r.key)
+            .collect();
+        idle_keys
+            .into_iter()
+            .filter_map(|k| self.flows.remove(&k))
+            .collect()
+    }
+
+    /// Remove and return every flow.
+    pub fn take_all(&mut self) -> Vec<FlowRecord> {
+        self.flows.drain().map(|(_, r)| r).collect()
+    }
+}
@@ -0,0 +1,143 @@
+use crate::packet::AsIpcPacket;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Supplies the buffers received payloads are copied into, in place of allocating a new `Vec`
+/// for every packet.
+pub trait PayloadAllocator: fmt::Debug + Send + Sync {
+    /// An empty buffer able to hold at least `len` bytes.
+    fn allocate(&self, len: usize) -> Vec<u8>;
+
+    /// Hand back a buffer that is no longer needed, e.g. from `Packet::into_data`.
+    fn release(&self, _buffer: Vec<u8>) {}
+}
+
+/// Recycles released payload buffers.
+///
+/// Buffers are allocated with at least `buffer_size` capacity so most payloads fit any recycled
+/// buffer. At most `max_buffers` released buffers are kept.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_buffers: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            buffer_size,
+            max_buffers,
+        }
+    }
+
+    /// Allocate `count` buffers up front.
+    pub fn with_preallocated(buffer_size: usize, max_buffers: usize, count: usize) -> BufferPool {
+        let pool = BufferPool::new(buffer_size, max_buffers);
+        for _ in 0..usize::min(count, max_buffers) {
+            pool.release(Vec::with_capacity(buffer_size));
+        }
+        pool
+    }
+
+    /// Number of buffers waiting to be reused.
+    pub fn available(&self) -> usize {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+impl PayloadAllocator for BufferPool {
+    fn allocate(&self, len: usize) -> Vec<u8> {
+        let recycled = self.buffers.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        match recycled {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer.reserve(len);
+                buffer
+            }
+            None => Vec::with_capacity(usize::max(len, self.buffer_size)),
+        }
+    }
+
+    fn release(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|e| e.into_inner());
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Consumer owned storage that `Client::recv_into` copies received packets into, so a receive
+/// loop can reuse the same buffers for every batch.
+#[derive(Debug, Default)]
+pub struct BufferSet {
+    data: Vec<u8>,
+    packets: Vec<(SystemTime, Range<usize>)>,
+    max_packets: usize,
+}
+
+impl BufferSet {
+    /// Hold up to `max_packets` per receive, preallocating `bytes` of payload storage.
+    pub fn new(max_packets: usize, bytes: usize) -> BufferSet {
+        BufferSet {
+            data: Vec::with_capacity(bytes),
+            packets: Vec::with_capacity(max_packets),
+            max_packets,
+        }
+    }
+
+    /// Most packets a single receive will store.
+    pub fn max_packets(&self) -> usize {
+        self.max_packets
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.packets.clear();
+    }
+
+    pub fn get(&self, index: usize) -> Option<BufferedPacket<'_>> {
+        self.packets.get(index).map(|(ts, range)| BufferedPacket {
+            ts: *ts,
+            data: &self.data[range.clone()],
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BufferedPacket<'_>> {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+
+    pub(crate) fn push<T: AsIpcPacket + ?Sized>(&mut self, packet: &T) {
+        let start = self.data.len();
+        self.data.extend_from_slice(packet.data());
+        self.packets
+            .push((*packet.timestamp(), start..self.data.len()));
+    }
+}
+
+/// A packet stored in a `BufferSet`.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferedPacket<'a> {
+    ts: SystemTime,
+    data: &'a [u8],
+}
+
+impl<'a> AsIpcPacket for BufferedPacket<'a> {
+    fn timestamp(&self) -> &SystemTime {
+        &self.ts
+    }
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+}
@@ -0,0 +1,666 @@
+use crate::alloc::PayloadAllocator;
+use crate::errors::Error;
+use crate::fingerprint::Fingerprint;
+use crate::message::{BatchHeader, BatchInfo, EncodedBatch, Message, Priority, WireFormat};
+use crate::packet::{AsIpcPacket, IpcPacket, Packet};
+use crate::server::ConnectedIpc;
+use bincode::Options;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+pub(crate) fn encoding_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// Builds a batch of packets, encoding each packet as it is pushed so that flushing the batch
+/// only needs to send the already encoded bytes.
+#[derive(Debug, Default)]
+pub struct BatchBuilder {
+    data: Vec<u8>,
+    count: usize,
+    first: Option<SystemTime>,
+    last: Option<SystemTime>,
+    wire_format: WireFormat,
+    fingerprint: Option<Fingerprint>,
+    fingerprints: Vec<u64>,
+    interfaces: Vec<Option<u32>>,
+    segments: Vec<u32>,
+    orig_lens: Vec<Option<u32>>,
+    on_error: EncodeErrorPolicy,
+    /// Packets skipped since the last flush.
+    skipped: u64,
+}
+
+/// What to do when a packet can't be encoded, e.g. a `WireFormat::Bincode` timestamp before
+/// the UNIX epoch.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EncodeErrorPolicy {
+    /// Fail the push, and so the whole send.
+    #[default]
+    FailBatch,
+    /// Leave the packet out and carry on, reporting it to the client as dropped with
+    /// `DropReason::EncodeFailed` when the batch is flushed. With `log`, each skipped packet is
+    /// logged along with an FNV-1a hash of its payload, to find it again in a capture.
+    SkipPacket { log: bool },
+}
+
+/// A packet as encoded for `WireFormat::NanosTimestamps`.
+#[derive(Deserialize, Serialize)]
+struct NanosPacket<'a> {
+    timestamp: u64,
+    #[serde(with = "serde_bytes")]
+    data: &'a [u8],
+}
+
+impl<'a> NanosPacket<'a> {
+    fn new<T: AsIpcPacket + ?Sized>(packet: &'a T) -> Result<Self, Error> {
+        Ok(NanosPacket {
+            timestamp: timestamp_nanos(packet.timestamp())?,
+            data: packet.data(),
+        })
+    }
+
+    fn timestamp(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(self.timestamp)
+    }
+}
+
+/// Bytes ahead of each packet's data in `WireFormat::Raw`.
+const RAW_HEADER_LEN: usize = 8 + 4 + 4 + 4;
+
+fn timestamp_error(ts: &SystemTime) -> Error {
+    Error::Bincode(Box::new(bincode::ErrorKind::Custom(format!(
+        "Timestamp {:?} is out of range for the wire format",
+        ts
+    ))))
+}
+
+fn since_epoch(ts: &SystemTime) -> Result<Duration, Error> {
+    ts.duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| timestamp_error(ts))
+}
+
+fn timestamp_nanos(ts: &SystemTime) -> Result<u64, Error> {
+    u64::try_from(since_epoch(ts)?.as_nanos()).map_err(|_| timestamp_error(ts))
+}
+
+fn raw_len(len: usize) -> Result<u32, Error> {
+    u32::try_from(len).map_err(|_| {
+        Error::Bincode(Box::new(bincode::ErrorKind::Custom(format!(
+            "Packet of {} bytes is too large for a raw frame",
+            len
+        ))))
+    })
+}
+
+fn push_raw<T: AsIpcPacket + ?Sized>(data: &mut Vec<u8>, packet: &T) -> Result<(), Error> {
+    let payload = packet.data();
+    let len = raw_len(payload.len())?;
+    let nanos = timestamp_nanos(packet.timestamp())?;
+    data.reserve(RAW_HEADER_LEN + payload.len());
+    data.extend_from_slice(&nanos.to_le_bytes());
+    data.extend_from_slice(&len.to_le_bytes());
+    data.extend_from_slice(&packet.orig_len().unwrap_or(len).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Read the raw frame at `offset`, moving `offset` past it. The original length is only kept
+/// when it differs from the captured length.
+fn read_raw<'a>(
+    data: &'a [u8],
+    offset: &mut usize,
+) -> bincode::Result<(SystemTime, &'a [u8], Option<u32>)> {
+    let truncated = || Box::new(bincode::ErrorKind::Custom("Truncated raw frame".to_owned()));
+    let header = data
+        .get(*offset..*offset + RAW_HEADER_LEN)
+        .ok_or_else(truncated)?;
+    let field = |at: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&header[at..at + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    let (caplen, origlen, flags) = (field(8), field(12), field(16));
+    if flags != 0 {
+        return Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "Unknown raw frame flags {:#x}",
+            flags
+        ))));
+    }
+    let mut nanos = [0u8; 8];
+    nanos.copy_from_slice(&header[..8]);
+    let start = *offset + RAW_HEADER_LEN;
+    let end = start + caplen as usize;
+    let payload = data.get(start..end).ok_or_else(truncated)?;
+    *offset = end;
+    let ts = SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::from_le_bytes(nanos));
+    Ok((ts, payload, Some(origlen).filter(|_| origlen != caplen)))
+}
+
+impl BatchBuilder {
+    pub fn new() -> BatchBuilder {
+        BatchBuilder::default()
+    }
+
+    /// Preallocate `bytes` of encoding buffer.
+    pub fn with_capacity(bytes: usize) -> BatchBuilder {
+        BatchBuilder {
+            data: Vec::with_capacity(bytes),
+            ..BatchBuilder::default()
+        }
+    }
+
+    /// Encode for connections that negotiated `wire_format`, rather than `WireFormat::Bincode`.
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
+    /// Compute a fingerprint of each packet pushed, sent with the batch.
+    pub fn fingerprint(mut self, fingerprint: Option<Fingerprint>) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// What to do with packets that can't be encoded, defaults to failing.
+    pub fn on_encode_error(mut self, policy: EncodeErrorPolicy) -> Self {
+        self.on_error = policy;
+        self
+    }
+
+    /// Packets skipped since the last flush because they couldn't be encoded.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn push<T: AsIpcPacket + ?Sized>(&mut self, packet: &T) -> Result<(), Error> {
+        let start = self.data.len();
+        let encoded = match self.wire_format {
+            WireFormat::Bincode => encoding_options()
+                .serialize_into(&mut self.data, &IpcPacket::from(packet))
+                .map_err(Error::Bincode),
+            WireFormat::NanosTimestamps => NanosPacket::new(packet).and_then(|packet| {
+                encoding_options()
+                    .serialize_into(&mut self.data, &packet)
+                    .map_err(Error::Bincode)
+            }),
+            WireFormat::Raw => push_raw(&mut self.data, packet),
+        };
+        if let Err(e) = encoded {
+            self.data.truncate(start);
+            return self.encode_failed(packet.data(), e);
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            self.fingerprints.push(fingerprint.compute(packet.data()));
+        }
+        self.push_interface(packet.interface());
+        self.push_segments(packet.segments());
+        self.push_orig_len(packet.orig_len());
+        self.count += 1;
+        let ts = *packet.timestamp();
+        self.first.get_or_insert(ts);
+        self.last = Some(ts);
+        Ok(())
+    }
+
+    /// Make room for a packet of up to `len` bytes, to be written in place with `BatchSlot::data`
+    /// and added to the batch by `BatchSlot::commit`. Dropping the slot without committing
+    /// leaves the batch as it was.
+    pub fn reserve(&mut self, len: usize) -> Result<BatchSlot<'_>, Error> {
+        BatchSlot::new(SlotBuilder::Batch(self), len)
+    }
+
+    /// Skip or fail on a packet that couldn't be encoded, according to `on_encode_error`.
+    fn encode_failed(&mut self, payload: &[u8], e: Error) -> Result<(), Error> {
+        match self.on_error {
+            EncodeErrorPolicy::FailBatch => Err(e),
+            EncodeErrorPolicy::SkipPacket { log } => {
+                if log {
+                    warn!(
+                        "Skipping packet of {} bytes with hash {:016x}: {}",
+                        payload.len(),
+                        Fingerprint::headers(usize::MAX).compute(payload),
+                        e
+                    );
+                }
+                self.skipped += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn commit_reserved(
+        &mut self,
+        start: usize,
+        ts: SystemTime,
+        caplen: usize,
+    ) -> Result<(), Error> {
+        let header_len = header_len(self.wire_format);
+        self.data.truncate(start + header_len + caplen);
+        // Lengths were checked to fit when the slot was reserved
+        let len = caplen as u64;
+        let header = &mut self.data[start..start + header_len];
+        let encoded = match self.wire_format {
+            WireFormat::Bincode => since_epoch(&ts).map(|since_epoch| {
+                header[..8].copy_from_slice(&since_epoch.as_secs().to_le_bytes());
+                header[8..12].copy_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+                header[12..20].copy_from_slice(&len.to_le_bytes());
+            }),
+            WireFormat::NanosTimestamps => timestamp_nanos(&ts).map(|nanos| {
+                header[..8].copy_from_slice(&nanos.to_le_bytes());
+                header[8..16].copy_from_slice(&len.to_le_bytes());
+            }),
+            WireFormat::Raw => timestamp_nanos(&ts).map(|nanos| {
+                header[..8].copy_from_slice(&nanos.to_le_bytes());
+                header[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+                header[12..16].copy_from_slice(&(len as u32).to_le_bytes());
+                header[16..20].copy_from_slice(&0u32.to_le_bytes());
+            }),
+        };
+        if let Err(e) = encoded {
+            let payload = self.data.split_off(start + header_len);
+            self.data.truncate(start);
+            return self.encode_failed(&payload, e);
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            let payload = &self.data[start + header_len..];
+            self.fingerprints.push(fingerprint.compute(payload));
+        }
+        self.push_interface(None);
+        self.push_segments(1);
+        self.push_orig_len(None);
+        self.count += 1;
+        self.first.get_or_insert(ts);
+        self.last = Some(ts);
+        Ok(())
+    }
+
+    /// Interfaces are only kept once a packet has one, as `None` for the packets before it.
+    fn push_interface(&mut self, interface: Option<u32>) {
+        if interface.is_some() && self.interfaces.is_empty() {
+            self.interfaces.resize(self.count, None);
+        }
+        if interface.is_some() || !self.interfaces.is_empty() {
+            self.interfaces.push(interface);
+        }
+    }
+
+    /// Segment counts are only kept once a packet was coalesced, as one for the packets before.
+    fn push_segments(&mut self, segments: u32) {
+        if segments != 1 && self.segments.is_empty() {
+            self.segments.resize(self.count, 1);
+        }
+        if segments != 1 || !self.segments.is_empty() {
+            self.segments.push(segments);
+        }
+    }
+
+    /// Original lengths are only kept once a packet was truncated, as `None` for the packets
+    /// before it.
+    fn push_orig_len(&mut self, orig_len: Option<u32>) {
+        if orig_len.is_some() && self.orig_lens.is_empty() {
+            self.orig_lens.resize(self.count, None);
+        }
+        if orig_len.is_some() || !self.orig_lens.is_empty() {
+            self.orig_lens.push(orig_len);
+        }
+    }
+
+    /// Number of packets in the batch.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Number of encoded bytes in the batch.
+    pub fn encoded_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Send the encoded packets to the connection, leaving the builder empty. Does nothing if
+    /// no packets have been pushed, and fails with `Error::WireFormatMismatch` if the connection
+    /// negotiated a different wire format than the builder encodes.
+    pub fn flush(&mut self, connection: &ConnectedIpc) -> Result<(), Error> {
+        self.flush_with_priority(connection, Priority::Normal)
+    }
+
+    /// Flush the batch on the given priority lane.
+    pub fn flush_with_priority(
+        &mut self,
+        connection: &ConnectedIpc,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let negotiated = connection.info().wire_format();
+        if negotiated != self.wire_format {
+            return Err(Error::WireFormatMismatch {
+                batch: self.wire_format,
+                connection: negotiated,
+            });
+        }
+        let skipped = std::mem::take(&mut self.skipped);
+        if skipped > 0 {
+            connection.record_encode_failures(skipped);
+        }
+        if self.is_empty() {
+            return Ok(());
+        }
+        let batch = self.take(priority);
+        connection.send_message(Message::Batch(batch))
+    }
+
+    pub(crate) fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub(crate) fn take(&mut self, priority: Priority) -> EncodedBatch {
+        let capacity = self.data.capacity();
+        let data = std::mem::replace(&mut self.data, Vec::with_capacity(capacity));
+        let count = std::mem::replace(&mut self.count, 0);
+        EncodedBatch {
+            header: BatchHeader::new(count, data.len()),
+            priority,
+            first: self.first.take(),
+            last: self.last.take(),
+            data,
+            fingerprints: std::mem::take(&mut self.fingerprints),
+            interfaces: std::mem::take(&mut self.interfaces),
+            segments: std::mem::take(&mut self.segments),
+            orig_lens: std::mem::take(&mut self.orig_lens),
+        }
+    }
+}
+
+/// Bytes ahead of each packet's data, with the data length as the last field.
+fn header_len(wire_format: WireFormat) -> usize {
+    match wire_format {
+        // Seconds and nanoseconds of the timestamp, then the data length
+        WireFormat::Bincode => 8 + 4 + 8,
+        WireFormat::NanosTimestamps => 8 + 8,
+        WireFormat::Raw => RAW_HEADER_LEN,
+    }
+}
+
+enum SlotBuilder<'a> {
+    Batch(&'a mut BatchBuilder),
+    Connection(BatchBuilder, &'a ConnectedIpc),
+}
+
+impl<'a> SlotBuilder<'a> {
+    fn builder(&mut self) -> &mut BatchBuilder {
+        match self {
+            SlotBuilder::Batch(builder) => builder,
+            SlotBuilder::Connection(builder, _) => builder,
+        }
+    }
+}
+
+/// Room for one packet's data in a batch's encoding buffer, from `BatchBuilder::reserve` or
+/// `ConnectedIpc::reserve`, so a packet can be copied straight from e.g. a DMA region without
+/// building a `Packet` first.
+pub struct BatchSlot<'a> {
+    builder: SlotBuilder<'a>,
+    start: usize,
+    len: usize,
+    committed: bool,
+}
+
+impl<'a> BatchSlot<'a> {
+    fn new(mut builder: SlotBuilder<'a>, len: usize) -> Result<BatchSlot<'a>, Error> {
+        let batch = builder.builder();
+        if batch.wire_format == WireFormat::Raw {
+            raw_len(len)?;
+        }
+        let start = batch.data.len();
+        batch
+            .data
+            .resize(start + header_len(batch.wire_format) + len, 0);
+        Ok(BatchSlot {
+            builder,
+            start,
+            len,
+            committed: false,
+        })
+    }
+
+    pub(crate) fn for_connection(
+        builder: BatchBuilder,
+        connection: &'a ConnectedIpc,
+        len: usize,
+    ) -> Result<BatchSlot<'a>, Error> {
+        BatchSlot::new(SlotBuilder::Connection(builder, connection), len)
+    }
+
+    /// Bytes reserved.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The reserved bytes to write the packet's data into, initially zeroed.
+    pub fn data(&mut self) -> &mut [u8] {
+        let start = self.start;
+        let batch = self.builder.builder();
+        let header_len = header_len(batch.wire_format);
+        &mut batch.data[start + header_len..]
+    }
+
+    /// Add the packet captured at `ts`, keeping the first `caplen` bytes written, at most the
+    /// bytes reserved. A slot from `ConnectedIpc::reserve` is sent straight away. A timestamp
+    /// the wire format can't encode is handled the same as by `BatchBuilder::push`.
+    pub fn commit(mut self, ts: SystemTime, caplen: usize) -> Result<(), Error> {
+        self.committed = true;
+        let caplen = caplen.min(self.len);
+        let start = self.start;
+        self.builder.builder().commit_reserved(start, ts, caplen)?;
+        match &mut self.builder {
+            SlotBuilder::Batch(_) => Ok(()),
+            SlotBuilder::Connection(builder, connection) => builder.flush(connection),
+        }
+    }
+}
+
+impl<'a> Drop for BatchSlot<'a> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let start = self.start;
+            self.builder.builder().data.truncate(start);
+        }
+    }
+}
+
+/// Options used to encode a `SerializedBatch`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncodeOptions {
+    wire_format: WireFormat,
+    priority: Priority,
+}
+
+impl EncodeOptions {
+    /// Encode for connections that negotiated `wire_format`.
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// A batch encoded once that can be sent to any number of connections using the same wire
+/// format, without encoding the packets again.
+#[derive(Clone, Debug)]
+pub struct SerializedBatch {
+    batch: EncodedBatch,
+    wire_format: WireFormat,
+}
+
+impl SerializedBatch {
+    pub fn encode<T: AsIpcPacket>(
+        packets: &[T],
+        options: EncodeOptions,
+    ) -> Result<SerializedBatch, Error> {
+        let mut builder = BatchBuilder::new().wire_format(options.wire_format);
+        for packet in packets {
+            builder.push(packet)?;
+        }
+        Ok(SerializedBatch {
+            batch: builder.take(options.priority),
+            wire_format: options.wire_format,
+        })
+    }
+
+    /// Number of packets in the batch.
+    pub fn len(&self) -> usize {
+        self.batch.header.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.header.count == 0
+    }
+
+    /// Number of encoded bytes in the batch.
+    pub fn encoded_len(&self) -> usize {
+        self.batch.data.len()
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    pub fn info(&self) -> BatchInfo {
+        self.batch.info()
+    }
+
+    /// Send the batch to `connection`, failing with `Error::WireFormatMismatch` if the connection
+    /// negotiated a different wire format. Does nothing if the batch is empty.
+    pub fn send(&self, connection: &ConnectedIpc) -> Result<(), Error> {
+        let negotiated = connection.info().wire_format();
+        if negotiated != self.wire_format {
+            return Err(Error::WireFormatMismatch {
+                batch: self.wire_format,
+                connection: negotiated,
+            });
+        }
+        if self.is_empty() {
+            return Ok(());
+        }
+        connection.send_message(Message::Batch(self.batch.clone()))
+    }
+}
+
+pub(crate) fn decode_batch(
+    batch: &EncodedBatch,
+    wire_format: WireFormat,
+    allocator: Option<&dyn PayloadAllocator>,
+) -> Result<Vec<Packet>, Error> {
+    let packets = decode_packets(&batch.data, batch.header.count, wire_format, allocator)?;
+    let has_metadata = [
+        batch.fingerprints.len(),
+        batch.interfaces.len(),
+        batch.segments.len(),
+        batch.orig_lens.len(),
+    ]
+    .contains(&packets.len());
+    if !has_metadata {
+        return Ok(packets);
+    }
+    Ok(packets
+        .into_iter()
+        .enumerate()
+        .map(|(index, packet)| {
+            let orig_len = batch.orig_len(index).or(packet.orig_len());
+            packet
+                .with_fingerprint(batch.fingerprint(index))
+                .with_interface(batch.interface(index))
+                .with_segments(batch.segments(index))
+                .with_orig_len(orig_len)
+        })
+        .collect())
+}
+
+/// Check `data` is long enough to hold `count` packets, as `count` comes from the sender and
+/// is used to preallocate for them.
+pub(crate) fn check_count(data: &[u8], count: usize, wire_format: WireFormat) -> Result<(), Error> {
+    let max = data.len() / header_len(wire_format);
+    if count > max {
+        return Err(Error::Decode {
+            index: max,
+            count,
+            source: Box::new(bincode::ErrorKind::Custom(format!(
+                "{} bytes can hold at most {} packets",
+                data.len(),
+                max
+            ))),
+        });
+    }
+    Ok(())
+}
+
+/// Decode `count` packets encoded back to back in `data`.
+pub(crate) fn decode_packets(
+    data: &[u8],
+    count: usize,
+    wire_format: WireFormat,
+    allocator: Option<&dyn PayloadAllocator>,
+) -> Result<Vec<Packet>, Error> {
+    check_count(data, count, wire_format)?;
+    let mut packets = Vec::with_capacity(count);
+    for_each_packet(data, count, wire_format, |ts, payload, orig_len| {
+        let data = match allocator {
+            Some(allocator) => {
+                let mut data = allocator.allocate(payload.len());
+                data.extend_from_slice(payload);
+                data
+            }
+            None => payload.to_vec(),
+        };
+        packets.push(Packet::new(ts, data).with_orig_len(orig_len));
+    })?;
+    Ok(packets)
+}
+
+/// Call `f` with the timestamp, payload and original length, if the wire format carries it, of
+/// each of `count` packets encoded back to back in `data`, borrowing the payloads from `data`.
+pub(crate) fn for_each_packet<'a, F>(
+    data: &'a [u8],
+    count: usize,
+    wire_format: WireFormat,
+    mut f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(SystemTime, &'a [u8], Option<u32>),
+{
+    let mut deserializer = bincode::Deserializer::from_slice(data, encoding_options());
+    let mut offset = 0;
+    for index in 0..count {
+        let decoded = match wire_format {
+            WireFormat::Bincode => IpcPacket::deserialize(&mut deserializer).map(|packet| {
+                let (ts, data) = packet.into_parts();
+                (ts, data, None)
+            }),
+            WireFormat::NanosTimestamps => NanosPacket::deserialize(&mut deserializer)
+                .map(|packet| (packet.timestamp(), packet.data, None)),
+            WireFormat::Raw => read_raw(data, &mut offset),
+        };
+        let (ts, payload, orig_len) = decoded.map_err(|source| Error::Decode {
+            index,
+            count,
+            source,
+        })?;
+        f(ts, payload, orig_len);
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use crate::batch::BatchBuilder;
+use crate::errors::Error;
+use crate::packet::AsIpcPacket;
+use crate::server::ConnectedIpc;
+use crate::stats::CloseSummary;
+use log::*;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Controls when a `BatchingSender` flushes its batch.
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    pub(crate) max_packets: usize,
+    pub(crate) max_bytes: usize,
+    pub(crate) flush_interval: Option<Duration>,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_packets: 1024,
+            max_bytes: 1024 * 1024,
+            flush_interval: Some(Duration::from_millis(100)),
+        }
+    }
+}
+
+impl BatchConfig {
+    /// Flush once this many packets are batched.
+    pub fn max_packets(mut self, max_packets: usize) -> Self {
+        self.max_packets = max_packets;
+        self
+    }
+
+    /// Flush once this many encoded bytes are batched.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Flush a partially filled batch once its oldest packet has waited this long. `None`
+    /// disables time based flushing.
+    pub fn flush_interval(mut self, flush_interval: Option<Duration>) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+}
+
+struct State {
+    connection: ConnectedIpc,
+    batch: BatchBuilder,
+    oldest: Option<Instant>,
+    stopped: bool,
+    /// Error from a flush by the background flusher, not yet returned to the caller.
+    failed: Option<Error>,
+}
+
+impl State {
+    fn flush(&mut self) -> Result<(), Error> {
+        self.oldest = None;
+        self.batch.flush(&self.connection)
+    }
+
+    /// Return the error of a failed background flush, if any.
+    fn check(&mut self) -> Result<(), Error> {
+        match self.failed.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    packet_added: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Batches packets for a connection, flushing when the batch reaches its packet or byte limit,
+/// or from a background thread once the batch has been waiting longer than the flush interval.
+/// A failed background flush is returned by the next `push`, `flush` or `close`.
+pub struct BatchingSender {
+    shared: Arc<Shared>,
+    config: BatchConfig,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl BatchingSender {
+    pub fn new(connection: ConnectedIpc, config: BatchConfig) -> BatchingSender {
+        let batch = connection.batch_builder();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                connection,
+                batch,
+                oldest: None,
+                stopped: false,
+                failed: None,
+            }),
+            packet_added: Condvar::new(),
+        });
+        let flusher = config.flush_interval.map(|interval| {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || run_flusher(&shared, interval))
+        });
+        BatchingSender {
+            shared,
+            config,
+            flusher,
+        }
+    }
+
+    /// Add a packet to the batch, unless the connection drops it as a duplicate. Packets are
+    /// encoded as they are pushed, so they aren't coalesced.
+    pub fn push<T: AsIpcPacket + ?Sized>(&self, packet: &T) -> Result<(), Error> {
+        let mut state = self.shared.lock();
+        state.check()?;
+        if state.connection.is_duplicate(packet) {
+            return Ok(());
+        }
+        state.batch.push(packet)?;
+        if state.oldest.is_none() {
+            state.oldest = Some(Instant::now());
+            self.shared.packet_added.notify_one();
+        }
+        if state.batch.len() >= self.config.max_packets
+            || state.batch.encoded_len() >= self.config.max_bytes
+        {
+            state.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut state = self.shared.lock();
+        state.check()?;
+        state.flush()
+    }
+
+    /// Estimated number of batches waiting, counting the one being built and those sent but not
+    /// yet received by the client.
+    pub fn pending_batches(&self) -> u64 {
+        let state = self.shared.lock();
+        state.connection.pending_batches() + !state.batch.is_empty() as u64
+    }
+
+    /// Estimated number of encoded bytes waiting, counting the batch being built and those sent
+    /// but not yet received by the client.
+    pub fn pending_bytes(&self) -> u64 {
+        let state = self.shared.lock();
+        state.connection.pending_bytes() + state.batch.encoded_len() as u64
+    }
+
+    /// Flush any remaining packets, stop the background flusher, and close the connection.
+    pub fn close(mut self) -> Result<CloseSummary, Error> {
+        self.stop();
+        let mut state = self.shared.lock();
+        let failed = state.check();
+        state.flush()?;
+        let summary = state.connection.close()?;
+        failed.map(|()| summary)
+    }
+
+    fn stop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.packet_added.notify_one();
+        if let Some(flusher) = self.flusher.take() {
+            if flusher.join().is_err() {
+                error!("Flush thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for BatchingSender {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_flusher(shared: &Shared, interval: Duration) {
+    let mut state = shared.lock();
+    while !state.stopped {
+        state = match state.oldest {
+            None => shared
+                .packet_added
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner()),
+            Some(oldest) => {
+                let waited = oldest.elapsed();
+                if waited >= interval {
+                    if let Err(e) = state.flush() {
+                        error!("Failed to flush batch: {:?}", e);
+                        state.failed = Some(e);
+                    }
+                    state
+                } else {
+                    shared
+                        .packet_added
+                        .wait_timeout(state, interval - waited)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            }
+        };
+    }
+}
@@ -0,0 +1,507 @@
+use crate::batch::BatchBuilder;
+use crate::drops::DropReason;
+use crate::errors::Error;
+use crate::info::ConsumerInfo;
+use crate::message::{EncodedBatch, Message, Priority, WireFormat};
+use crate::packet::AsIpcPacket;
+use crate::server::ConnectedIpc;
+use crate::shutdown::{Shutdown, ShutdownReport};
+use log::*;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+type Filter = Arc<dyn Fn(&dyn AsIpcPacket) -> bool + Send + Sync>;
+
+/// What a `Broadcaster` does when sending to a destination fails.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OnFailure {
+    /// Fail the whole broadcast, leaving the destination in place.
+    #[default]
+    Fail,
+    /// Remove the destination and carry on sending to the others.
+    Remove,
+    /// Retry this many times, doubling the backoff between attempts, then remove the
+    /// destination.
+    Retry { retries: usize, backoff: Duration },
+}
+
+/// Something that happened to a `Broadcaster` destination.
+#[derive(Clone, Debug)]
+pub enum BroadcastEvent {
+    Added {
+        id: usize,
+        consumer: ConsumerInfo,
+    },
+    Removed {
+        id: usize,
+        consumer: ConsumerInfo,
+    },
+    /// Sending to the destination failed, after any retries.
+    Failed {
+        id: usize,
+        error: String,
+    },
+    /// Packets were dropped rather than sent to the destination because it was over a quota.
+    /// They are also reported to its client as drops.
+    Throttled {
+        id: usize,
+        packets: u64,
+    },
+}
+
+/// Controls which packets, and how much of each, a broadcast destination receives.
+#[derive(Clone, Default)]
+pub struct Policy {
+    snaplen: Option<usize>,
+    sample: Option<u64>,
+    filter: Option<Filter>,
+    on_failure: OnFailure,
+    max_rate: Option<u64>,
+    max_queued_bytes: Option<u64>,
+}
+
+impl Policy {
+    /// Start from the options a consumer asked for in its handshake.
+    pub fn from_consumer(consumer: &ConsumerInfo) -> Policy {
+        Policy {
+            snaplen: consumer.snaplen().map(|s| s as usize),
+            ..Policy::default()
+        }
+    }
+
+    /// Send at most `snaplen` bytes of each packet.
+    pub fn snaplen(mut self, snaplen: usize) -> Self {
+        self.snaplen = Some(snaplen);
+        self
+    }
+
+    /// Send one of every `one_in` packets.
+    pub fn sample(mut self, one_in: u64) -> Self {
+        self.sample = Some(one_in.max(1));
+        self
+    }
+
+    /// Only send packets for which `filter` returns true.
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&dyn AsIpcPacket) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// How to handle failing to send to this destination.
+    pub fn on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// Send at most `bytes_per_second` of packet data, allowing bursts of up to a second's worth.
+    /// Packets over the rate are dropped for this destination only, with `DropReason::Degraded`.
+    pub fn max_rate(mut self, bytes_per_second: u64) -> Self {
+        self.max_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Drop whole batches for this destination, with `DropReason::Overflow`, while at least
+    /// `bytes` it was sent are still waiting to be received, so a slow consumer can't hold up
+    /// the others.
+    pub fn max_queued_bytes(mut self, bytes: u64) -> Self {
+        self.max_queued_bytes = Some(bytes);
+        self
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Policy")
+            .field("snaplen", &self.snaplen)
+            .field("sample", &self.sample)
+            .field("filter", &self.filter.is_some())
+            .field("on_failure", &self.on_failure)
+            .field("max_rate", &self.max_rate)
+            .field("max_queued_bytes", &self.max_queued_bytes)
+            .finish()
+    }
+}
+
+/// Shared handle to a broadcast destination's `Policy`, see `Broadcaster::policy`. Replacing
+/// the policy takes effect from the next `Broadcaster::send`, without touching the connection.
+#[derive(Clone, Debug)]
+pub struct PolicyHandle {
+    policy: Arc<RwLock<Arc<Policy>>>,
+    generation: Arc<AtomicU64>,
+}
+
+impl PolicyHandle {
+    fn new(policy: Policy) -> PolicyHandle {
+        PolicyHandle {
+            policy: Arc::new(RwLock::new(Arc::new(policy))),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Policy> {
+        match self.policy.read() {
+            Ok(policy) => Arc::clone(&policy),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+
+    /// Replace the policy. A rate limit starts again from a full burst if its rate changed.
+    pub fn set(&self, policy: Policy) {
+        let policy = Arc::new(policy);
+        match self.policy.write() {
+            Ok(mut current) => *current = policy,
+            Err(poisoned) => *poisoned.into_inner() = policy,
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+/// A packet truncated to a snaplen.
+struct Truncated<'a, T: ?Sized> {
+    packet: &'a T,
+    snaplen: usize,
+}
+
+impl<'a, T: AsIpcPacket + ?Sized> AsIpcPacket for Truncated<'a, T> {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        self.packet.timestamp()
+    }
+    fn data(&self) -> &[u8] {
+        let data = self.packet.data();
+        &data[..usize::min(data.len(), self.snaplen)]
+    }
+    fn interface(&self) -> Option<u32> {
+        self.packet.interface()
+    }
+    fn segments(&self) -> u32 {
+        self.packet.segments()
+    }
+    fn orig_len(&self) -> Option<u32> {
+        let len = self.packet.data().len();
+        self.packet
+            .orig_len()
+            .or_else(|| u32::try_from(len).ok().filter(|_| len > self.snaplen))
+    }
+}
+
+/// Bytes a destination may be sent, refilled at a fixed rate.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_second: u64) -> TokenBucket {
+        TokenBucket {
+            rate: bytes_per_second as f64,
+            tokens: bytes_per_second as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, bytes: usize) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+struct Destination {
+    id: usize,
+    connection: ConnectedIpc,
+    policy: Arc<Policy>,
+    handle: PolicyHandle,
+    generation: u64,
+    seen: u64,
+    bucket: Option<TokenBucket>,
+    /// Packets dropped by the rate limit that haven't been reported yet.
+    throttled: u64,
+}
+
+impl Destination {
+    /// Pick up a policy replaced through the destination's handle.
+    fn refresh_policy(&mut self) {
+        let generation = self.handle.generation();
+        if generation == self.generation {
+            return;
+        }
+        self.generation = generation;
+        let policy = self.handle.get();
+        if policy.max_rate != self.policy.max_rate {
+            self.bucket = policy.max_rate.map(TokenBucket::new);
+        }
+        self.policy = policy;
+    }
+
+    /// Whether the destination is over its queue quota, so should be skipped.
+    fn over_queue_quota(&self) -> bool {
+        self.policy
+            .max_queued_bytes
+            .is_some_and(|max| self.connection.pending_bytes() >= max)
+    }
+
+    fn report_throttled(&self, packets: u64, reason: DropReason, events: &mut Vec<BroadcastEvent>) {
+        if packets == 0 {
+            return;
+        }
+        self.connection.record_drops(packets, reason);
+        events.push(BroadcastEvent::Throttled {
+            id: self.id,
+            packets,
+        });
+    }
+
+    fn accepts<T: AsIpcPacket>(&mut self, packet: &T) -> bool {
+        if let Some(filter) = &self.policy.filter {
+            if !filter(packet) {
+                return false;
+            }
+        }
+        let seen = self.seen;
+        self.seen += 1;
+        self.policy
+            .sample
+            .map(|n| seen.is_multiple_of(n))
+            .unwrap_or(true)
+    }
+
+    /// Encode the packets this destination accepts, as its connection's `send` would.
+    fn encode<T: AsIpcPacket>(&mut self, packets: &[T]) -> Result<BatchBuilder, Error> {
+        let snaplen = self.policy.snaplen.unwrap_or(usize::MAX);
+        let mut accepted = Vec::with_capacity(packets.len());
+        for packet in packets {
+            if !self.accepts(packet) {
+                continue;
+            }
+            if let Some(bucket) = self.bucket.as_mut() {
+                if !bucket.take(packet.data().len().min(snaplen)) {
+                    self.throttled += 1;
+                    continue;
+                }
+            }
+            accepted.push(Truncated { packet, snaplen });
+        }
+        self.connection.encode(accepted)
+    }
+
+    /// Send an encoded batch, retrying if the policy allows.
+    fn deliver(&self, batch: &EncodedBatch) -> Result<(), Error> {
+        let (retries, mut backoff) = match self.policy.on_failure {
+            OnFailure::Retry { retries, backoff } => (retries, backoff),
+            _ => (0, Duration::from_secs(0)),
+        };
+        let mut attempt = 0;
+        loop {
+            match self.connection.send_message(Message::Batch(batch.clone())) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    warn!(
+                        "Failed to send to destination {}, retrying in {:?}: {:?}",
+                        self.id, backoff, e
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Destinations with the same key are sent identical batches, so the batch only needs to be
+    /// encoded once. Policies with filters, sampling or a rate limit have no key, nor do
+    /// connections encoding batches of their own, e.g. deduplicating.
+    fn shared_key(&self) -> Option<(Option<usize>, WireFormat)> {
+        if self.policy.filter.is_some()
+            || self.policy.sample.is_some()
+            || self.bucket.is_some()
+            || self.connection.custom_encoding()
+        {
+            None
+        } else {
+            Some((self.policy.snaplen, self.connection.info().wire_format()))
+        }
+    }
+}
+
+/// Sends every packet to a set of connections, applying each destination's `Policy` first.
+#[derive(Default)]
+pub struct Broadcaster {
+    destinations: Vec<Destination>,
+    next_id: usize,
+    events: Vec<BroadcastEvent>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        Broadcaster::default()
+    }
+
+    /// Add a destination, returning the id it is known by in events.
+    pub fn add(&mut self, connection: ConnectedIpc, policy: Policy) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.events.push(BroadcastEvent::Added {
+            id,
+            consumer: connection.consumer().clone(),
+        });
+        let handle = PolicyHandle::new(policy);
+        self.destinations.push(Destination {
+            id,
+            connection,
+            bucket: handle.get().max_rate.map(TokenBucket::new),
+            policy: handle.get(),
+            generation: handle.generation(),
+            handle,
+            seen: 0,
+            throttled: 0,
+        });
+        id
+    }
+
+    /// Stop sending to destination `id`, returning its connection. Batches already sent are
+    /// still in flight, so close it with e.g. `Shutdown::close` to wait for them to be received.
+    pub fn remove(&mut self, id: usize) -> Option<ConnectedIpc> {
+        let position = self.destinations.iter().position(|d| d.id == id)?;
+        let destination = self.destinations.remove(position);
+        self.events.push(BroadcastEvent::Removed {
+            id,
+            consumer: destination.connection.consumer().clone(),
+        });
+        Some(destination.connection)
+    }
+
+    /// Handle to destination `id`'s policy, to change what it is sent while broadcasting, e.g.
+    /// from a thread handling configuration reloads.
+    pub fn policy(&self, id: usize) -> Option<PolicyHandle> {
+        self.destinations
+            .iter()
+            .find(|d| d.id == id)
+            .map(|d| d.handle.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.destinations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.destinations.is_empty()
+    }
+
+    /// Events that have happened since last called.
+    pub fn events(&mut self) -> impl Iterator<Item = BroadcastEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    /// Send packets to every destination. A destination that fails is handled according to its
+    /// `OnFailure` policy.
+    pub fn send<T: AsIpcPacket>(&mut self, packets: &[T]) -> Result<(), Error> {
+        let mut encoded: Vec<(Option<usize>, WireFormat, EncodedBatch, u64)> = vec![];
+        let mut failed = vec![];
+        let mut result = Ok(());
+        for (position, destination) in self.destinations.iter_mut().enumerate() {
+            destination.refresh_policy();
+            if destination.over_queue_quota() {
+                let accepted = packets.iter().filter(|p| destination.accepts(*p)).count();
+                destination.report_throttled(
+                    accepted as u64,
+                    DropReason::Overflow,
+                    &mut self.events,
+                );
+                continue;
+            }
+            let own;
+            let batch = match destination.shared_key() {
+                None => match destination.encode(packets) {
+                    Ok(mut builder) => {
+                        own = builder.take(Priority::Normal);
+                        Ok((&own, builder.skipped()))
+                    }
+                    Err(e) => Err(e),
+                },
+                Some(key) => match encoded.iter().position(|(s, f, ..)| (*s, *f) == key) {
+                    Some(index) => Ok((&encoded[index].2, encoded[index].3)),
+                    None => match destination.encode(packets) {
+                        Ok(mut builder) => {
+                            let batch = builder.take(Priority::Normal);
+                            encoded.push((key.0, key.1, batch, builder.skipped()));
+                            let (_, _, batch, skipped) = &encoded[encoded.len() - 1];
+                            Ok((batch, *skipped))
+                        }
+                        Err(e) => Err(e),
+                    },
+                },
+            };
+            // A batch that can't be encoded fails the destination the same as one that can't
+            // be sent, though retrying the encode would fail again
+            let sent = match batch {
+                Ok((batch, skipped)) => {
+                    // Packets skipped by `EncodeErrorPolicy::SkipPacket` are counted per
+                    // destination, as when flushing to a connection
+                    if skipped > 0 {
+                        destination.connection.record_encode_failures(skipped);
+                    }
+                    let throttled = std::mem::take(&mut destination.throttled);
+                    destination.report_throttled(throttled, DropReason::Degraded, &mut self.events);
+                    if batch.header.count == 0 {
+                        continue;
+                    }
+                    destination.deliver(batch)
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                self.events.push(BroadcastEvent::Failed {
+                    id: destination.id,
+                    error: e.to_string(),
+                });
+                if destination.policy.on_failure == OnFailure::Fail {
+                    result = Err(e);
+                    break;
+                }
+                warn!(
+                    "Removing destination {} after failure: {:?}",
+                    destination.id, e
+                );
+                failed.push(position);
+            }
+        }
+        for position in failed.into_iter().rev() {
+            let destination = self.destinations.remove(position);
+            self.events.push(BroadcastEvent::Removed {
+                id: destination.id,
+                consumer: destination.connection.consumer().clone(),
+            });
+        }
+        result
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        for destination in self.destinations.iter_mut() {
+            destination.connection.close()?;
+        }
+        Ok(())
+    }
+
+    /// Close every destination in the order they were added, waiting for acknowledgements.
+    pub fn shutdown(self, shutdown: &Shutdown) -> ShutdownReport {
+        shutdown.close(self.destinations.into_iter().map(|d| d.connection))
+    }
+}
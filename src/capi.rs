@@ -0,0 +1,373 @@
+//! C bindings, enabled with the `capi` feature. See `include/packet_ipc.h` for the C
+//! declarations.
+
+use crate::client::Client;
+use crate::packet::{AsIpcPacket, Packet};
+use crate::server::{ConnectedIpc, Server};
+use log::*;
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::sync::Arc;
+
+/// Returned when a call completed successfully.
+pub const PACKET_IPC_OK: c_int = 0;
+/// Returned by receive calls once the connection has closed and all packets were received.
+pub const PACKET_IPC_CLOSED: c_int = 1;
+/// Returned when an argument was null or otherwise invalid.
+pub const PACKET_IPC_INVALID_ARGUMENT: c_int = -1;
+/// Returned when the underlying operation failed.
+pub const PACKET_IPC_ERROR: c_int = -2;
+
+/// Describes a packet copied into a caller provided buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketIpcPacket {
+    /// Seconds since the unix epoch.
+    pub ts_sec: u64,
+    /// Nanoseconds within the second.
+    pub ts_nsec: u32,
+    /// Length of the packet data.
+    pub len: u32,
+    /// Offset of the packet data in the caller's buffer.
+    pub offset: usize,
+}
+
+/// Describes a packet for the server to send.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PacketIpcSendPacket {
+    /// Seconds since the unix epoch.
+    pub ts_sec: u64,
+    /// Nanoseconds within the second.
+    pub ts_nsec: u32,
+    /// Packet data.
+    pub data: *const u8,
+    /// Length of the packet data.
+    pub len: usize,
+}
+
+struct BorrowedPacket<'a> {
+    ts: std::time::SystemTime,
+    data: &'a [u8],
+}
+
+impl<'a> AsIpcPacket for BorrowedPacket<'a> {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        &self.ts
+    }
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+}
+
+/// Opaque handle to a server waiting for a client.
+pub struct PacketIpcServer {
+    server: Server,
+    name: CString,
+}
+
+/// Opaque handle to a server's connection to a client.
+pub struct PacketIpcConnection {
+    connection: ConnectedIpc,
+}
+
+/// Opaque handle to a connected client.
+pub struct PacketIpcClient {
+    client: Option<Client>,
+    pending: VecDeque<Arc<Packet>>,
+}
+
+fn describe<T: AsIpcPacket + ?Sized>(packet: &T, offset: usize) -> PacketIpcPacket {
+    let since_epoch = packet
+        .timestamp()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    PacketIpcPacket {
+        ts_sec: since_epoch.as_secs(),
+        ts_nsec: since_epoch.subsec_nanos(),
+        len: packet.data().len() as u32,
+        offset,
+    }
+}
+
+/// Connect a client to the server named `server_name`, returning null on failure.
+///
+/// # Safety
+///
+/// `server_name` must be a valid null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_client_new(server_name: *const c_char) -> *mut PacketIpcClient {
+    if server_name.is_null() {
+        return std::ptr::null_mut();
+    }
+    let server_name = match CStr::from_ptr(server_name).to_str() {
+        Ok(server_name) => server_name.to_owned(),
+        Err(e) => {
+            error!("Server name is not valid utf8: {:?}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    match Client::new(server_name) {
+        Ok(client) => Box::into_raw(Box::new(PacketIpcClient {
+            client: Some(client),
+            pending: VecDeque::new(),
+        })),
+        Err(e) => {
+            error!("Failed to connect client: {:?}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Receive up to `max_packets` packets, blocking until at least one is available. Packet data is
+/// copied back to back into `buffer`, and each packet is described in `packets`. The number of
+/// packets received is written to `received`. Packets that do not fit in `buffer` are kept for
+/// the next call; a single packet larger than `buffer` is truncated to fit.
+///
+/// Returns `PACKET_IPC_OK` when packets were received, or `PACKET_IPC_CLOSED` once the server has
+/// closed the connection and every packet has been received.
+///
+/// # Safety
+///
+/// `client` must come from `packet_ipc_client_new` and not have been freed, `packets` must point
+/// to `max_packets` writable descriptors, and `buffer` to `buffer_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_client_recv(
+    client: *mut PacketIpcClient,
+    packets: *mut PacketIpcPacket,
+    max_packets: usize,
+    buffer: *mut u8,
+    buffer_len: usize,
+    received: *mut usize,
+) -> c_int {
+    if client.is_null() || packets.is_null() || buffer.is_null() || received.is_null() {
+        return PACKET_IPC_INVALID_ARGUMENT;
+    }
+    let handle = &mut *client;
+    let packets = std::slice::from_raw_parts_mut(packets, max_packets);
+    let buffer = std::slice::from_raw_parts_mut(buffer, buffer_len);
+    *received = 0;
+
+    if handle.pending.is_empty() {
+        let client = match handle.client.as_mut() {
+            None => return PACKET_IPC_CLOSED,
+            Some(client) => client,
+        };
+        match client.recv(max_packets) {
+            Ok(Some(batch)) => handle.pending.extend(batch),
+            Ok(None) => return PACKET_IPC_CLOSED,
+            Err(e) => {
+                error!("Failed to receive packets: {:?}", e);
+                return PACKET_IPC_ERROR;
+            }
+        }
+    }
+
+    let mut offset = 0;
+    let mut count = 0;
+    while count < max_packets {
+        let packet = match handle.pending.front() {
+            None => break,
+            Some(packet) => packet,
+        };
+        let data = packet.data();
+        let len = if count == 0 {
+            usize::min(data.len(), buffer_len)
+        } else if data.len() <= buffer_len - offset {
+            data.len()
+        } else {
+            break;
+        };
+        buffer[offset..offset + len].copy_from_slice(&data[..len]);
+        packets[count] = PacketIpcPacket {
+            len: len as u32,
+            ..describe(packet.as_ref(), offset)
+        };
+        offset += len;
+        count += 1;
+        handle.pending.pop_front();
+    }
+    *received = count;
+    PACKET_IPC_OK
+}
+
+/// Disconnect from the server. Packets already received can still be read with
+/// `packet_ipc_client_recv`, after which it returns `PACKET_IPC_CLOSED`.
+///
+/// # Safety
+///
+/// `client` must come from `packet_ipc_client_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_client_close(client: *mut PacketIpcClient) -> c_int {
+    if client.is_null() {
+        return PACKET_IPC_INVALID_ARGUMENT;
+    }
+    (*client).client = None;
+    PACKET_IPC_OK
+}
+
+/// Free a client, disconnecting it if still connected.
+///
+/// # Safety
+///
+/// `client` must come from `packet_ipc_client_new` and not have been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_client_free(client: *mut PacketIpcClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Create a server, returning null on failure. Clients connect using the name returned by
+/// `packet_ipc_server_name`.
+#[no_mangle]
+pub extern "C" fn packet_ipc_server_new() -> *mut PacketIpcServer {
+    let server = match Server::new() {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to create server: {:?}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let name = match CString::new(server.name().clone()) {
+        Ok(name) => name,
+        Err(e) => {
+            error!("Server name is not a valid string: {:?}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    Box::into_raw(Box::new(PacketIpcServer { server, name }))
+}
+
+/// Name clients use to connect to the server. The string is owned by the server and valid until
+/// it is accepted or freed.
+///
+/// # Safety
+///
+/// `server` must come from `packet_ipc_server_new` and not have been accepted or freed.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_server_name(server: *const PacketIpcServer) -> *const c_char {
+    if server.is_null() {
+        return std::ptr::null();
+    }
+    (*server).name.as_ptr()
+}
+
+/// Block until a client connects, returning the connection or null on failure. The server is
+/// consumed either way and must not be used afterwards.
+///
+/// # Safety
+///
+/// `server` must come from `packet_ipc_server_new` and not have been accepted or freed.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_server_accept(
+    server: *mut PacketIpcServer,
+) -> *mut PacketIpcConnection {
+    if server.is_null() {
+        return std::ptr::null_mut();
+    }
+    let server = Box::from_raw(server);
+    match server.server.accept() {
+        Ok(connection) => Box::into_raw(Box::new(PacketIpcConnection { connection })),
+        Err(e) => {
+            error!("Failed to accept connection: {:?}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a server that was not accepted.
+///
+/// # Safety
+///
+/// `server` must come from `packet_ipc_server_new` and not have been accepted or freed, or be
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_server_free(server: *mut PacketIpcServer) {
+    if !server.is_null() {
+        drop(Box::from_raw(server));
+    }
+}
+
+/// Send `count` packets to the client as a single batch. Fails with
+/// `PACKET_IPC_INVALID_ARGUMENT` if a timestamp is out of range.
+///
+/// # Safety
+///
+/// `connection` must come from `packet_ipc_server_accept` and not have been freed, and `packets`
+/// must point to `count` descriptors, each pointing to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_send(
+    connection: *mut PacketIpcConnection,
+    packets: *const PacketIpcSendPacket,
+    count: usize,
+) -> c_int {
+    if connection.is_null() || (packets.is_null() && count > 0) {
+        return PACKET_IPC_INVALID_ARGUMENT;
+    }
+    if count == 0 {
+        return PACKET_IPC_OK;
+    }
+    let packets = std::slice::from_raw_parts(packets, count);
+    let mut borrowed = Vec::with_capacity(count);
+    for packet in packets {
+        if packet.data.is_null() && packet.len > 0 {
+            return PACKET_IPC_INVALID_ARGUMENT;
+        }
+        let data: &[u8] = if packet.len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(packet.data, packet.len)
+        };
+        // Out of range timestamps would panic, which can't unwind into the caller
+        if packet.ts_nsec >= 1_000_000_000 {
+            return PACKET_IPC_INVALID_ARGUMENT;
+        }
+        let since_epoch = std::time::Duration::new(packet.ts_sec, packet.ts_nsec);
+        let ts = match std::time::UNIX_EPOCH.checked_add(since_epoch) {
+            Some(ts) => ts,
+            None => return PACKET_IPC_INVALID_ARGUMENT,
+        };
+        borrowed.push(BorrowedPacket { ts, data });
+    }
+    match (*connection).connection.send(&borrowed) {
+        Ok(()) => PACKET_IPC_OK,
+        Err(e) => {
+            error!("Failed to send packets: {:?}", e);
+            PACKET_IPC_ERROR
+        }
+    }
+}
+
+/// Tell the client no more packets will be sent.
+///
+/// # Safety
+///
+/// `connection` must come from `packet_ipc_server_accept` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_close(connection: *mut PacketIpcConnection) -> c_int {
+    if connection.is_null() {
+        return PACKET_IPC_INVALID_ARGUMENT;
+    }
+    match (*connection).connection.close() {
+        Ok(_) => PACKET_IPC_OK,
+        Err(e) => {
+            error!("Failed to close connection: {:?}", e);
+            PACKET_IPC_ERROR
+        }
+    }
+}
+
+/// Free a connection. Call `packet_ipc_close` first so the client knows the connection ended
+/// normally.
+///
+/// # Safety
+///
+/// `connection` must come from `packet_ipc_server_accept` and not have been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn packet_ipc_connection_free(connection: *mut PacketIpcConnection) {
+    if !connection.is_null() {
+        drop(Box::from_raw(connection));
+    }
+}
@@ -1,50 +1,404 @@
+use crate::alloc::{BufferSet, PayloadAllocator};
 use crate::errors::Error;
 
-use crate::packet::{IpcPacket, Packet};
-use crossbeam_channel::{Receiver as CrossbeamReceiver, Sender as CrossbeamSender};
-use ipc_channel::ipc::{self, IpcSender};
+use crate::aggregate::FlowRecord;
+use crate::batch::decode_batch;
+use crate::clock::{ClockEstimator, ClockOffset};
+use crate::drops::DropReport;
+use crate::info::{ConnectionInfo, ConsumerHealth, ConsumerInfo};
+use crate::message::{
+    BatchInfo, CloseReason, Control, EncodedBatch, Handshake, Hello, Message, Priority, WireFormat,
+    PROTOCOL_VERSION,
+};
+use crate::packet::{AsIpcPacket, Packet};
+use crate::received::{Batch, EncodedPackets};
+#[cfg(all(unix, not(target_os = "macos")))]
+use crate::server::resolve_name;
+use crate::stats::{Stats, StatsSnapshot};
+use crossbeam_channel::{
+    Receiver as CrossbeamReceiver, RecvError, RecvTimeoutError, Sender as CrossbeamSender,
+};
+use ipc_channel::ipc::{self, IpcReceiver, IpcSender};
 use ipc_channel::ipc::{IpcReceiverSet, IpcSelectionResult};
 use log::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Options used when connecting a `Client`.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    consumer: ConsumerInfo,
+    channel_size: Option<usize>,
+    wire_formats: Vec<WireFormat>,
+    retry_connect: Option<Duration>,
+    allocator: Option<Arc<dyn PayloadAllocator>>,
+    health_interval: Option<Duration>,
+    packet_views: bool,
+    max_held_batches: Option<usize>,
+    resync: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            consumer: ConsumerInfo::default(),
+            channel_size: None,
+            wire_formats: WireFormat::supported(),
+            retry_connect: None,
+            allocator: None,
+            health_interval: Some(Duration::from_secs(1)),
+            packet_views: false,
+            max_held_batches: None,
+            resync: false,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Name identifying this consumer to the server.
+    pub fn name(mut self, name: &str) -> Self {
+        self.consumer.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn version(mut self, version: &str) -> Self {
+        self.consumer.version = Some(version.to_owned());
+        self
+    }
+
+    /// Ask the server to send at most `snaplen` bytes of each packet.
+    pub fn snaplen(mut self, snaplen: u32) -> Self {
+        self.consumer.snaplen = Some(snaplen);
+        self
+    }
+
+    /// Ask the server to only send packets matching `filter`.
+    pub fn filter(mut self, filter: &str) -> Self {
+        self.consumer.filter = Some(filter.to_owned());
+        self
+    }
+
+    /// Ask the server to send packets in `format`.
+    pub fn format(mut self, format: &str) -> Self {
+        self.consumer.format = Some(format.to_owned());
+        self
+    }
+
+    /// Wire formats to offer the server, most preferred first. Defaults to every supported
+    /// format.
+    pub fn wire_formats(mut self, wire_formats: Vec<WireFormat>) -> Self {
+        self.wire_formats = wire_formats;
+        self
+    }
+
+    /// Bound the number of received batches buffered by the client, or `None` for unbounded.
+    pub fn channel_size(mut self, channel_size: Option<usize>) -> Self {
+        self.channel_size = channel_size;
+        self
+    }
+
+    /// Copy received payloads into buffers from `allocator` rather than the global allocator.
+    pub fn allocator(mut self, allocator: Arc<dyn PayloadAllocator>) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    /// How often to report `ConsumerHealth` to the server while receiving, or `None` to never
+    /// report. Defaults to every second.
+    pub fn health_interval(mut self, health_interval: Option<Duration>) -> Self {
+        self.health_interval = health_interval;
+        self
+    }
+
+    /// Leave batches encoded until they are taken, so `Client::recv_views` can borrow packets
+    /// from them without copying. Other ways of receiving then decode batches as they take
+    /// them, rather than on the receive thread.
+    pub fn packet_views(mut self, packet_views: bool) -> Self {
+        self.packet_views = packet_views;
+        self
+    }
+
+    /// Skip a message that can't be read, reporting it as `StreamItem::Corrupt`, rather than end
+    /// the stream with `CloseReason::ReceiveError`. Packets in a skipped batch are lost.
+    pub fn resync(mut self, resync: bool) -> Self {
+        self.resync = resync;
+        self
+    }
+
+    /// Fail `Client::recv_views` with `Error::TooManyHeld` rather than receive while `max`
+    /// batches are still held, or never with `None`, the default. Holding batches holds their
+    /// buffers, so a consumer that forgets views could otherwise grow without bound.
+    pub fn max_held_batches(mut self, max: Option<usize>) -> Self {
+        self.max_held_batches = max;
+        self
+    }
+
+    /// Keep retrying for up to `timeout` if the server isn't accepting yet, such as while a
+    /// `MultiServer` moves its name to a new endpoint. Names given to `Server::new_with_name`
+    /// or `MultiServer::new` are retried for a second by default, long enough to ride out that
+    /// switch.
+    pub fn retry_connect(mut self, timeout: Duration) -> Self {
+        self.retry_connect = Some(timeout);
+        self
+    }
+}
+
+/// How long connecting to a server by name retries unless `ClientConfig::retry_connect` says
+/// otherwise.
+#[cfg(all(unix, not(target_os = "macos")))]
+const NAMED_RETRY_CONNECT: Duration = Duration::from_secs(1);
+
+/// Send the handshake and wait for the server's reply.
+fn handshake(
+    server_name: &str,
+    consumer: &ConsumerInfo,
+    wire_formats: &[WireFormat],
+) -> Result<(Hello, IpcReceiver<Message>), Error> {
+    let (ipc_tx, ipc_rx) = ipc::channel::<Message>().map_err(Error::Io)?;
+    let server_sender = IpcSender::connect(server_name.to_owned()).map_err(Error::Io)?;
+    server_sender
+        .send(Handshake {
+            sender: ipc_tx,
+            pid: std::process::id(),
+            protocol_version: PROTOCOL_VERSION,
+            consumer: consumer.clone(),
+            wire_formats: wire_formats.to_vec(),
+        })
+        .map_err(Error::Bincode)?;
+
+    match ipc_rx.recv()? {
+        Message::Hello(hello) => Ok((hello, ipc_rx)),
+        Message::Rejected(reason) => Err(Error::Rejected(reason)),
+        m => Err(Error::Handshake(format!(
+            "Expected hello, received {:?}",
+            m
+        ))),
+    }
+}
+
+/// Whether a failed handshake may succeed if retried.
+fn is_retryable(e: &Error) -> bool {
+    match e {
+        Error::Io(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+        ),
+        Error::Disconnected => true,
+        _ => false,
+    }
+}
+
+type Delivery = Option<(Priority, Vec<Arc<Packet>>)>;
+
+/// Something received from the server.
+#[derive(Debug)]
+pub enum StreamItem {
+    Packets(Vec<Arc<Packet>>),
+    DropReport(DropReport),
+    Flows(Vec<FlowRecord>),
+    Stats(Stats),
+    /// Stats the server sent in reply to `Client::request_stats`.
+    Snapshot(StatsSnapshot),
+    Heartbeat,
+    /// Data the server sent with `ConnectedIpc::send_user_control`, in order with the packets
+    /// sent around it.
+    UserControl(Vec<u8>),
+    /// A `ReconnectingClient` reconnected after the connection failed. `gap_estimate` is how
+    /// long it went without receiving in between.
+    Reconnected {
+        gap_estimate: Duration,
+    },
+    /// A message that couldn't be read was skipped, with `ClientConfig::resync`. `bytes_skipped`
+    /// is how much batch data was lost, or zero if the message couldn't be read at all.
+    Corrupt {
+        bytes_skipped: usize,
+    },
+    /// The connection closed. This is the last item received.
+    Closed(CloseReason),
+}
+
+/// What the receive thread hands to the client.
+#[derive(Debug)]
+pub(crate) enum Event {
+    Packets(BatchInfo, Vec<Arc<Packet>>),
+    /// A batch left encoded, with `ClientConfig::packet_views`.
+    Encoded(EncodedBatch),
+    Item(StreamItem),
+    /// A heartbeat, with the clock offset it showed.
+    Heartbeat(ClockOffset),
+    Closed(CloseReason),
+}
 
 #[derive(Debug)]
 pub struct Client {
-    receiver: CrossbeamReceiver<Option<Vec<Arc<Packet>>>>,
+    receiver: CrossbeamReceiver<Event>,
+    high: Vec<Arc<Packet>>,
     available: Vec<Arc<Packet>>,
+    /// Items waiting, each with the packets that had been buffered in each lane before it.
+    items: VecDeque<(Lanes, StreamItem)>,
+    buffered: Lanes,
+    taken: Lanes,
+    dropped: u64,
     is_closed: bool,
+    close_reason: Option<CloseReason>,
+    info: ConnectionInfo,
+    control: IpcSender<Control>,
+    allocator: Option<Arc<dyn PayloadAllocator>>,
+    last_batch: Option<BatchInfo>,
+    health_interval: Option<Duration>,
+    last_health_report: Instant,
+    newest_processed: Option<SystemTime>,
+    clock: ClockEstimator,
+    max_held_batches: Option<usize>,
+    holding: Arc<AtomicUsize>,
+    resync: bool,
 }
 
-fn process_selection_result(
-    msg_tx: &CrossbeamSender<Option<Vec<Arc<Packet>>>>,
-    result: IpcSelectionResult,
-) -> bool {
-    let mut closed = false;
-    match result {
-        IpcSelectionResult::MessageReceived(_id, message) => {
-            let opt_packets = match message.to::<Option<Vec<Packet>>>() {
-                Err(e) => {
-                    error!("Failed to convert message to packets: {:?}", e);
-                    None
-                }
-                Ok(opt_packets) => opt_packets.map(|packets| {
-                    let packets: Vec<_> = packets.into_iter().map(|p| Arc::new(p)).collect();
-                    packets
-                }),
-            };
-            closed = opt_packets.is_none();
-            if let Err(e) = msg_tx.send(opt_packets) {
-                error!("Failed to send message: {:?}", e);
-                closed = true;
+/// Count of packets that have passed through each priority lane.
+#[derive(Clone, Copy, Debug, Default)]
+struct Lanes {
+    high: u64,
+    normal: u64,
+}
+
+fn take_from(buffer: &mut Vec<Arc<Packet>>, size: usize) -> Vec<Arc<Packet>> {
+    let packets_to_take = usize::min(size, buffer.len());
+    let mut rem = buffer.split_off(packets_to_take);
+    std::mem::swap(buffer, &mut rem);
+    rem
+}
+
+/// State of the thread receiving from the server.
+struct Receiving {
+    msg_tx: CrossbeamSender<Event>,
+    control: IpcSender<Control>,
+    /// What has been received from the server so far.
+    received: Stats,
+    allocator: Option<Arc<dyn PayloadAllocator>>,
+    wire_format: WireFormat,
+    packet_views: bool,
+    resync: bool,
+}
+
+impl Receiving {
+    /// Tell the server how much has been received so far.
+    fn acknowledge(&self) {
+        let received = Control::Received {
+            batches: self.received.batches,
+            bytes: self.received.bytes,
+        };
+        if let Err(e) = self.control.send(received) {
+            debug!("Failed to acknowledge batches: {:?}", e);
+        }
+    }
+
+    /// What to do with a message that couldn't be read: skip it when resyncing, counting a
+    /// skipped batch as received so the server isn't left waiting on it, otherwise close.
+    fn corrupt(&mut self, e: Error, batch: Option<&EncodedBatch>) -> Event {
+        if !self.resync {
+            return Event::Closed(CloseReason::ReceiveError(e.to_string()));
+        }
+        let bytes_skipped = batch.map_or(0, |batch| batch.data.len());
+        if batch.is_some() {
+            self.received.batches += 1;
+            self.received.bytes += bytes_skipped as u64;
+        }
+        Event::Item(StreamItem::Corrupt { bytes_skipped })
+    }
+
+    /// Decode a received batch for `recv`.
+    fn decode(&mut self, batch: EncodedBatch) -> Event {
+        match decode_batch(&batch, self.wire_format, self.allocator.as_deref()) {
+            Err(e) => {
+                error!("Failed to decode packets: {:?}", e);
+                self.corrupt(e, Some(&batch))
+            }
+            Ok(packets) => {
+                self.received.batches += 1;
+                self.received.packets += packets.len() as u64;
+                self.received.bytes += batch.data.len() as u64;
+                Event::Packets(batch.info(), packets.into_iter().map(Arc::new).collect())
             }
         }
-        IpcSelectionResult::ChannelClosed(_id) => {
-            if let Err(e) = msg_tx.send(None) {
-                error!("Failed to send message: {:?}", e);
-                closed = true;
+    }
+
+    fn process_selection_result(&mut self, result: IpcSelectionResult) -> bool {
+        match result {
+            IpcSelectionResult::MessageReceived(_id, message) => {
+                let event = match message.to::<Message>() {
+                    Ok(Message::Batch(batch)) => match batch.header.check(&batch.data) {
+                        Err(e) => {
+                            error!("Received invalid batch: {:?}", e);
+                            self.corrupt(e, Some(&batch))
+                        }
+                        Ok(()) if self.packet_views => {
+                            self.received.batches += 1;
+                            self.received.packets += batch.header.count as u64;
+                            self.received.bytes += batch.data.len() as u64;
+                            Event::Encoded(batch)
+                        }
+                        Ok(()) => self.decode(batch),
+                    },
+                    Ok(Message::DropReport(report)) => {
+                        self.received.drops += report.count;
+                        Event::Item(StreamItem::DropReport(report))
+                    }
+                    Ok(Message::Snapshot(snapshot)) => Event::Item(StreamItem::Snapshot(snapshot)),
+                    Ok(Message::StatsRequest) => {
+                        let snapshot = StatsSnapshot {
+                            stats: self.received,
+                            queued_batches: self.msg_tx.len() as u64,
+                        };
+                        if let Err(e) = self.control.send(Control::Stats(snapshot)) {
+                            debug!("Failed to send stats: {:?}", e);
+                        }
+                        return false;
+                    }
+                    Ok(Message::Flows(flows)) => Event::Item(StreamItem::Flows(flows)),
+                    Ok(Message::Stats(stats)) => Event::Item(StreamItem::Stats(stats)),
+                    Ok(Message::Heartbeat(sent_at)) => {
+                        // Answering shows the server this end is still receiving
+                        self.acknowledge();
+                        Event::Heartbeat(ClockOffset::between(sent_at, SystemTime::now()))
+                    }
+                    Ok(Message::UserControl(data)) => Event::Item(StreamItem::UserControl(data)),
+                    Ok(Message::Hello(_)) | Ok(Message::Rejected(_)) => {
+                        error!("Unexpected handshake reply after connection established");
+                        return false;
+                    }
+                    Ok(Message::Close(reason)) => {
+                        // Acknowledge once the close is queued behind everything received, so a
+                        // server waiting on the ack knows the client has all of it
+                        if let Err(e) = self.msg_tx.send(Event::Closed(reason)) {
+                            error!("Failed to send message: {:?}", e);
+                        } else if let Err(e) = self.control.send(Control::CloseAck) {
+                            debug!("Failed to acknowledge close: {:?}", e);
+                        }
+                        return true;
+                    }
+                    Err(e) => {
+                        error!("Failed to convert message to packets: {:?}", e);
+                        self.corrupt(Error::Bincode(e), None)
+                    }
+                };
+                let closed = matches!(event, Event::Closed(_));
+                if let Err(e) = self.msg_tx.send(event) {
+                    error!("Failed to send message: {:?}", e);
+                    return true;
+                }
+                closed
+            }
+            IpcSelectionResult::ChannelClosed(_id) => {
+                if let Err(e) = self.msg_tx.send(Event::Closed(CloseReason::Disconnected)) {
+                    error!("Failed to send message: {:?}", e);
+                }
+                true
             }
         }
     }
-    closed
 }
 
 impl Client {
@@ -52,14 +406,97 @@ impl Client {
     pub fn new(server_name: String) -> Result<Client, Error> {
         Self::new_with_size(server_name, None)
     }
+
+    /// Connect to the server named by an environment variable, usually set by a parent process
+    /// with `Server::export_to_env`.
+    pub fn connect_from_env(var: &str) -> Result<Client, Error> {
+        let server_name = match std::env::var(var) {
+            Ok(server_name) => server_name,
+            Err(std::env::VarError::NotPresent) => return Err(Error::MissingEnv(var.to_owned())),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                return Err(Error::InvalidEnv {
+                    var: var.to_owned(),
+                    reason: "not valid unicode".to_owned(),
+                })
+            }
+        };
+        if server_name.is_empty() || server_name.contains('\0') {
+            return Err(Error::InvalidEnv {
+                var: var.to_owned(),
+                reason: format!("{:?} is not a server name", server_name),
+            });
+        }
+        Self::new(server_name)
+    }
+
     /// new client with a choice of bounded or unbounded based on the channel_size bening Some(size) or None
     pub fn new_with_size(
         server_name: String,
         channel_size: Option<usize>,
     ) -> Result<Client, Error> {
-        let (ipc_tx, ipc_rx) = ipc::channel::<IpcSender<Vec<IpcPacket>>>().map_err(Error::Io)?;
-        let server_sender = IpcSender::connect(server_name).map_err(Error::Io)?;
-        server_sender.send(ipc_tx).map_err(Error::Bincode)?;
+        Self::connect(
+            server_name,
+            ClientConfig::default().channel_size(channel_size),
+        )
+    }
+
+    /// Connect to a server, declaring the identity and options in `config`.
+    pub fn connect(server_name: String, config: ClientConfig) -> Result<Client, Error> {
+        let ClientConfig {
+            consumer,
+            channel_size,
+            wire_formats,
+            retry_connect,
+            allocator,
+            health_interval,
+            packet_views,
+            max_held_batches,
+            resync,
+        } = config;
+        // Generated names are absolute paths here, so a relative name was given to
+        // `Server::new_with_name`; elsewhere names are passed through as generated
+        #[cfg(all(unix, not(target_os = "macos")))]
+        let server_name = resolve_name(&server_name).display().to_string();
+        // A name a `MultiServer` moves to each new endpoint can briefly point at one already
+        // taken, so connecting by name always retries a little
+        #[cfg(all(unix, not(target_os = "macos")))]
+        let retry_connect = retry_connect.or_else(|| {
+            std::fs::symlink_metadata(&server_name)
+                .ok()
+                .filter(|metadata| metadata.file_type().is_symlink())
+                .map(|_| NAMED_RETRY_CONNECT)
+        });
+        let deadline = retry_connect.map(|timeout| Instant::now() + timeout);
+        let (hello, ipc_rx) = loop {
+            match handshake(&server_name, &consumer, &wire_formats) {
+                Err(e) if is_retryable(&e) && deadline.is_some_and(|d| Instant::now() < d) => {
+                    debug!("Retrying connection to {}: {:?}", server_name, e);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                r => break r?,
+            }
+        };
+        if hello.protocol_version > PROTOCOL_VERSION {
+            return Err(Error::Handshake(format!(
+                "Server selected unsupported protocol version {}",
+                hello.protocol_version
+            )));
+        }
+        if !wire_formats.contains(&hello.wire_format) {
+            return Err(Error::Handshake(format!(
+                "Server selected unrequested wire format {:?}",
+                hello.wire_format
+            )));
+        }
+        let mut clock = ClockEstimator::default();
+        let connected_at = SystemTime::now();
+        clock.add(ClockOffset::between(hello.sent_at, connected_at));
+        let info = ConnectionInfo {
+            connected_at,
+            peer_pid: Some(hello.pid),
+            protocol_version: hello.protocol_version,
+            wire_format: hello.wire_format,
+        };
 
         let mut receiver = IpcReceiverSet::new().map_err(Error::Io)?;
         receiver.add_opaque(ipc_rx.to_opaque()).map_err(Error::Io)?;
@@ -69,6 +506,15 @@ impl Client {
             None => crossbeam_channel::unbounded(),
         };
 
+        let mut receiving = Receiving {
+            msg_tx,
+            control: hello.control.clone(),
+            received: Stats::default(),
+            allocator: allocator.clone(),
+            wire_format: hello.wire_format,
+            packet_views,
+            resync,
+        };
         std::thread::spawn(move || {
             let mut closed = false;
             while !closed {
@@ -78,8 +524,12 @@ impl Client {
                         closed = true;
                     }
                     Ok(results) => {
+                        let batches = receiving.received.batches;
                         for result in results.into_iter() {
-                            closed = closed || process_selection_result(&msg_tx, result);
+                            closed = closed || receiving.process_selection_result(result);
+                        }
+                        if receiving.received.batches != batches {
+                            receiving.acknowledge();
                         }
                     }
                 }
@@ -87,38 +537,471 @@ impl Client {
         });
         Ok(Client {
             receiver: msg_rx,
+            high: vec![],
             available: vec![],
+            items: VecDeque::new(),
+            buffered: Lanes::default(),
+            taken: Lanes::default(),
+            dropped: 0,
             is_closed: false,
+            close_reason: None,
+            info,
+            control: hello.control,
+            allocator,
+            last_batch: None,
+            health_interval,
+            last_health_report: Instant::now(),
+            newest_processed: None,
+            clock,
+            max_held_batches,
+            holding: Arc::new(AtomicUsize::new(0)),
+            resync,
         })
     }
 
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Ask the server for its stats, which arrive as `StreamItem::Snapshot` the next time the
+    /// server sends.
+    pub fn request_stats(&self) -> Result<(), Error> {
+        self.control
+            .send(Control::StatsRequest)
+            .map_err(Error::Bincode)
+    }
+
     pub fn take(&mut self, size: usize) -> Vec<Arc<Packet>> {
-        let packets_to_take = usize::min(size, self.available.len());
-        let mut rem = self.available.split_off(packets_to_take);
-        std::mem::swap(&mut self.available, &mut rem);
-        rem
+        self.take_lane(Priority::Normal, size)
     }
 
-    pub fn recv(&mut self, size: usize) -> Result<Option<Vec<Arc<Packet>>>, Error> {
-        if self.is_closed {
-            if self.available.is_empty() {
-                Ok(None)
-            } else {
-                Ok(Some(self.take(size)))
+    /// Take up to `size` packets from a lane, noting how far the consumer has got.
+    fn take_lane(&mut self, priority: Priority, size: usize) -> Vec<Arc<Packet>> {
+        let packets = match priority {
+            Priority::High => take_from(&mut self.high, size),
+            Priority::Normal => take_from(&mut self.available, size),
+        };
+        match priority {
+            Priority::High => self.taken.high += packets.len() as u64,
+            Priority::Normal => self.taken.normal += packets.len() as u64,
+        }
+        if let Some(packet) = packets.last() {
+            let ts = *packet.timestamp();
+            if self.newest_processed.is_none_or(|newest| ts > newest) {
+                self.newest_processed = Some(ts);
+            }
+        }
+        self.report_health();
+        packets
+    }
+
+    /// Tell the server how far behind this consumer is, if a report is due.
+    fn report_health(&mut self) {
+        let interval = match self.health_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if self.last_health_report.elapsed() < interval {
+            return;
+        }
+        self.last_health_report = Instant::now();
+        let health = ConsumerHealth {
+            buffered_batches: self.receiver.len() as u64,
+            buffered_packets: (self.high.len() + self.available.len()) as u64,
+            newest_processed: self.newest_processed,
+            reported_at: SystemTime::now(),
+        };
+        if let Err(e) = self.control.send(Control::Health(health)) {
+            debug!("Failed to report consumer health: {:?}", e);
+        }
+    }
+
+    /// Best estimate of how far the producer's clock is from this consumer's, to normalize packet
+    /// timestamps before comparing them with local time. Estimated from the handshake and
+    /// heartbeats received so far, so it is most accurate for producers sending heartbeats.
+    pub fn clock_offset(&self) -> ClockOffset {
+        self.clock
+            .estimate()
+            .unwrap_or(ClockOffset::Ahead(Duration::from_secs(0)))
+    }
+
+    /// Total number of packets the server has reported dropping.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Summary of the most recently received batch, e.g. to compute lag with `BatchInfo::lag`.
+    pub fn last_batch(&self) -> Option<&BatchInfo> {
+        self.last_batch.as_ref()
+    }
+
+    fn deliver(&mut self, event: Event) {
+        match event {
+            Event::Packets(info, packets) => {
+                self.last_batch = Some(info);
+                match info.priority {
+                    Priority::High => {
+                        self.buffered.high += packets.len() as u64;
+                        self.high.extend(packets);
+                    }
+                    Priority::Normal => {
+                        self.buffered.normal += packets.len() as u64;
+                        self.available.extend(packets);
+                    }
+                }
             }
-        } else if self.available.len() >= size {
-            Ok(Some(self.take(size)))
+            Event::Encoded(batch) => {
+                match decode_batch(&batch, self.info.wire_format, self.allocator.as_deref()) {
+                    Ok(packets) => self.deliver(Event::Packets(
+                        batch.info(),
+                        packets.into_iter().map(Arc::new).collect(),
+                    )),
+                    Err(e) if self.resync => {
+                        error!("Failed to decode packets: {:?}", e);
+                        self.deliver(Event::Item(StreamItem::Corrupt {
+                            bytes_skipped: batch.data.len(),
+                        }));
+                    }
+                    Err(e) => {
+                        error!("Failed to decode packets: {:?}", e);
+                        self.deliver(Event::Closed(CloseReason::ReceiveError(e.to_string())));
+                    }
+                }
+            }
+            Event::Item(item) => {
+                if let StreamItem::DropReport(report) = &item {
+                    self.dropped += report.count;
+                }
+                self.items.push_back((self.buffered, item));
+            }
+            Event::Heartbeat(offset) => {
+                self.clock.add(offset);
+                self.items.push_back((self.buffered, StreamItem::Heartbeat));
+            }
+            Event::Closed(reason) => {
+                self.is_closed = true;
+                self.close_reason = Some(reason.clone());
+                self.items
+                    .push_back((self.buffered, StreamItem::Closed(reason)));
+            }
+        }
+    }
+
+    /// Why the connection closed, once it has.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    fn deliver_pending(&mut self) {
+        while !self.is_closed {
+            match self.receiver.try_recv() {
+                Ok(event) => self.deliver(event),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Deliver waiting events, discarding non-packet items, and return how many packets are
+    /// waiting.
+    pub(crate) fn pending(&mut self) -> usize {
+        self.deliver_pending();
+        self.items.clear();
+        self.high.len() + self.available.len()
+    }
+
+    /// Take up to `size` waiting packets without blocking, high priority first.
+    pub(crate) fn take_pending(&mut self, size: usize) -> Vec<Arc<Packet>> {
+        if self.high.is_empty() {
+            self.take(size)
         } else {
-            let opt_packets = self.receiver.recv().map_err(Error::Recv)?;
-            if let Some(packets) = opt_packets {
-                self.available.extend(packets);
+            self.take_lane(Priority::High, size)
+        }
+    }
+
+    /// Timestamp of the packet `take_pending` would return first.
+    pub(crate) fn next_timestamp(&self) -> Option<SystemTime> {
+        self.high
+            .first()
+            .or_else(|| self.available.first())
+            .map(|p| *p.timestamp())
+    }
+
+    pub(crate) fn events(&self) -> &CrossbeamReceiver<Event> {
+        &self.receiver
+    }
+
+    pub(crate) fn receive_event(&mut self, event: Result<Event, RecvError>) {
+        match event {
+            Ok(event) => self.deliver(event),
+            Err(_) => self.deliver(Event::Closed(CloseReason::Disconnected)),
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.is_closed
+    }
+
+    /// Number of received packets waiting to be taken with `recv`.
+    pub fn buffered(&mut self) -> usize {
+        self.deliver_pending();
+        self.high.len() + self.available.len()
+    }
+
+    /// Iterate over items received from the server until the connection closes.
+    pub fn items(&mut self) -> Items<'_> {
+        Items { client: self }
+    }
+
+    /// Receive on a new thread, handing every item to `send` until the connection closes or
+    /// `send` returns false, e.g. once the channel it feeds has been dropped. Blocking in `send`
+    /// holds up receiving, so backpressure carries through to the server as with `recv_item`.
+    /// This suits the blocking send of most channels, such as
+    /// `move |item| tx.blocking_send(item).is_ok()` for a tokio mpsc channel, or
+    /// `move |item| tx.send(item).is_ok()` for flume.
+    ///
+    /// The thread returns the client once done, or the error that stopped receiving.
+    pub fn hand_off<F>(mut self, mut send: F) -> JoinHandle<Result<Client, Error>>
+    where
+        F: FnMut(StreamItem) -> bool + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            while let Some(item) = self.recv_item()? {
+                if !send(item) {
+                    debug!("Hand off stopped accepting items");
+                    break;
+                }
+            }
+            Ok(self)
+        })
+    }
+
+    /// Receive the next item from the server in the order items were sent, ending with
+    /// `StreamItem::Closed`, after which `None` is returned. Waiting high priority packets are
+    /// still returned before normal priority ones, but never ahead of an item sent before them.
+    pub fn recv_item(&mut self) -> Result<Option<StreamItem>, Error> {
+        loop {
+            // Packets buffered before the next item go first, or all of them without one
+            let (high, normal) = match self.items.front() {
+                // Packets taken some other way, e.g. with `take`, may have gone past it
+                Some((ahead, _)) => (
+                    ahead.high.saturating_sub(self.taken.high) as usize,
+                    ahead.normal.saturating_sub(self.taken.normal) as usize,
+                ),
+                None => (usize::MAX, usize::MAX),
+            };
+            if high > 0 && !self.high.is_empty() {
+                let packets = self.take_lane(Priority::High, high);
+                return Ok(Some(StreamItem::Packets(packets)));
+            }
+            if normal > 0 && !self.available.is_empty() {
+                let packets = self.take_lane(Priority::Normal, normal);
+                return Ok(Some(StreamItem::Packets(packets)));
+            }
+            if let Some((_, item)) = self.items.pop_front() {
+                return Ok(Some(item));
+            }
+            if self.is_closed {
+                return Ok(None);
+            }
+            let event = self.receiver.recv().map_err(Error::Recv)?;
+            self.deliver(event);
+        }
+    }
+
+    /// Receive up to `size` packets. Other items are discarded, though drop reports are still
+    /// counted in `dropped`; use `recv_item` to receive them.
+    pub fn recv(&mut self, size: usize) -> Result<Option<Vec<Arc<Packet>>>, Error> {
+        let opt_packets = self.recv_with_priority(size)?;
+        Ok(opt_packets.map(|(_, packets)| packets))
+    }
+
+    /// Receive up to `size` packets as a `Batch`, to be taken from in parts.
+    pub fn recv_batch(&mut self, size: usize) -> Result<Option<Batch>, Error> {
+        Ok(self.recv(size)?.map(Batch::from))
+    }
+
+    /// Receive every packet from up to `max` batches in one call, high priority first, waiting
+    /// only until the first batch arrives. Packets already buffered count as one batch. Other
+    /// items are discarded as with `recv`. Returns `None` once the connection has closed.
+    pub fn recv_many(&mut self, max: usize) -> Result<Option<Vec<Arc<Packet>>>, Error> {
+        let mut batches = usize::from(!self.high.is_empty() || !self.available.is_empty());
+        while batches < max.max(1) && !self.is_closed {
+            let event = if batches == 0 {
+                self.receiver.recv().map_err(Error::Recv)?
             } else {
-                self.is_closed = true;
-                if self.available.is_empty() {
-                    return Ok(None);
+                match self.receiver.try_recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
                 }
+            };
+            if matches!(event, Event::Packets(..) | Event::Encoded(_)) {
+                batches += 1;
+            }
+            self.deliver(event);
+        }
+        self.items.clear();
+        if batches == 0 {
+            return Ok(None);
+        }
+        let mut packets = self.take_lane(Priority::High, usize::MAX);
+        packets.extend(self.take(usize::MAX));
+        Ok(Some(packets))
+    }
+
+    /// Batches from `recv_views` not yet released by dropping every handle to them.
+    pub fn held_batches(&self) -> usize {
+        self.holding.load(Ordering::Relaxed)
+    }
+
+    /// Receive the next batch still encoded, to borrow its packets with `EncodedPackets::views`
+    /// instead of copying each one. Needs `ClientConfig::packet_views`; without it batches are
+    /// decoded as they arrive and left for `recv`, so this only returns `None` once the
+    /// connection has closed. Other items are discarded as with `recv`.
+    pub fn recv_views(&mut self) -> Result<Option<EncodedPackets>, Error> {
+        if let Some(max) = self.max_held_batches {
+            if self.held_batches() >= max {
+                return Err(Error::TooManyHeld(max));
+            }
+        }
+        loop {
+            self.items.clear();
+            if self.is_closed {
+                return Ok(None);
+            }
+            match self.receiver.recv().map_err(Error::Recv)? {
+                Event::Encoded(batch) => {
+                    self.last_batch = Some(batch.info());
+                    return Ok(Some(EncodedPackets::new(
+                        batch,
+                        self.info.wire_format,
+                        self.allocator.clone(),
+                        Arc::clone(&self.holding),
+                    )));
+                }
+                event => self.deliver(event),
+            }
+        }
+    }
+
+    /// Receive up to `size` packets, waiting no later than `deadline`. Returns whatever packets
+    /// are waiting as soon as any arrive, or `Ok(None)` if the deadline passes, a heartbeat
+    /// arrives without packets, or the connection has closed (see `close_reason`).
+    pub fn recv_deadline(
+        &mut self,
+        size: usize,
+        deadline: Instant,
+    ) -> Result<Option<Vec<Arc<Packet>>>, Error> {
+        let mut heartbeat = self.deliver_heartbeats();
+        loop {
+            if !self.high.is_empty() {
+                return Ok(Some(self.take_lane(Priority::High, size)));
+            }
+            if !self.available.is_empty() {
+                return Ok(Some(self.take(size)));
+            }
+            if self.is_closed || heartbeat {
+                return Ok(None);
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(timeout) {
+                Ok(event) => self.deliver(event),
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Recv(RecvError)),
             }
-            Ok(Some(self.take(size)))
+            heartbeat = self.deliver_heartbeats();
         }
     }
+
+    /// Deliver waiting events, discarding non-packet items, and return whether any were
+    /// heartbeats.
+    fn deliver_heartbeats(&mut self) -> bool {
+        self.deliver_pending();
+        let heartbeat = self
+            .items
+            .iter()
+            .any(|(_, item)| matches!(item, StreamItem::Heartbeat));
+        self.items.clear();
+        heartbeat
+    }
+
+    /// Receive packets by copying them into `buffers`, replacing what it held, and return how
+    /// many were received or `None` once the connection has closed. Packets are still decoded
+    /// into their own payload buffers first, which are handed back to the allocator once copied
+    /// if one is configured.
+    pub fn recv_into(&mut self, buffers: &mut BufferSet) -> Result<Option<usize>, Error> {
+        buffers.clear();
+        let packets = match self.recv(buffers.max_packets())? {
+            Some(packets) => packets,
+            None => return Ok(None),
+        };
+        for packet in packets {
+            buffers.push(packet.as_ref());
+            if let Some(allocator) = &self.allocator {
+                if let Ok(packet) = Arc::try_unwrap(packet) {
+                    allocator.release(packet.into_data());
+                }
+            }
+        }
+        Ok(Some(buffers.len()))
+    }
+
+    /// Receive up to `size` packets along with the lane they were sent on. Packets sent with
+    /// high priority are returned before any normal priority packets that are waiting.
+    pub fn recv_with_priority(&mut self, size: usize) -> Result<Delivery, Error> {
+        self.deliver_pending();
+        self.items.clear();
+        let mut received = false;
+        loop {
+            if !self.high.is_empty() {
+                return Ok(Some((Priority::High, self.take_lane(Priority::High, size))));
+            }
+            if !self.available.is_empty()
+                && (received || self.is_closed || self.available.len() >= size)
+            {
+                return Ok(Some((Priority::Normal, self.take(size))));
+            }
+            if self.is_closed {
+                return Ok(None);
+            }
+            let event = self.receiver.recv().map_err(Error::Recv)?;
+            self.deliver(event);
+            self.deliver_pending();
+            self.items.clear();
+            received = true;
+        }
+    }
+}
+
+/// Let the server know not to expect anything more, so its sends fail with
+/// `Error::ConsumerGone` rather than an opaque channel error.
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Err(e) = self.control.send(Control::Goodbye) {
+            debug!("Failed to say goodbye: {:?}", e);
+        }
+    }
+}
+
+/// Iterator over items received by a `Client`.
+pub struct Items<'a> {
+    client: &'a mut Client,
+}
+
+impl<'a> Items<'a> {
+    /// Only yield received packets, skipping every other item.
+    pub fn filter_packets(self) -> impl Iterator<Item = Result<Vec<Arc<Packet>>, Error>> + 'a {
+        self.filter_map(|item| match item {
+            Ok(StreamItem::Packets(packets)) => Some(Ok(packets)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Result<StreamItem, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.client.recv_item().transpose()
+    }
 }
@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// Number of recent samples an estimate is taken from, so it follows clocks drifting apart.
+const WINDOW: usize = 16;
+
+/// How far the producer's clock is from the consumer's, see `Client::clock_offset`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClockOffset {
+    /// The producer's clock reads later than the consumer's.
+    Ahead(Duration),
+    /// The producer's clock reads earlier than the consumer's.
+    Behind(Duration),
+}
+
+impl ClockOffset {
+    /// Offset seen when something stamped `producer` by the producer arrived at `consumer`. This
+    /// underestimates the offset by however long the message took to arrive.
+    pub(crate) fn between(producer: SystemTime, consumer: SystemTime) -> ClockOffset {
+        match producer.duration_since(consumer) {
+            Ok(ahead) => ClockOffset::Ahead(ahead),
+            Err(e) => ClockOffset::Behind(e.duration()),
+        }
+    }
+
+    fn nanos(&self) -> i128 {
+        match self {
+            ClockOffset::Ahead(d) => d.as_nanos() as i128,
+            ClockOffset::Behind(d) => -(d.as_nanos() as i128),
+        }
+    }
+
+    /// Convert a timestamp taken on the producer's clock, such as a packet's, to the consumer's.
+    pub fn normalize(&self, ts: SystemTime) -> SystemTime {
+        match self {
+            ClockOffset::Ahead(d) => ts.checked_sub(*d).unwrap_or(ts),
+            ClockOffset::Behind(d) => ts.checked_add(*d).unwrap_or(ts),
+        }
+    }
+}
+
+/// Estimates the clock offset from the handshake and heartbeats, each stamped with the time the
+/// producer sent it. Every sample is short of the true offset by its transit time, so the
+/// largest recent sample, the one that arrived quickest, is the best estimate.
+#[derive(Debug, Default)]
+pub(crate) struct ClockEstimator {
+    samples: VecDeque<ClockOffset>,
+}
+
+impl ClockEstimator {
+    pub fn add(&mut self, sample: ClockOffset) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn estimate(&self) -> Option<ClockOffset> {
+        self.samples.iter().max_by_key(|s| s.nanos()).copied()
+    }
+}
@@ -0,0 +1,264 @@
+use crate::aggregate::{
+    read_u16, FlowKey, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ETHERTYPE_QINQ, ETHERTYPE_VLAN, PROTOCOL_TCP,
+};
+use crate::packet::{AsIpcPacket, Packet};
+use std::time::SystemTime;
+
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+/// Largest IP packet a run is coalesced into, as IP lengths are 16 bits.
+const MAX_IP_LEN: usize = 65535;
+
+/// Where a TCP segment's headers and payload sit in an ethernet frame.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    flow: FlowKey,
+    ipv6: bool,
+    ip_offset: usize,
+    tcp_offset: usize,
+    payload_offset: usize,
+    /// End of the IP packet, ahead of any ethernet padding.
+    end: usize,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+}
+
+impl Segment {
+    fn parse(frame: &[u8]) -> Option<Segment> {
+        let flow = FlowKey::from_ethernet(frame)?;
+        if flow.protocol != PROTOCOL_TCP {
+            return None;
+        }
+        let mut offset = 12;
+        let mut ethertype = read_u16(frame, offset)?;
+        while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+            offset += 4;
+            ethertype = read_u16(frame, offset)?;
+        }
+        let ip_offset = offset + 2;
+        let (ipv6, tcp_offset, ip_len) = match ethertype {
+            ETHERTYPE_IPV4 => {
+                let header_len = (*frame.get(ip_offset)? as usize & 0x0f) * 4;
+                // Fragments can't be coalesced
+                if read_u16(frame, ip_offset + 6)? & 0x3fff != 0 {
+                    return None;
+                }
+                let total_len = read_u16(frame, ip_offset + 2)? as usize;
+                (false, ip_offset + header_len, total_len)
+            }
+            // TCP directly follows the fixed header, as `FlowKey` found it there
+            ETHERTYPE_IPV6 => (
+                true,
+                ip_offset + 40,
+                40 + read_u16(frame, ip_offset + 4)? as usize,
+            ),
+            _ => return None,
+        };
+        let end = ip_offset + ip_len;
+        let tcp = frame.get(tcp_offset..end)?;
+        let header_len = (*tcp.get(12)? >> 4) as usize * 4;
+        if header_len < 20 || header_len > tcp.len() {
+            return None;
+        }
+        let field =
+            |at: usize| u32::from_be_bytes([tcp[at], tcp[at + 1], tcp[at + 2], tcp[at + 3]]);
+        Some(Segment {
+            flow,
+            ipv6,
+            ip_offset,
+            tcp_offset,
+            payload_offset: tcp_offset + header_len,
+            end,
+            seq: field(4),
+            ack: field(8),
+            flags: tcp[13],
+        })
+    }
+
+    fn payload_len(&self) -> usize {
+        self.end - self.payload_offset
+    }
+
+    /// Only segments carrying data with nothing but ACK set start a run. PSH ends one.
+    fn starts_run(&self) -> bool {
+        self.flags == TCP_ACK && self.payload_len() > 0
+    }
+
+    /// Whether `next` continues a run started by this segment, expecting `next_seq`.
+    fn continued_by(&self, next: &Segment, next_seq: u32) -> bool {
+        next.flow == self.flow
+            && next.ipv6 == self.ipv6
+            && next.flags & !TCP_PSH == TCP_ACK
+            && next.seq == next_seq
+            && next.ack == self.ack
+            && next.ip_offset == self.ip_offset
+            && next.payload_offset - next.ip_offset == self.payload_offset - self.ip_offset
+            && next.payload_len() > 0
+    }
+}
+
+/// A run of segments being coalesced, holding the first segment's headers.
+struct Run {
+    first: Segment,
+    data: Vec<u8>,
+    next_seq: u32,
+    segments: u32,
+}
+
+impl Run {
+    fn new(frame: &[u8], first: Segment) -> Run {
+        Run {
+            first,
+            data: frame[..first.end].to_vec(),
+            next_seq: first.seq.wrapping_add(first.payload_len() as u32),
+            segments: 1,
+        }
+    }
+
+    fn ip_len(&self) -> usize {
+        self.data.len() - self.first.ip_offset
+    }
+
+    fn append(&mut self, frame: &[u8], segment: &Segment) {
+        self.data
+            .extend_from_slice(&frame[segment.payload_offset..segment.end]);
+        self.data[self.first.tcp_offset + 13] |= segment.flags & TCP_PSH;
+        self.next_seq = segment.seq.wrapping_add(segment.payload_len() as u32);
+        self.segments += 1;
+    }
+
+    fn finish(mut self, ts: SystemTime, interface: Option<u32>) -> Packet {
+        let ip = self.first.ip_offset;
+        if self.first.ipv6 {
+            let payload_len = (self.ip_len() - 40) as u16;
+            self.data[ip + 4..ip + 6].copy_from_slice(&payload_len.to_be_bytes());
+        } else {
+            let total_len = self.ip_len() as u16;
+            self.data[ip + 2..ip + 4].copy_from_slice(&total_len.to_be_bytes());
+            self.data[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+            let header = &self.data[ip..self.first.tcp_offset];
+            let mut sum = header
+                .chunks(2)
+                .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+                .sum::<u32>();
+            while sum > 0xffff {
+                sum = (sum & 0xffff) + (sum >> 16);
+            }
+            self.data[ip + 10..ip + 12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        }
+        Packet::new(ts, self.data)
+            .with_interface(interface)
+            .with_segments(self.segments)
+    }
+}
+
+/// A packet as sent, either as given or coalesced from a run of them.
+pub(crate) enum Coalesced<T> {
+    Single(T),
+    Merged(Packet),
+}
+
+impl<T: AsIpcPacket> AsIpcPacket for Coalesced<T> {
+    fn timestamp(&self) -> &SystemTime {
+        match self {
+            Coalesced::Single(packet) => packet.timestamp(),
+            Coalesced::Merged(packet) => packet.timestamp(),
+        }
+    }
+    fn data(&self) -> &[u8] {
+        match self {
+            Coalesced::Single(packet) => packet.data(),
+            Coalesced::Merged(packet) => packet.data(),
+        }
+    }
+    fn interface(&self) -> Option<u32> {
+        match self {
+            Coalesced::Single(packet) => packet.interface(),
+            Coalesced::Merged(packet) => packet.interface(),
+        }
+    }
+    fn segments(&self) -> u32 {
+        match self {
+            Coalesced::Single(packet) => packet.segments(),
+            Coalesced::Merged(packet) => packet.segments(),
+        }
+    }
+    fn orig_len(&self) -> Option<u32> {
+        match self {
+            Coalesced::Single(packet) => packet.orig_len(),
+            Coalesced::Merged(packet) => packet.orig_len(),
+        }
+    }
+}
+
+/// Coalesces runs of consecutive in order TCP segments of a flow into one packet of up to
+/// `max_bytes` IP bytes, passing every other packet through as is. With no limit nothing is
+/// coalesced.
+pub(crate) struct Coalesce<I: Iterator> {
+    packets: I,
+    max_bytes: Option<usize>,
+    pending: Option<I::Item>,
+}
+
+impl<I: Iterator> Coalesce<I> {
+    pub fn new(packets: I, max_bytes: Option<usize>) -> Coalesce<I> {
+        Coalesce {
+            packets,
+            max_bytes: max_bytes.map(|max| max.min(MAX_IP_LEN)),
+            pending: None,
+        }
+    }
+}
+
+impl<I, T> Iterator for Coalesce<I>
+where
+    I: Iterator<Item = T>,
+    T: AsIpcPacket,
+{
+    type Item = Coalesced<T>;
+
+    fn next(&mut self) -> Option<Coalesced<T>> {
+        let first = self.pending.take().or_else(|| self.packets.next())?;
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Some(Coalesced::Single(first)),
+        };
+        let segment = match Segment::parse(first.data()).filter(Segment::starts_run) {
+            Some(segment) => segment,
+            None => return Some(Coalesced::Single(first)),
+        };
+        let mut run: Option<Run> = None;
+        for next in self.packets.by_ref() {
+            let (next_seq, ip_len) = match &run {
+                Some(run) => (run.next_seq, run.ip_len()),
+                None => (
+                    segment.seq.wrapping_add(segment.payload_len() as u32),
+                    segment.end - segment.ip_offset,
+                ),
+            };
+            let continues = Segment::parse(next.data()).filter(|next_segment| {
+                segment.continued_by(next_segment, next_seq)
+                    && ip_len + next_segment.payload_len() <= max_bytes
+                    && next.interface() == first.interface()
+            });
+            match continues {
+                Some(next_segment) => {
+                    run.get_or_insert_with(|| Run::new(first.data(), segment))
+                        .append(next.data(), &next_segment);
+                    if next_segment.flags & TCP_PSH != 0 {
+                        break;
+                    }
+                }
+                None => {
+                    self.pending = Some(next);
+                    break;
+                }
+            }
+        }
+        Some(match run {
+            Some(run) => Coalesced::Merged(run.finish(*first.timestamp(), first.interface())),
+            None => Coalesced::Single(first),
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use crate::client::Client;
+use crate::errors::Error;
+use crate::packet::{AsIpcPacket, Packet};
+use crossbeam_channel::Select;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How a `Collector` chooses which source to receive from next.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Order {
+    /// Take turns between sources with packets waiting.
+    #[default]
+    RoundRobin,
+    /// Receive from the source whose next packet is oldest. Only packets already received are
+    /// compared, so a slow source never holds up the others.
+    Timestamp,
+}
+
+/// How far a `Collector` source is behind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SourceLag {
+    /// Packets received from the source but not yet collected.
+    pub buffered: usize,
+    /// How far the source's last collected packet trails the newest collected from any source,
+    /// or `None` if nothing has been collected from it yet.
+    pub behind: Option<Duration>,
+    pub closed: bool,
+}
+
+/// How far out of timestamp order a `Collector` has delivered packets, to size the window of a
+/// consumer re-sorting the merged stream.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ReorderStats {
+    pub packets: u64,
+    /// Packets older than a packet collected before them.
+    pub late: u64,
+    /// Late packets older than the newest collected before them by more than the reorder window.
+    pub outside_window: u64,
+    /// Most any packet was older than the newest collected before it.
+    pub max_lateness: Duration,
+}
+
+/// Packets collected from the source at an index.
+type Collected = Option<(usize, Vec<Arc<Packet>>)>;
+
+struct Source {
+    client: Client,
+    last: Option<SystemTime>,
+}
+
+/// Merges packets from several producers into one stream.
+///
+/// Each source buffers independently in its `Client`, so connect clients with a bounded
+/// `ClientConfig::channel_size` to stop one slow producer from growing without limit, while the
+/// others keep flowing.
+#[derive(Default)]
+pub struct Collector {
+    sources: Vec<Source>,
+    order: Order,
+    next: usize,
+    window: Duration,
+    newest: Option<SystemTime>,
+    reorder: ReorderStats,
+}
+
+impl Collector {
+    pub fn new(order: Order) -> Collector {
+        Collector {
+            order,
+            ..Collector::default()
+        }
+    }
+
+    /// Lateness a consumer re-sorting the collected packets would tolerate, against which
+    /// `ReorderStats::outside_window` is counted. Defaults to zero.
+    pub fn reorder_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// How far out of order packets have been collected so far.
+    pub fn reorder_stats(&self) -> ReorderStats {
+        self.reorder
+    }
+
+    /// Add a source, returning the index it is identified by.
+    pub fn add(&mut self, client: Client) -> usize {
+        self.sources.push(Source { client, last: None });
+        self.sources.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Receive up to `size` packets from one source, along with that source's index. Returns
+    /// `None` once every source has closed and been drained.
+    pub fn recv(&mut self, size: usize) -> Result<Collected, Error> {
+        loop {
+            if let Some(index) = self.choose() {
+                let source = &mut self.sources[index];
+                let packets = source.client.take_pending(size);
+                if let Some(last) = packets.last() {
+                    source.last = Some(*last.timestamp());
+                }
+                for packet in packets.iter() {
+                    self.track_order(*packet.timestamp());
+                }
+                self.next = index + 1;
+                return Ok(Some((index, packets)));
+            }
+
+            let open: Vec<_> = (0..self.sources.len())
+                .filter(|&i| !self.sources[i].client.is_closed())
+                .collect();
+            if open.is_empty() {
+                return Ok(None);
+            }
+            let (index, event) = {
+                let mut select = Select::new();
+                for &i in open.iter() {
+                    select.recv(self.sources[i].client.events());
+                }
+                let operation = select.select();
+                let index = open[operation.index()];
+                (index, operation.recv(self.sources[index].client.events()))
+            };
+            self.sources[index].client.receive_event(event);
+        }
+    }
+
+    fn track_order(&mut self, ts: SystemTime) {
+        self.reorder.packets += 1;
+        let newest = *self.newest.get_or_insert(ts);
+        match newest.duration_since(ts) {
+            Ok(lateness) if lateness > Duration::from_secs(0) => {
+                self.reorder.late += 1;
+                if lateness > self.window {
+                    self.reorder.outside_window += 1;
+                }
+                self.reorder.max_lateness = self.reorder.max_lateness.max(lateness);
+            }
+            _ => self.newest = Some(ts),
+        }
+    }
+
+    /// Lag of each source, by index.
+    pub fn lag(&mut self) -> Vec<SourceLag> {
+        let newest = self.sources.iter().filter_map(|s| s.last).max();
+        self.sources
+            .iter_mut()
+            .map(|source| SourceLag {
+                buffered: source.client.pending(),
+                behind: source.last.map(|last| {
+                    newest
+                        .and_then(|newest| newest.duration_since(last).ok())
+                        .unwrap_or_default()
+                }),
+                closed: source.client.is_closed(),
+            })
+            .collect()
+    }
+
+    /// Source with packets waiting that should be received from next.
+    fn choose(&mut self) -> Option<usize> {
+        let (count, next) = (self.sources.len(), self.next);
+        let mut ready = (0..count)
+            .map(|i| (next + i) % count)
+            .filter(|&i| self.sources[i].client.pending() > 0)
+            .collect::<Vec<_>>()
+            .into_iter();
+        match self.order {
+            Order::RoundRobin => ready.next(),
+            Order::Timestamp => ready.min_by_key(|&i| self.sources[i].client.next_timestamp()),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::packet::FromPayload;
+use std::fmt;
+
+/// Payload storage that keeps up to `N` bytes inline, only going to the heap for larger
+/// payloads. Use as `Packet<SmallData>`, e.g. with `EncodedPackets::packets`, to avoid an
+/// allocation per packet for small traffic such as ACKs. Packets taken with `Client::recv` are
+/// always `Packet<Vec<u8>>`.
+#[derive(Clone)]
+pub struct SmallData<const N: usize = 64> {
+    inner: Storage<N>,
+}
+
+#[derive(Clone)]
+enum Storage<const N: usize> {
+    Inline { len: usize, buf: [u8; N] },
+    Heap(Vec<u8>),
+}
+
+impl<const N: usize> SmallData<N> {
+    /// Whether the payload is held inline rather than on the heap.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.inner, Storage::Inline { .. })
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.inner {
+            Storage::Inline { len, buf } => buf[..len].to_vec(),
+            Storage::Heap(v) => v,
+        }
+    }
+}
+
+impl<const N: usize> Default for SmallData<N> {
+    fn default() -> Self {
+        SmallData {
+            inner: Storage::Inline {
+                len: 0,
+                buf: [0u8; N],
+            },
+        }
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for SmallData<N> {
+    fn as_ref(&self) -> &[u8] {
+        match &self.inner {
+            Storage::Inline { len, buf } => &buf[..*len],
+            Storage::Heap(v) => v.as_ref(),
+        }
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8]> for SmallData<N> {
+    fn from(v: &'a [u8]) -> Self {
+        let inner = if v.len() <= N {
+            let mut buf = [0u8; N];
+            buf[..v.len()].copy_from_slice(v);
+            Storage::Inline { len: v.len(), buf }
+        } else {
+            Storage::Heap(v.to_vec())
+        };
+        SmallData { inner }
+    }
+}
+
+impl<const N: usize> FromPayload for SmallData<N> {
+    fn from_payload(data: &[u8]) -> Self {
+        SmallData::from(data)
+    }
+}
+
+impl<const N: usize> From<Vec<u8>> for SmallData<N> {
+    fn from(v: Vec<u8>) -> Self {
+        if v.len() <= N {
+            SmallData::from(v.as_slice())
+        } else {
+            SmallData {
+                inner: Storage::Heap(v),
+            }
+        }
+    }
+}
+
+impl<const N: usize> fmt::Debug for SmallData<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_ref()).finish()
+    }
+}
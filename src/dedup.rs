@@ -0,0 +1,60 @@
+use crate::packet::AsIpcPacket;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Suppresses packets identical to one seen recently, keyed by a hash of the packet payload and
+/// its timestamp rounded down to a bucket. Keeps the `window` most recently seen keys.
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: usize,
+    bucket: Duration,
+    recent: VecDeque<u64>,
+    suppressed: u64,
+}
+
+impl Deduplicator {
+    pub fn new(window: usize, bucket: Duration) -> Deduplicator {
+        Deduplicator {
+            window: window.max(1),
+            bucket,
+            recent: VecDeque::with_capacity(window),
+            suppressed: 0,
+        }
+    }
+
+    fn key<T: AsIpcPacket + ?Sized>(&self, packet: &T) -> u64 {
+        let since_epoch = packet
+            .timestamp()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let bucket = since_epoch.as_nanos() / self.bucket.as_nanos().max(1);
+        let mut hasher = DefaultHasher::new();
+        bucket.hash(&mut hasher);
+        packet.data().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Whether `packet` duplicates one seen within the window. Packets that are not duplicates
+    /// are remembered.
+    pub fn is_duplicate<T: AsIpcPacket + ?Sized>(&mut self, packet: &T) -> bool {
+        let key = self.key(packet);
+        if let Some(pos) = self.recent.iter().position(|k| *k == key) {
+            self.recent.remove(pos);
+            self.recent.push_back(key);
+            self.suppressed += 1;
+            return true;
+        }
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(key);
+        false
+    }
+
+    /// Number of duplicates suppressed so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
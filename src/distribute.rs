@@ -0,0 +1,129 @@
+use crate::aggregate::FlowKey;
+use crate::errors::Error;
+use crate::info::ConsumerInfo;
+use crate::packet::AsIpcPacket;
+use crate::server::ConnectedIpc;
+use crate::shutdown::{drain, DrainStatus};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+
+type KeyFn = Box<dyn Fn(&dyn AsIpcPacket) -> u64 + Send>;
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Sends each packet to exactly one of a set of connections, chosen by a key so that packets
+/// with the same key always go to the same connection.
+///
+/// Connections are chosen by rendezvous hashing, so when one is added or removed only the keys
+/// that must move do: keys on a removed connection are spread over the others, and a new
+/// connection takes its share of keys from each. Every other key stays where it was.
+pub struct Distributor {
+    destinations: Vec<(usize, ConnectedIpc)>,
+    next_id: usize,
+    key: KeyFn,
+}
+
+impl Distributor {
+    pub fn new<F, K>(key: F) -> Distributor
+    where
+        F: Fn(&dyn AsIpcPacket) -> K + Send + 'static,
+        K: Hash,
+    {
+        Distributor {
+            destinations: vec![],
+            next_id: 0,
+            key: Box::new(move |packet| hash(&key(packet))),
+        }
+    }
+
+    /// Distribute ethernet frames by `FlowKey`. Non IP traffic all goes to one connection.
+    pub fn by_flow() -> Distributor {
+        Distributor::new(|packet| FlowKey::from_ethernet(packet.data()))
+    }
+
+    /// Add a connection, returning its id. It takes over a share of keys straight away.
+    pub fn add(&mut self, connection: ConnectedIpc) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.destinations.push((id, connection));
+        id
+    }
+
+    /// Stop sending to connection `id`, returning it so it can be closed. Its keys move to the
+    /// remaining connections.
+    pub fn remove(&mut self, id: usize) -> Option<ConnectedIpc> {
+        let position = self.destinations.iter().position(|(i, _)| *i == id)?;
+        Some(self.destinations.remove(position).1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.destinations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.destinations.is_empty()
+    }
+
+    fn position_for(&self, key: u64) -> Option<usize> {
+        self.destinations
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (id, _))| hash(&(key, *id)))
+            .map(|(position, _)| position)
+    }
+
+    /// Id of the connection `packet` would currently be sent to.
+    pub fn destination_for<T: AsIpcPacket>(&self, packet: &T) -> Option<usize> {
+        self.position_for((self.key)(packet))
+            .map(|position| self.destinations[position].0)
+    }
+
+    /// Send each packet to the connection its key maps to. Does nothing without connections.
+    pub fn send<T: AsIpcPacket>(&mut self, packets: &[T]) -> Result<(), Error> {
+        let mut assigned = vec![vec![]; self.destinations.len()];
+        for packet in packets {
+            if let Some(position) = self.position_for((self.key)(packet)) {
+                assigned[position].push(packet);
+            }
+        }
+        for (packets, (_, connection)) in assigned.into_iter().zip(self.destinations.iter()) {
+            connection.encode(packets)?.flush(connection)?;
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        for (_, connection) in self.destinations.iter_mut() {
+            connection.close()?;
+        }
+        Ok(())
+    }
+
+    /// Stop distributing and close every connection in the order they were added, flushing
+    /// what was sent to each and waiting until `deadline` for the workers to acknowledge, e.g.
+    /// for a rolling restart.
+    pub fn shutdown(self, deadline: Instant) -> Vec<WorkerDrain> {
+        drain(self.destinations, deadline)
+            .into_iter()
+            .map(|(id, consumer, status)| WorkerDrain {
+                id,
+                consumer,
+                status,
+            })
+            .collect()
+    }
+}
+
+/// How one connection of a `Distributor` drained on shutdown.
+#[derive(Clone, Debug)]
+pub struct WorkerDrain {
+    /// Id returned by `Distributor::add`.
+    pub id: usize,
+    pub consumer: ConsumerInfo,
+    pub status: DrainStatus,
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Why the producer dropped packets instead of sending them.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum DropReason {
+    /// The producer could not keep up, or a buffer overflowed.
+    Overflow,
+    /// The producer deliberately reduced what it sends, e.g. under load.
+    Degraded,
+    /// A packet could not be encoded, so was skipped, see `EncodeErrorPolicy::SkipPacket`.
+    EncodeFailed,
+    Other(String),
+}
+
+/// Number of packets dropped by the producer since its previous report, delivered in-band to
+/// the client.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DropReport {
+    pub count: u64,
+    pub reason: DropReason,
+}
+
+/// Drops recorded on a connection that have not been reported yet.
+#[derive(Debug)]
+pub(crate) struct DropAccounting {
+    pending: Vec<DropReport>,
+    interval: Duration,
+    last_report: Instant,
+}
+
+impl Default for DropAccounting {
+    fn default() -> Self {
+        DropAccounting {
+            pending: vec![],
+            interval: Duration::from_secs(1),
+            last_report: Instant::now(),
+        }
+    }
+}
+
+impl DropAccounting {
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn record(&mut self, count: u64, reason: DropReason) {
+        match self.pending.iter_mut().find(|r| r.reason == reason) {
+            Some(report) => report.count += count,
+            None => self.pending.push(DropReport { count, reason }),
+        }
+    }
+
+    /// Reports to send now, if any are pending and the report interval has elapsed or `force`
+    /// is set.
+    pub fn take_due(&mut self, force: bool) -> Vec<DropReport> {
+        if self.pending.is_empty() || !(force || self.last_report.elapsed() >= self.interval) {
+            return vec![];
+        }
+        self.last_report = Instant::now();
+        std::mem::take(&mut self.pending)
+    }
+}
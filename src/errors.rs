@@ -1,3 +1,4 @@
+use crate::message::{RejectReason, WireFormat};
 use thiserror::Error as ThisError;
 
 #[derive(Debug, ThisError)]
@@ -10,9 +11,58 @@ pub enum Error {
     Utf8(#[from] std::str::Utf8Error),
     #[error("Error during bincode: {0:?}")]
     Bincode(#[from] bincode::Error),
+    #[error("Failed to send {message} carrying {packets} packets in {bytes} bytes: {source}")]
+    Send {
+        message: &'static str,
+        packets: usize,
+        bytes: usize,
+        #[source]
+        source: bincode::Error,
+    },
+    #[error("Failed to decode packet {index} of {count} in batch: {source}")]
+    Decode {
+        index: usize,
+        count: usize,
+        #[source]
+        source: bincode::Error,
+    },
     #[error("Error receiving: {0:?}")]
     Recv(#[from] crossbeam_channel::RecvError),
+    #[error("Channel disconnected")]
+    Disconnected,
+    #[error("Consumer went away")]
+    ConsumerGone,
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+    #[error("Rejected by server: {0:?}")]
+    Rejected(RejectReason),
+    #[error("Batch encoded as {batch:?} but connection uses {connection:?}")]
+    WireFormatMismatch {
+        batch: WireFormat,
+        connection: WireFormat,
+    },
+    #[error("Invalid recording: {0}")]
+    InvalidRecording(String),
+    #[error("Server name already in use: {0}")]
+    NameTaken(String),
+    #[error("Invalid batch {sequence}: {reason}")]
+    InvalidBatch { sequence: u64, reason: String },
+    #[error("Already holding {0} received batches")]
+    TooManyHeld(usize),
+    #[error("Runtime directory {0} is accessible to other users")]
+    InsecureDirectory(String),
+    #[error("Environment variable {0} is not set")]
+    MissingEnv(String),
+    #[error("Environment variable {var} is invalid: {reason}")]
+    InvalidEnv { var: String, reason: String },
 }
 
-unsafe impl Sync for Error {}
-unsafe impl Send for Error {}
+impl From<ipc_channel::ipc::IpcError> for Error {
+    fn from(e: ipc_channel::ipc::IpcError) -> Self {
+        match e {
+            ipc_channel::ipc::IpcError::Bincode(e) => Error::Bincode(e),
+            ipc_channel::ipc::IpcError::Io(e) => Error::Io(e),
+            ipc_channel::ipc::IpcError::Disconnected => Error::Disconnected,
+        }
+    }
+}
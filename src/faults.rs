@@ -0,0 +1,154 @@
+use crate::message::Message;
+use std::time::Duration;
+
+/// Faults to inject into what a connection sends, to check a consumer recovers from the ways IPC
+/// can fail. See `ConnectedIpc::inject_faults`.
+///
+/// Faults only apply to batches. Batches lost or delayed by them still count as sent, as they
+/// would have been had the channel lost them.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    latency: Duration,
+    jitter: Duration,
+    drop_probability: f64,
+    reorder_probability: f64,
+    corrupt_probability: f64,
+    disconnect_after: Option<u64>,
+    seed: u64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            drop_probability: 0.0,
+            reorder_probability: 0.0,
+            corrupt_probability: 0.0,
+            disconnect_after: None,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl Faults {
+    pub fn new() -> Faults {
+        Faults::default()
+    }
+
+    /// Wait this long before sending each batch.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Wait up to this much longer than `latency`, chosen at random for each batch.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Chance each batch is silently lost, from 0 to 1.
+    pub fn drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Chance each batch is held back and sent after the next one, from 0 to 1.
+    pub fn reorder_probability(mut self, probability: f64) -> Self {
+        self.reorder_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Chance a byte of each batch's data is flipped, from 0 to 1. Only noticed for certain with
+    /// `ConnectedIpc::checksum_batches`.
+    pub fn corrupt_probability(mut self, probability: f64) -> Self {
+        self.corrupt_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Close the channel without a word once this many batches have been sent, as if the
+    /// producer had crashed.
+    pub fn disconnect_after(mut self, batches: u64) -> Self {
+        self.disconnect_after = Some(batches);
+        self
+    }
+
+    /// Seed for the random choices, so runs can be repeated.
+    pub fn seed(mut self, seed: u64) -> Self {
+        // Xorshift never leaves zero
+        self.seed = seed.max(1);
+        self
+    }
+}
+
+/// What to do with a message after injecting faults.
+pub(crate) enum Action {
+    /// Send these messages, in order, which may be none.
+    Send(Vec<Message>),
+    Disconnect,
+}
+
+/// Faults being injected into a connection.
+#[derive(Debug)]
+pub(crate) struct FaultInjector {
+    faults: Faults,
+    state: u64,
+    batches: u64,
+    held: Option<Message>,
+}
+
+impl FaultInjector {
+    pub fn new(faults: Faults) -> FaultInjector {
+        FaultInjector {
+            state: faults.seed,
+            faults,
+            batches: 0,
+            held: None,
+        }
+    }
+
+    /// A random number from 0 to 1.
+    fn random(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn apply(&mut self, mut message: Message) -> Action {
+        let batch = match &mut message {
+            Message::Batch(batch) => batch,
+            // A held batch goes ahead of anything else, so a close doesn't lose it
+            _ => return Action::Send(self.held.take().into_iter().chain(Some(message)).collect()),
+        };
+        if self
+            .faults
+            .disconnect_after
+            .is_some_and(|batches| self.batches >= batches)
+        {
+            return Action::Disconnect;
+        }
+        self.batches += 1;
+        let delay = self.faults.latency + self.faults.jitter.mul_f64(self.random());
+        if delay > Duration::from_secs(0) {
+            std::thread::sleep(delay);
+        }
+        if self.random() < self.faults.drop_probability {
+            return Action::Send(vec![]);
+        }
+        if !batch.data.is_empty() && self.random() < self.faults.corrupt_probability {
+            let len = batch.data.len();
+            let index = (self.random() * len as f64) as usize;
+            batch.data[index.min(len - 1)] ^= 0xff;
+        }
+        if let Some(held) = self.held.take() {
+            return Action::Send(vec![message, held]);
+        }
+        if self.random() < self.faults.reorder_probability {
+            self.held = Some(message);
+            return Action::Send(vec![]);
+        }
+        Action::Send(vec![message])
+    }
+}
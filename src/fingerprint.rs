@@ -0,0 +1,39 @@
+use std::fmt;
+use std::sync::Arc;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+type FingerprintFn = dyn Fn(&[u8]) -> u64 + Send + Sync;
+
+/// Computes an id for each packet once on the producer, carried alongside it so several
+/// consumers can correlate the same packet without hashing payloads again. See
+/// `ConnectedIpc::fingerprint` and `Packet::fingerprint`.
+#[derive(Clone)]
+pub struct Fingerprint(Arc<FingerprintFn>);
+
+impl Fingerprint {
+    pub fn new<F: Fn(&[u8]) -> u64 + Send + Sync + 'static>(f: F) -> Fingerprint {
+        Fingerprint(Arc::new(f))
+    }
+
+    /// FNV-1a hash of the first `len` bytes of each packet, such as its headers. The hash is
+    /// stable across builds, so fingerprints can be compared between tools.
+    pub fn headers(len: usize) -> Fingerprint {
+        Fingerprint::new(move |data| {
+            data.iter().take(len).fold(FNV_OFFSET, |hash, b| {
+                (hash ^ u64::from(*b)).wrapping_mul(FNV_PRIME)
+            })
+        })
+    }
+
+    pub fn compute(&self, data: &[u8]) -> u64 {
+        (self.0)(data)
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Fingerprint")
+    }
+}
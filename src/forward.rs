@@ -0,0 +1,163 @@
+use crate::batch::BatchBuilder;
+use crate::batching::BatchConfig;
+use crate::errors::Error;
+use crate::message::{Message, Priority};
+use crate::packet::AsIpcPacket;
+use crate::server::ConnectedIpc;
+use crate::stats::Stats;
+use log::*;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type CongestionCallback = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// Controls how `forward_packets` batches and paces packets.
+#[derive(Clone)]
+pub struct ForwardConfig {
+    batch: BatchConfig,
+    retries: usize,
+    retry_backoff: Duration,
+    congestion_threshold: Duration,
+    on_congestion: Option<CongestionCallback>,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        ForwardConfig {
+            batch: BatchConfig::default(),
+            retries: 3,
+            retry_backoff: Duration::from_millis(10),
+            congestion_threshold: Duration::from_millis(10),
+            on_congestion: None,
+        }
+    }
+}
+
+impl ForwardConfig {
+    /// When to flush batches. The flush interval is checked as packets arrive.
+    pub fn batch(mut self, batch: BatchConfig) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// Retry a failed send this many times, doubling the backoff between attempts, before
+    /// giving up.
+    pub fn retries(mut self, retries: usize, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Call `on_congestion` with the time taken whenever a send blocks for at least
+    /// `threshold`, which happens when the client falls behind.
+    pub fn on_congestion<F>(mut self, threshold: Duration, on_congestion: F) -> Self
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        self.congestion_threshold = threshold;
+        self.on_congestion = Some(Arc::new(on_congestion));
+        self
+    }
+}
+
+impl fmt::Debug for ForwardConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForwardConfig")
+            .field("batch", &self.batch)
+            .field("retries", &self.retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("congestion_threshold", &self.congestion_threshold)
+            .field("on_congestion", &self.on_congestion.is_some())
+            .finish()
+    }
+}
+
+/// Send every packet from `stream` to `connection` in batches, returning what was sent once the
+/// stream ends. Does not close the connection.
+pub fn forward_packets<I, T>(
+    stream: I,
+    connection: &ConnectedIpc,
+    config: ForwardConfig,
+) -> Result<Stats, Error>
+where
+    I: IntoIterator<Item = T>,
+    T: AsIpcPacket,
+{
+    let mut forwarder = Forwarder {
+        connection,
+        batch: BatchBuilder::with_capacity(config.batch.max_bytes)
+            .wire_format(connection.info().wire_format()),
+        oldest: None,
+        stats: Stats::default(),
+        config,
+    };
+    for packet in stream {
+        forwarder.push(&packet)?;
+    }
+    forwarder.flush()?;
+    Ok(forwarder.stats)
+}
+
+struct Forwarder<'a> {
+    connection: &'a ConnectedIpc,
+    batch: BatchBuilder,
+    oldest: Option<Instant>,
+    stats: Stats,
+    config: ForwardConfig,
+}
+
+impl<'a> Forwarder<'a> {
+    fn push<T: AsIpcPacket + ?Sized>(&mut self, packet: &T) -> Result<(), Error> {
+        self.batch.push(packet)?;
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        let batch = &self.config.batch;
+        if self.batch.len() >= batch.max_packets
+            || self.batch.encoded_len() >= batch.max_bytes
+            || batch
+                .flush_interval
+                .is_some_and(|interval| oldest.elapsed() >= interval)
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.oldest = None;
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = self.batch.take(Priority::Normal);
+        let (count, len) = (batch.header.count as u64, batch.data.len() as u64);
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = self.connection.send_message(Message::Batch(batch.clone()));
+            let elapsed = started.elapsed();
+            if elapsed >= self.config.congestion_threshold {
+                if let Some(on_congestion) = &self.config.on_congestion {
+                    on_congestion(elapsed);
+                }
+            }
+            match result {
+                Ok(()) => break,
+                Err(e) if attempt < self.config.retries => {
+                    warn!(
+                        "Failed to forward batch, retrying in {:?}: {:?}",
+                        backoff, e
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.stats.batches += 1;
+        self.stats.packets += count;
+        self.stats.bytes += len;
+        Ok(())
+    }
+}
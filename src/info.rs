@@ -0,0 +1,90 @@
+use crate::message::WireFormat;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// Details about an established connection, available from both ends.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    pub(crate) connected_at: SystemTime,
+    pub(crate) peer_pid: Option<u32>,
+    pub(crate) protocol_version: u32,
+    pub(crate) wire_format: WireFormat,
+}
+
+impl ConnectionInfo {
+    /// When this end saw the connection established.
+    pub fn connected_at(&self) -> SystemTime {
+        self.connected_at
+    }
+
+    /// Process id reported by the other end of the connection, if known.
+    pub fn peer_pid(&self) -> Option<u32> {
+        self.peer_pid
+    }
+
+    /// Protocol version both ends agreed to use.
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Wire format both ends agreed to use for batches.
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+}
+
+/// Identity and desired options declared by a client when it connects, so the server can
+/// apply per-consumer policies.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConsumerInfo {
+    pub(crate) name: Option<String>,
+    pub(crate) version: Option<String>,
+    pub(crate) snaplen: Option<u32>,
+    pub(crate) filter: Option<String>,
+    pub(crate) format: Option<String>,
+}
+
+impl ConsumerInfo {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Maximum number of bytes of each packet the consumer wants.
+    pub fn snaplen(&self) -> Option<u32> {
+        self.snaplen
+    }
+
+    /// Filter expression the consumer would like applied before packets are sent.
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_deref()
+    }
+
+    /// Packet format the consumer would like to receive.
+    pub fn format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+}
+
+/// How far behind a consumer is, reported by its client periodically.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConsumerHealth {
+    /// Batches the client has received but not yet decoded into its buffer.
+    pub buffered_batches: u64,
+    /// Packets waiting in the client's buffer to be taken.
+    pub buffered_packets: u64,
+    /// Timestamp of the newest packet the consumer has taken.
+    pub newest_processed: Option<SystemTime>,
+    pub reported_at: SystemTime,
+}
+
+impl ConsumerHealth {
+    /// How far the newest packet the consumer has taken trails the time of the report.
+    pub fn lag(&self) -> Option<Duration> {
+        self.newest_processed
+            .map(|newest| self.reported_at.duration_since(newest).unwrap_or_default())
+    }
+}
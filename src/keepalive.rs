@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+/// How a connection checks its client is alive, see `ConnectedIpc::keepalive`.
+///
+/// Heartbeats are sent whenever nothing else has been sent for `heartbeat_interval`, and the
+/// client answers each one from its receive thread, whether or not the consumer is taking
+/// packets. A client that misses `miss_threshold` heartbeats in a row has timed out, likely hung
+/// or stopped, while one that answers but hasn't been sent a batch for `idle_timeout` is only
+/// idle.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Keepalive {
+    heartbeat_interval: Duration,
+    miss_threshold: u32,
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            heartbeat_interval: Duration::from_secs(1),
+            miss_threshold: 3,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl Keepalive {
+    pub fn new() -> Keepalive {
+        Keepalive::default()
+    }
+
+    /// Longest the connection goes without sending, defaults to a second.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Heartbeats in a row the client can miss before it has timed out, defaults to 3.
+    pub fn miss_threshold(mut self, misses: u32) -> Self {
+        self.miss_threshold = misses.max(1);
+        self
+    }
+
+    /// How long without sending a batch before the connection is idle, or never with `None`,
+    /// the default.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+}
+
+/// How a client looks from `ConnectedIpc::maintain`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Liveness {
+    Active,
+    /// The client answers heartbeats, but nothing has been sent to it for a while.
+    Idle(Duration),
+    /// The client missed this many heartbeats in a row.
+    TimedOut(u32),
+}
+
+/// Keepalive bookkeeping for a connection.
+#[derive(Debug)]
+pub(crate) struct KeepaliveState {
+    keepalive: Keepalive,
+    last_sent: Instant,
+    last_batch: Instant,
+    last_heartbeat: Option<Instant>,
+    unanswered: u32,
+    liveness: Liveness,
+}
+
+impl KeepaliveState {
+    pub fn new(keepalive: Keepalive) -> KeepaliveState {
+        let now = Instant::now();
+        KeepaliveState {
+            keepalive,
+            last_sent: now,
+            last_batch: now,
+            last_heartbeat: None,
+            unanswered: 0,
+            liveness: Liveness::Active,
+        }
+    }
+
+    pub fn sent(&mut self, batch: bool, heartbeat: bool) {
+        let now = Instant::now();
+        self.last_sent = now;
+        if batch {
+            self.last_batch = now;
+        }
+        if heartbeat {
+            self.last_heartbeat = Some(now);
+            self.unanswered += 1;
+        }
+    }
+
+    /// The client sent something, so it is still receiving.
+    pub fn heard(&mut self) {
+        self.unanswered = 0;
+    }
+
+    pub fn heartbeat_due(&self) -> bool {
+        self.last_sent.elapsed() >= self.keepalive.heartbeat_interval
+    }
+
+    /// Work out how the client looks now, returning it if it changed.
+    pub fn update(&mut self) -> Option<Liveness> {
+        // The latest heartbeat only counts as missed once the client has had an interval to
+        // answer it
+        let pending = self
+            .last_heartbeat
+            .is_some_and(|at| at.elapsed() < self.keepalive.heartbeat_interval);
+        let missed = self.unanswered - u32::from(pending && self.unanswered > 0);
+        let idle = self.last_batch.elapsed();
+        let liveness = if missed >= self.keepalive.miss_threshold {
+            Liveness::TimedOut(missed)
+        } else if self.keepalive.idle_timeout.is_some_and(|t| idle >= t) {
+            Liveness::Idle(idle)
+        } else {
+            Liveness::Active
+        };
+        let changed = std::mem::discriminant(&liveness) != std::mem::discriminant(&self.liveness);
+        self.liveness = liveness;
+        if changed {
+            Some(liveness)
+        } else {
+            None
+        }
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        self.liveness
+    }
+}
@@ -1,9 +1,76 @@
+pub mod aggregate;
+mod alloc;
+mod batch;
+mod batching;
+mod broadcast;
+#[cfg(feature = "capi")]
+pub mod capi;
 mod client;
+mod clock;
+mod coalesce;
+mod collector;
+mod crc;
+mod data;
+mod dedup;
+mod distribute;
+mod drops;
 mod errors;
+mod faults;
+mod fingerprint;
+mod forward;
+mod info;
+mod keepalive;
+mod message;
+mod monitor;
+mod multi;
 mod packet;
+mod pcap;
+#[cfg(unix)]
+pub mod privsep;
+mod received;
+mod reconnect;
+mod record;
+mod send_thread;
 mod server;
+mod shard;
+mod shutdown;
+#[cfg(feature = "async")]
+mod source;
+mod stats;
+pub mod testing;
+mod timing;
+pub mod wire;
 
-pub use client::Client;
+pub use alloc::{BufferPool, BufferSet, BufferedPacket, PayloadAllocator};
+pub use batch::{BatchBuilder, BatchSlot, EncodeErrorPolicy, EncodeOptions, SerializedBatch};
+pub use batching::{BatchConfig, BatchingSender};
+pub use broadcast::{BroadcastEvent, Broadcaster, OnFailure, Policy, PolicyHandle};
+pub use client::{Client, ClientConfig, Items, StreamItem};
+pub use clock::ClockOffset;
+pub use collector::{Collector, Order, ReorderStats, SourceLag};
+pub use data::SmallData;
+pub use dedup::Deduplicator;
+pub use distribute::{Distributor, WorkerDrain};
+pub use drops::{DropReason, DropReport};
 pub use errors::Error;
-pub use packet::{AsIpcPacket, IpcPacket, Packet};
-pub use server::{ConnectedIpc, Server};
+pub use faults::Faults;
+pub use fingerprint::Fingerprint;
+pub use forward::{forward_packets, ForwardConfig};
+pub use info::{ConnectionInfo, ConsumerHealth, ConsumerInfo};
+pub use keepalive::{Keepalive, Liveness};
+pub use message::{BatchInfo, CloseReason, Priority, RejectReason, WireFormat};
+pub use monitor::{ConnectionReport, StatsMonitor};
+pub use multi::{Incoming, MultiServer, ServerEvent};
+pub use packet::{AsIpcPacket, FromPayload, IpcPacket, Packet, PacketBuilder, PacketView};
+pub use pcap::{PcapReader, PcapRecordHeader, TimestampPrecision};
+pub use received::{Batch, EncodedPackets};
+pub use reconnect::{Backoff, ExponentialBackoff, ReconnectingClient};
+pub use record::{Recorder, Replayer};
+pub use send_thread::{SendThread, SendThreadConfig};
+pub use server::{ConnectedIpc, Permit, Server, ServerConfig};
+pub use shard::{Partition, Partitions, Shard};
+pub use shutdown::{DrainStatus, Shutdown, ShutdownReport};
+#[cfg(feature = "async")]
+pub use source::{pump, IterSource, Pacing, PacketSource, PcapSource, ReplayControl};
+pub use stats::{CloseSummary, Stats, StatsSnapshot};
+pub use timing::{SendTimings, StageTimings};
@@ -0,0 +1,311 @@
+use crate::aggregate::FlowRecord;
+use crate::crc::crc32;
+use crate::drops::DropReport;
+use crate::errors::Error;
+use crate::info::{ConsumerHealth, ConsumerInfo};
+use crate::stats::{Stats, StatsSnapshot};
+use ipc_channel::ipc::IpcSender;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// Highest protocol version this build of the library can speak. Version 2 added `BatchHeader`.
+pub(crate) const PROTOCOL_VERSION: u32 = 2;
+
+/// Version of `BatchHeader` this build writes and the highest it can read.
+pub(crate) const BATCH_HEADER_VERSION: u8 = 1;
+/// `BatchHeader::crc` holds a CRC-32 of the batch data.
+pub(crate) const BATCH_FLAG_CRC: u32 = 1;
+/// Every flag this build understands. The rest are reserved for extensions such as compression
+/// or fragmentation, and a batch with any of them is rejected rather than misread.
+const BATCH_FLAGS_KNOWN: u32 = BATCH_FLAG_CRC;
+
+/// Delivery lane for a batch. Batches sent with `High` priority are handed to the client
+/// ahead of any `Normal` priority batches waiting to be received.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+/// Why a connection was closed.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CloseReason {
+    /// The producer finished sending.
+    Normal,
+    /// The producer stopped because of an error.
+    ProducerError(String),
+    /// The channel closed without the producer closing it.
+    Disconnected,
+    /// Messages from the producer could not be received.
+    ReceiveError(String),
+}
+
+/// Describes the data of a batch, so a client can tell whether it understands a batch before
+/// decoding it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct BatchHeader {
+    pub version: u8,
+    pub flags: u32,
+    /// Packets encoded in the data.
+    pub count: usize,
+    /// Bytes of data.
+    pub bytes: usize,
+    /// Position of the batch among those sent on its connection, from zero.
+    pub sequence: u64,
+    pub crc: Option<u32>,
+}
+
+impl BatchHeader {
+    pub fn new(count: usize, bytes: usize) -> BatchHeader {
+        BatchHeader {
+            version: BATCH_HEADER_VERSION,
+            flags: 0,
+            count,
+            bytes,
+            sequence: 0,
+            crc: None,
+        }
+    }
+
+    /// Check the header describes `data` and asks for nothing this build doesn't support.
+    pub fn check(&self, data: &[u8]) -> Result<(), Error> {
+        let invalid = |reason: String| {
+            Err(Error::InvalidBatch {
+                sequence: self.sequence,
+                reason,
+            })
+        };
+        if self.version > BATCH_HEADER_VERSION {
+            return invalid(format!("unsupported header version {}", self.version));
+        }
+        if self.flags & !BATCH_FLAGS_KNOWN != 0 {
+            return invalid(format!("unsupported flags {:#x}", self.flags));
+        }
+        if self.bytes != data.len() {
+            return invalid(format!(
+                "{} bytes of data, expected {}",
+                data.len(),
+                self.bytes
+            ));
+        }
+        if self.flags & BATCH_FLAG_CRC != 0 {
+            let crc = crc32(data);
+            match self.crc {
+                Some(expected) if expected == crc => {}
+                Some(expected) => {
+                    return invalid(format!("crc {:#010x}, expected {:#010x}", crc, expected))
+                }
+                None => return invalid("crc flagged but missing".to_owned()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Packets encoded back to back as `IpcPacket`s, along with how many were encoded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct EncodedBatch {
+    pub header: BatchHeader,
+    pub priority: Priority,
+    /// Timestamps of the first and last packets encoded.
+    pub first: Option<SystemTime>,
+    pub last: Option<SystemTime>,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    /// Fingerprint of each packet, or empty if the producer didn't compute them.
+    pub fingerprints: Vec<u64>,
+    /// Interface of each packet, or empty if none were captured on a known interface.
+    pub interfaces: Vec<Option<u32>>,
+    /// Segments coalesced into each packet, or empty if none were coalesced.
+    pub segments: Vec<u32>,
+    /// Length of each packet before it was truncated, or empty if none were.
+    pub orig_lens: Vec<Option<u32>>,
+}
+
+impl EncodedBatch {
+    /// Fingerprint of packet `index`, if the batch has one for every packet.
+    pub fn fingerprint(&self, index: usize) -> Option<u64> {
+        if self.fingerprints.len() == self.header.count {
+            self.fingerprints.get(index).copied()
+        } else {
+            None
+        }
+    }
+
+    /// Segments coalesced into packet `index`.
+    pub fn segments(&self, index: usize) -> u32 {
+        if self.segments.len() == self.header.count {
+            self.segments.get(index).copied().unwrap_or(1)
+        } else {
+            1
+        }
+    }
+
+    /// Interface of packet `index`, if the batch has them.
+    pub fn interface(&self, index: usize) -> Option<u32> {
+        if self.interfaces.len() == self.header.count {
+            self.interfaces.get(index).copied().flatten()
+        } else {
+            None
+        }
+    }
+
+    /// Length of packet `index` before it was truncated, if the batch has them.
+    pub fn orig_len(&self, index: usize) -> Option<u32> {
+        if self.orig_lens.len() == self.header.count {
+            self.orig_lens.get(index).copied().flatten()
+        } else {
+            None
+        }
+    }
+
+    pub fn info(&self) -> BatchInfo {
+        BatchInfo {
+            count: self.header.count,
+            sequence: self.header.sequence,
+            priority: self.priority,
+            first: self.first,
+            last: self.last,
+        }
+    }
+}
+
+/// Summary of a batch, sent ahead of its packets so it is known without decoding them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BatchInfo {
+    pub count: usize,
+    /// Position of the batch among those sent on its connection, from zero. A gap means
+    /// batches were lost on the way.
+    pub sequence: u64,
+    pub priority: Priority,
+    /// Timestamp of the first packet in the batch.
+    pub first: Option<SystemTime>,
+    /// Timestamp of the last packet in the batch.
+    pub last: Option<SystemTime>,
+}
+
+impl BatchInfo {
+    /// How far behind now the last packet in the batch is.
+    pub fn lag(&self) -> Option<Duration> {
+        self.last
+            .map(|last| SystemTime::now().duration_since(last).unwrap_or_default())
+    }
+}
+
+/// How packets in a batch are encoded on the wire.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum WireFormat {
+    /// Each packet encoded with bincode as an `IpcPacket`.
+    #[default]
+    Bincode,
+    /// As `Bincode`, but with each timestamp encoded as a u64 of nanoseconds since the UNIX
+    /// epoch rather than as a `SystemTime`, which is simpler for peers not written in Rust.
+    NanosTimestamps,
+    /// Each packet as a fixed little endian header of timestamp nanoseconds since the UNIX epoch
+    /// (u64), captured length (u32), original length (u32) and flags (u32, currently zero),
+    /// followed by the captured bytes. Nothing is serialized, so this is the cheapest format to
+    /// encode and decode. Only used when a server asks for it with `ServerConfig::wire_format`.
+    Raw,
+}
+
+impl WireFormat {
+    /// Every format this build supports, most preferred first.
+    pub(crate) fn supported() -> Vec<WireFormat> {
+        vec![
+            WireFormat::Bincode,
+            WireFormat::NanosTimestamps,
+            WireFormat::Raw,
+        ]
+    }
+}
+
+/// Why a server turned a client away during the handshake.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RejectReason {
+    /// None of the wire formats the client offered are acceptable to the server.
+    WireFormat(Vec<WireFormat>),
+    /// The server is not accepting new clients for now.
+    Paused,
+    /// The server already has as many clients as it allows.
+    AtCapacity,
+}
+
+/// First message sent by a client to the server, carrying the channel the server should send on.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Handshake {
+    pub sender: IpcSender<Message>,
+    pub pid: u32,
+    pub protocol_version: u32,
+    pub consumer: ConsumerInfo,
+    /// Formats the client can decode, most preferred first.
+    pub wire_formats: Vec<WireFormat>,
+}
+
+/// First message sent by the server in response to a `Handshake`.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Hello {
+    pub pid: u32,
+    pub protocol_version: u32,
+    pub wire_format: WireFormat,
+    pub control: IpcSender<Control>,
+    /// Producer's clock as the hello was sent.
+    pub sent_at: SystemTime,
+}
+
+/// Messages sent from a client back to its server.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum Control {
+    /// Total batches and encoded bytes received so far.
+    Received { batches: u64, bytes: u64 },
+    /// Everything up to and including the close was received.
+    CloseAck,
+    /// Periodic report of how far behind the consumer is.
+    Health(ConsumerHealth),
+    /// Ask the server for a `Message::Snapshot`.
+    StatsRequest,
+    /// Reply to `Message::StatsRequest`.
+    Stats(StatsSnapshot),
+    /// The client was dropped and will receive nothing more.
+    Goodbye,
+}
+
+/// Messages sent from a server to a connected client.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum Message {
+    Hello(Hello),
+    /// Sent instead of `Hello` when the client is turned away.
+    Rejected(RejectReason),
+    Batch(EncodedBatch),
+    DropReport(DropReport),
+    Flows(Vec<FlowRecord>),
+    Stats(Stats),
+    /// Reply to `Control::StatsRequest`.
+    Snapshot(StatsSnapshot),
+    /// Ask the client for a `Control::Stats`.
+    StatsRequest,
+    /// Sent while there is nothing else to send, with the producer's clock at the time.
+    Heartbeat(SystemTime),
+    /// Application data, delivered in order with the batches around it.
+    UserControl(#[serde(with = "serde_bytes")] Vec<u8>),
+    Close(CloseReason),
+}
+
+impl Message {
+    /// Name of the message, for errors and logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "hello",
+            Message::Rejected(_) => "rejection",
+            Message::Batch(_) => "batch",
+            Message::DropReport(_) => "drop report",
+            Message::Flows(_) => "flows",
+            Message::Stats(_) => "stats",
+            Message::Snapshot(_) => "snapshot",
+            Message::StatsRequest => "stats request",
+            Message::Heartbeat(_) => "heartbeat",
+            Message::UserControl(_) => "user control",
+            Message::Close(_) => "close",
+        }
+    }
+}
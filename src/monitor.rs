@@ -0,0 +1,166 @@
+use crate::info::ConsumerInfo;
+use crate::stats::Stats;
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use log::*;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Stats of one monitored connection, as sampled by a `StatsMonitor`.
+#[derive(Clone, Debug)]
+pub struct ConnectionReport {
+    pub consumer: ConsumerInfo,
+    /// Counters since the connection was accepted.
+    pub stats: Stats,
+    /// Counters since the previous report, or since the connection was monitored.
+    pub delta: Stats,
+    /// Time the delta covers.
+    pub elapsed: Duration,
+}
+
+impl ConnectionReport {
+    pub fn packets_per_second(&self) -> f64 {
+        self.delta.packets as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn bytes_per_second(&self) -> f64 {
+        self.delta.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+fn delta(now: &Stats, then: &Stats) -> Stats {
+    Stats {
+        packets: now.packets - then.packets,
+        bytes: now.bytes - then.bytes,
+        batches: now.batches - then.batches,
+        drops: now.drops - then.drops,
+        duplicates: now.duplicates - then.duplicates,
+        encode_failures: now.encode_failures - then.encode_failures,
+    }
+}
+
+/// Stats a connection publishes for a `StatsMonitor`, updated as it sends.
+pub(crate) type Published = Arc<Mutex<Stats>>;
+
+struct Entry {
+    consumer: ConsumerInfo,
+    stats: Weak<Mutex<Stats>>,
+    last: Stats,
+    last_at: Instant,
+}
+
+/// Samples the stats of every connection added with `ConnectedIpc::monitor` on a background
+/// thread, logging them or handing them to a callback every interval, so long running producers
+/// see throughput and drops without a sampling loop of their own. Connections leave the monitor
+/// once dropped. Sampling stops when the monitor is dropped.
+pub struct StatsMonitor {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatsMonitor {
+    /// Log each connection's stats at info level every `interval`.
+    pub fn new(interval: Duration) -> StatsMonitor {
+        StatsMonitor::with_callback(interval, |reports| {
+            for report in reports {
+                info!(
+                    "{}: {:.0} packets/s, {:.0} bytes/s, {} drops, {:?}",
+                    report.consumer.name().unwrap_or("unnamed consumer"),
+                    report.packets_per_second(),
+                    report.bytes_per_second(),
+                    report.delta.drops,
+                    report.stats
+                );
+            }
+        })
+    }
+
+    /// Call `report` with every connection's stats every `interval`, skipping intervals with no
+    /// connections.
+    pub fn with_callback<F>(interval: Duration, mut report: F) -> StatsMonitor
+    where
+        F: FnMut(&[ConnectionReport]) + Send + 'static,
+    {
+        let entries = Arc::new(Mutex::new(vec![]));
+        let (stop, stopped) = crossbeam_channel::bounded(0);
+        let handle = {
+            let entries = Arc::clone(&entries);
+            std::thread::spawn(move || {
+                // Dropping the monitor hangs up, ending the wait early
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let reports = sample(&entries);
+                    if !reports.is_empty() {
+                        report(&reports);
+                    }
+                }
+            })
+        };
+        StatsMonitor {
+            entries,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Connections being monitored.
+    pub fn len(&self) -> usize {
+        lock(&self.entries)
+            .iter()
+            .filter(|entry| entry.stats.strong_count() > 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn add(&self, consumer: ConsumerInfo, stats: Stats) -> Published {
+        let published = Arc::new(Mutex::new(stats));
+        lock(&self.entries).push(Entry {
+            consumer,
+            stats: Arc::downgrade(&published),
+            last: stats,
+            last_at: Instant::now(),
+        });
+        published
+    }
+}
+
+impl Drop for StatsMonitor {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Stats monitor panicked");
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Report every connection still alive, forgetting the rest.
+fn sample(entries: &Mutex<Vec<Entry>>) -> Vec<ConnectionReport> {
+    let now = Instant::now();
+    let mut entries = lock(entries);
+    entries.retain(|entry| entry.stats.strong_count() > 0);
+    entries
+        .iter_mut()
+        .filter_map(|entry| {
+            let published = entry.stats.upgrade()?;
+            let stats = *lock(&published);
+            let report = ConnectionReport {
+                consumer: entry.consumer.clone(),
+                stats,
+                delta: delta(&stats, &entry.last),
+                elapsed: now - entry.last_at,
+            };
+            entry.last = stats;
+            entry.last_at = now;
+            Some(report)
+        })
+        .collect()
+}
@@ -0,0 +1,324 @@
+use crate::errors::Error;
+use crate::info::{ConnectionInfo, ConsumerInfo};
+use crate::message::{Handshake, RejectReason};
+#[cfg(all(unix, not(target_os = "macos")))]
+use crate::server::relink;
+use crate::server::{ConnectedIpc, NameLink, ServerConfig};
+use crate::stats::Stats;
+use crossbeam_channel::{Receiver, Sender};
+use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
+use log::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Something that happened on a `MultiServer` operators may want to know about.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ServerEvent {
+    /// A client connected, and its connection is waiting to be taken with `accept`.
+    Connected {
+        info: ConnectionInfo,
+        consumer: ConsumerInfo,
+    },
+    /// A client was turned away.
+    Rejected { pid: u32, reason: RejectReason },
+    /// A connection accepted by the server was dropped, having sent `stats`.
+    Disconnected {
+        info: ConnectionInfo,
+        consumer: ConsumerInfo,
+        stats: Stats,
+    },
+    /// A connection's client answers heartbeats but hasn't been sent a batch for `idle_for`,
+    /// see `ConnectedIpc::keepalive`.
+    ClientIdle {
+        info: ConnectionInfo,
+        consumer: ConsumerInfo,
+        idle_for: Duration,
+    },
+    /// A connection's client missed `missed` heartbeats in a row, so is likely hung, see
+    /// `ConnectedIpc::keepalive`.
+    ClientTimedOut {
+        info: ConnectionInfo,
+        consumer: ConsumerInfo,
+        missed: u32,
+    },
+    /// Accepting a client failed before it could be told why.
+    AcceptError(String),
+}
+
+/// Counts a connection against `ServerConfig::max_clients` until dropped.
+#[derive(Debug)]
+pub(crate) struct ClientSlot {
+    clients: Arc<AtomicUsize>,
+    events: Sender<ServerEvent>,
+}
+
+impl ClientSlot {
+    /// Report an event about the connection holding this slot.
+    pub fn emit(&self, event: ServerEvent) {
+        if self.events.try_send(event).is_err() {
+            debug!("Discarding server event, too many queued");
+        }
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Number of events kept for `MultiServer::events` before newer events are discarded.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Accepts any number of clients on one well-known name.
+///
+/// Each client still gets its own endpoint: once a client connects, a fresh endpoint is created
+/// and the name is pointed at it. Clients racing that switch retry for up to a second, or as
+/// long as `ClientConfig::retry_connect` allows.
+pub struct MultiServer {
+    name: String,
+    accepted: Receiver<ConnectedIpc>,
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    clients: Arc<AtomicUsize>,
+    events: Receiver<ServerEvent>,
+    handle: Option<JoinHandle<()>>,
+    _link: NameLink,
+}
+
+impl MultiServer {
+    /// Create a server clients reach as `name`, failing with `Error::NameTaken` if the name is in
+    /// use. Not available on macOS, see `Server::new_with_name`.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub fn new(name: &str, config: ServerConfig) -> Result<MultiServer, Error> {
+        let path = config.resolve_name(name)?;
+        let (server, server_name) = IpcOneShotServer::new().map_err(Error::Io)?;
+        config.secure_endpoint(&server_name)?;
+        let link = NameLink::create(&server_name, path)?;
+        let (tx, accepted) = crossbeam_channel::unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let clients = Arc::new(AtomicUsize::new(0));
+        let (events_tx, events) = crossbeam_channel::bounded(EVENT_CAPACITY);
+
+        let acceptor = Acceptor {
+            config,
+            path: link.0.clone(),
+            stop: Arc::clone(&stop),
+            paused: Arc::clone(&paused),
+            clients: Arc::clone(&clients),
+            tx,
+            events: events_tx,
+        };
+        let handle = std::thread::spawn(move || acceptor.run(server));
+
+        Ok(MultiServer {
+            name: link.0.display().to_string(),
+            accepted,
+            stop,
+            paused,
+            clients,
+            events,
+            handle: Some(handle),
+            _link: link,
+        })
+    }
+
+    /// Name clients should connect to.
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    /// Wait for the next client to connect.
+    pub fn accept(&self) -> Result<ConnectedIpc, Error> {
+        self.accepted.recv().map_err(|_| Error::Disconnected)
+    }
+
+    /// Wait up to `timeout` for the next client to connect.
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<Option<ConnectedIpc>, Error> {
+        match self.accepted.recv_timeout(timeout) {
+            Ok(connection) => Ok(Some(connection)),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => Ok(None),
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
+        }
+    }
+
+    /// Turn away clients that connect until `resume_accept` is called. Existing connections are
+    /// unaffected.
+    pub fn pause_accept(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume_accept(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Number of accepted connections still open, including those not yet taken with `accept`.
+    pub fn clients(&self) -> usize {
+        self.clients.load(Ordering::SeqCst)
+    }
+
+    /// Events that have happened since last called.
+    pub fn events(&self) -> impl Iterator<Item = ServerEvent> + '_ {
+        self.events.try_iter()
+    }
+
+    /// Channel events are queued on, for supervisory code to wait on events as they happen
+    /// rather than polling `events`. Each event is received by only one holder.
+    pub fn event_stream(&self) -> Receiver<ServerEvent> {
+        self.events.clone()
+    }
+
+    /// Iterate over clients as they connect.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { server: self }
+    }
+}
+
+impl Drop for MultiServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            // The acceptor is blocked waiting for a client, so connect to wake it
+            while !handle.is_finished() {
+                let _ = IpcSender::<Handshake>::connect(self.name.clone());
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            if handle.join().is_err() {
+                error!("Acceptor for {} panicked", self.name);
+            }
+        }
+    }
+}
+
+/// Iterator over clients connecting to a `MultiServer`.
+pub struct Incoming<'a> {
+    server: &'a MultiServer,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = ConnectedIpc;
+
+    fn next(&mut self) -> Option<ConnectedIpc> {
+        self.server.accept().ok()
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+struct Acceptor {
+    config: ServerConfig,
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    clients: Arc<AtomicUsize>,
+    tx: Sender<ConnectedIpc>,
+    events: Sender<ServerEvent>,
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+impl Acceptor {
+    fn run(self, mut server: IpcOneShotServer<Handshake>) {
+        let path = &self.path;
+        loop {
+            let accepted = server.accept();
+            if self.stop.load(Ordering::SeqCst) {
+                return;
+            }
+            // Move the name on before completing the handshake so the next client isn't kept
+            // waiting
+            server = match IpcOneShotServer::new() {
+                Ok((next, next_name)) => {
+                    if let Err(e) = self.config.secure_endpoint(&next_name) {
+                        error!("Failed to secure endpoint for {:?}: {:?}", path, e);
+                        return;
+                    }
+                    if let Err(e) = relink(path, &next_name) {
+                        error!("Failed to relink {:?}: {:?}", path, e);
+                        return;
+                    }
+                    next
+                }
+                Err(e) => {
+                    error!("Failed to create endpoint for {:?}: {:?}", path, e);
+                    return;
+                }
+            };
+            match accepted {
+                Ok((_, handshake)) => {
+                    let pid = handshake.pid;
+                    let connected = match self.admit(&handshake) {
+                        Err(reason) => {
+                            // Queue the event first so it is there by the time the client hears
+                            self.emit(ServerEvent::Rejected {
+                                pid,
+                                reason: reason.clone(),
+                            });
+                            ServerConfig::reject(handshake, reason);
+                            continue;
+                        }
+                        Ok(slot) => self.config.connect(handshake).map(|mut connection| {
+                            connection.hold_slot(slot);
+                            connection
+                        }),
+                    };
+                    match connected {
+                        Ok(connection) => {
+                            self.emit(ServerEvent::Connected {
+                                info: connection.info().clone(),
+                                consumer: connection.consumer().clone(),
+                            });
+                            if self.tx.send(connection).is_err() {
+                                return;
+                            }
+                        }
+                        Err(Error::Rejected(reason)) => {
+                            self.emit(ServerEvent::Rejected { pid, reason })
+                        }
+                        Err(e) => {
+                            error!("Failed to complete handshake on {:?}: {:?}", path, e);
+                            self.emit(ServerEvent::AcceptError(e.to_string()));
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to accept on {:?}: {:?}", path, e);
+                    self.emit(ServerEvent::AcceptError(e.to_string()));
+                }
+            }
+        }
+    }
+
+    fn emit(&self, event: ServerEvent) {
+        if self.events.try_send(event).is_err() {
+            debug!("Discarding server event, too many queued");
+        }
+    }
+
+    /// Take a slot for a new client, or say why it should be turned away.
+    fn admit(&self, handshake: &Handshake) -> Result<ClientSlot, RejectReason> {
+        if self.paused.load(Ordering::SeqCst) {
+            return Err(RejectReason::Paused);
+        }
+        let clients = self.clients.fetch_add(1, Ordering::SeqCst);
+        let slot = ClientSlot {
+            clients: Arc::clone(&self.clients),
+            events: self.events.clone(),
+        };
+        if self.config.max_clients.is_some_and(|max| clients >= max) {
+            warn!(
+                "Turning away pid {}, already serving {} clients",
+                handshake.pid, clients
+            );
+            return Err(RejectReason::AtCapacity);
+        }
+        Ok(slot)
+    }
+}
@@ -1,8 +1,81 @@
+use crate::batch::encoding_options;
+use crate::errors::Error;
+use bincode::Options;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 pub trait AsIpcPacket {
     fn timestamp(&self) -> &std::time::SystemTime;
     fn data(&self) -> &[u8];
+    /// Interface the packet was captured on, for producers capturing more than one.
+    fn interface(&self) -> Option<u32> {
+        None
+    }
+    /// Number of captured segments coalesced into this packet, see
+    /// `ConnectedIpc::coalesce_tcp`.
+    fn segments(&self) -> u32 {
+        1
+    }
+    /// Length of the packet before it was truncated to the captured data, if it was.
+    fn orig_len(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl<T: AsIpcPacket + ?Sized> AsIpcPacket for &T {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        (**self).timestamp()
+    }
+    fn data(&self) -> &[u8] {
+        (**self).data()
+    }
+    fn interface(&self) -> Option<u32> {
+        (**self).interface()
+    }
+    fn segments(&self) -> u32 {
+        (**self).segments()
+    }
+    fn orig_len(&self) -> Option<u32> {
+        (**self).orig_len()
+    }
+}
+
+/// Lets boxed trait objects, e.g. `Box<dyn AsIpcPacket + Send>`, be sent directly.
+impl<T: AsIpcPacket + ?Sized> AsIpcPacket for Box<T> {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        (**self).timestamp()
+    }
+    fn data(&self) -> &[u8] {
+        (**self).data()
+    }
+    fn interface(&self) -> Option<u32> {
+        (**self).interface()
+    }
+    fn segments(&self) -> u32 {
+        (**self).segments()
+    }
+    fn orig_len(&self) -> Option<u32> {
+        (**self).orig_len()
+    }
+}
+
+/// Lets received packets be sent on without copying them out first.
+impl<T: AsIpcPacket + ?Sized> AsIpcPacket for std::sync::Arc<T> {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        (**self).timestamp()
+    }
+    fn data(&self) -> &[u8] {
+        (**self).data()
+    }
+    fn interface(&self) -> Option<u32> {
+        (**self).interface()
+    }
+    fn segments(&self) -> u32 {
+        (**self).segments()
+    }
+    fn orig_len(&self) -> Option<u32> {
+        (**self).orig_len()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -12,47 +85,329 @@ pub struct IpcPacket<'a> {
     data: &'a [u8],
 }
 
-impl<'a, T: AsIpcPacket> From<&'a T> for IpcPacket<'a> {
+impl<'a, T: AsIpcPacket + ?Sized> From<&'a T> for IpcPacket<'a> {
     fn from(v: &'a T) -> Self {
         IpcPacket {
-            timestamp: v.timestamp().clone(),
+            timestamp: *v.timestamp(),
             data: v.data(),
         }
     }
 }
 
-impl<'a> From<IpcPacket<'a>> for Packet {
+impl<'a> IpcPacket<'a> {
+    pub(crate) fn into_parts(self) -> (std::time::SystemTime, &'a [u8]) {
+        (self.timestamp, self.data)
+    }
+}
+
+impl<'a> AsIpcPacket for IpcPacket<'a> {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        &self.timestamp
+    }
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+}
+
+impl<'a, D: FromPayload> From<IpcPacket<'a>> for Packet<D> {
     fn from(v: IpcPacket<'a>) -> Self {
         Packet {
-            ts: v.timestamp.clone(),
-            data: v.data.to_vec(),
+            ts: v.timestamp,
+            data: D::from_payload(v.data),
+            fingerprint: None,
+            interface: None,
+            segments: 1,
+            orig_len: None,
         }
     }
 }
 
+/// A received packet borrowing its payload from the batch it arrived in, see
+/// `Client::recv_views`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PacketView<'a> {
+    ts: std::time::SystemTime,
+    data: &'a [u8],
+    fingerprint: Option<u64>,
+    interface: Option<u32>,
+    segments: u32,
+    orig_len: Option<u32>,
+}
+
+impl<'a> PacketView<'a> {
+    pub(crate) fn new(
+        ts: std::time::SystemTime,
+        data: &'a [u8],
+        fingerprint: Option<u64>,
+        interface: Option<u32>,
+        segments: u32,
+        orig_len: Option<u32>,
+    ) -> PacketView<'a> {
+        PacketView {
+            ts,
+            data,
+            fingerprint,
+            interface,
+            segments,
+            orig_len,
+        }
+    }
+
+    pub fn timestamp(&self) -> &std::time::SystemTime {
+        &self.ts
+    }
+
+    /// The payload, borrowed for as long as the batch.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Fingerprint the producer computed for this packet, if it was configured to.
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    /// Copy the packet out of the batch, to keep it.
+    pub fn to_packet(&self) -> Packet {
+        self.copy_out()
+    }
+
+    pub(crate) fn copy_out<D: FromPayload>(&self) -> Packet<D> {
+        Packet::new(self.ts, D::from_payload(self.data))
+            .with_fingerprint(self.fingerprint)
+            .with_interface(self.interface)
+            .with_segments(self.segments)
+            .with_orig_len(self.orig_len)
+    }
+}
+
+impl<'a> AsIpcPacket for PacketView<'a> {
+    fn timestamp(&self) -> &std::time::SystemTime {
+        &self.ts
+    }
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+    fn interface(&self) -> Option<u32> {
+        self.interface
+    }
+    fn segments(&self) -> u32 {
+        self.segments
+    }
+    fn orig_len(&self) -> Option<u32> {
+        self.orig_len
+    }
+}
+
+/// Payload containers a received packet can be decoded into, see `Packet`. Implement it for
+/// other containers, such as `bytes::Bytes`, to decode into them.
+pub trait FromPayload: Sized {
+    fn from_payload(data: &[u8]) -> Self;
+}
+
+impl FromPayload for Vec<u8> {
+    fn from_payload(data: &[u8]) -> Self {
+        data.to_vec()
+    }
+}
+
+impl FromPayload for Box<[u8]> {
+    fn from_payload(data: &[u8]) -> Self {
+        Box::from(data)
+    }
+}
+
+impl FromPayload for std::sync::Arc<[u8]> {
+    fn from_payload(data: &[u8]) -> Self {
+        std::sync::Arc::from(data)
+    }
+}
+
+impl FromPayload for std::rc::Rc<[u8]> {
+    fn from_payload(data: &[u8]) -> Self {
+        std::rc::Rc::from(data)
+    }
+}
+
+/// A received packet. The payload container defaults to `Vec<u8>`, but any type that can be
+/// viewed as bytes and implements `FromPayload` (e.g. `Arc<[u8]>`, `Box<[u8]>`) may be used.
 #[derive(Debug)]
-pub struct Packet {
+pub struct Packet<D = Vec<u8>> {
     ts: std::time::SystemTime,
-    data: Vec<u8>,
+    data: D,
+    fingerprint: Option<u64>,
+    interface: Option<u32>,
+    segments: u32,
+    orig_len: Option<u32>,
 }
 
-impl Packet {
-    pub fn new(ts: std::time::SystemTime, data: Vec<u8>) -> Packet {
-        Packet { ts, data }
+impl<D> Packet<D> {
+    pub fn new(ts: std::time::SystemTime, data: D) -> Packet<D> {
+        Packet {
+            ts,
+            data,
+            fingerprint: None,
+            interface: None,
+            segments: 1,
+            orig_len: None,
+        }
+    }
+
+    /// Fingerprint the producer computed for this packet, if it was configured to.
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    /// Set the fingerprint, e.g. to restore one computed by the producer.
+    pub fn with_fingerprint(mut self, fingerprint: Option<u64>) -> Packet<D> {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Mark the packet as `segments` captured segments coalesced into one, sent along with it.
+    pub fn with_segments(mut self, segments: u32) -> Packet<D> {
+        self.segments = segments;
+        self
+    }
+
+    /// Mark the packet as captured on `interface`, sent along with it.
+    pub fn with_interface(mut self, interface: Option<u32>) -> Packet<D> {
+        self.interface = interface;
+        self
     }
 
-    pub fn into_data(self) -> Vec<u8> {
+    /// Mark the packet as truncated from `orig_len` bytes when captured, sent along with it.
+    pub fn with_orig_len(mut self, orig_len: Option<u32>) -> Packet<D> {
+        self.orig_len = orig_len;
+        self
+    }
+
+    pub fn into_data(self) -> D {
         self.data
     }
 }
 
-impl AsIpcPacket for Packet {
+/// A packet encoded on its own, laid out as an `IpcPacket` followed by the metadata a batch
+/// sends alongside it.
+#[derive(Deserialize, Serialize)]
+struct StandalonePacket<'a> {
+    timestamp: std::time::SystemTime,
+    #[serde(with = "serde_bytes")]
+    data: &'a [u8],
+    fingerprint: Option<u64>,
+    interface: Option<u32>,
+    segments: u32,
+    orig_len: Option<u32>,
+}
+
+impl<D: AsRef<[u8]>> Packet<D> {
+    /// Encode this packet on its own, the same way packets are encoded within a batch, along
+    /// with its fingerprint, interface, segments and original length.
+    pub fn encode_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let packet = StandalonePacket {
+            timestamp: self.ts,
+            data: self.data.as_ref(),
+            fingerprint: self.fingerprint,
+            interface: self.interface,
+            segments: self.segments,
+            orig_len: self.orig_len,
+        };
+        encoding_options()
+            .serialize(&packet)
+            .map_err(Error::Bincode)
+    }
+}
+
+impl<D: FromPayload> Packet<D> {
+    /// Decode a packet encoded with `encode_to_vec`.
+    pub fn decode(bytes: &[u8]) -> Result<Packet<D>, Error> {
+        let packet: StandalonePacket = encoding_options()
+            .reject_trailing_bytes()
+            .deserialize(bytes)
+            .map_err(Error::Bincode)?;
+        Ok(Packet::new(packet.timestamp, D::from_payload(packet.data))
+            .with_fingerprint(packet.fingerprint)
+            .with_interface(packet.interface)
+            .with_segments(packet.segments)
+            .with_orig_len(packet.orig_len))
+    }
+}
+
+impl<'a, D: FromPayload> TryFrom<&'a [u8]> for Packet<D> {
+    type Error = Error;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Error> {
+        Packet::decode(bytes)
+    }
+}
+
+impl<D: Default> Packet<D> {
+    /// Build a packet field by field, e.g. `Packet::builder().timestamp(ts).data(buf).build()`.
+    pub fn builder() -> PacketBuilder<D> {
+        PacketBuilder::default()
+    }
+}
+
+/// Builds a `Packet`, see `Packet::builder`. Fields left unset are defaulted when built, with the
+/// timestamp taken as the time `build` is called.
+#[derive(Debug, Default)]
+pub struct PacketBuilder<D = Vec<u8>> {
+    ts: Option<std::time::SystemTime>,
+    data: Option<D>,
+    interface: Option<u32>,
+    orig_len: Option<u32>,
+}
+
+impl<D: Default> PacketBuilder<D> {
+    pub fn timestamp(mut self, ts: std::time::SystemTime) -> Self {
+        self.ts = Some(ts);
+        self
+    }
+
+    pub fn data(mut self, data: D) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn interface(mut self, interface: u32) -> Self {
+        self.interface = Some(interface);
+        self
+    }
+
+    /// Length of the packet before capture truncated it to `data`.
+    pub fn orig_len(mut self, orig_len: u32) -> Self {
+        self.orig_len = Some(orig_len);
+        self
+    }
+
+    pub fn build(self) -> Packet<D> {
+        Packet {
+            ts: self.ts.unwrap_or_else(std::time::SystemTime::now),
+            data: self.data.unwrap_or_default(),
+            fingerprint: None,
+            interface: self.interface,
+            segments: 1,
+            orig_len: self.orig_len,
+        }
+    }
+}
+
+impl<D: AsRef<[u8]>> AsIpcPacket for Packet<D> {
     fn timestamp(&self) -> &std::time::SystemTime {
         &self.ts
     }
     fn data(&self) -> &[u8] {
         self.data.as_ref()
     }
+    fn interface(&self) -> Option<u32> {
+        self.interface
+    }
+    fn segments(&self) -> u32 {
+        self.segments
+    }
+    fn orig_len(&self) -> Option<u32> {
+        self.orig_len
+    }
 }
 
 #[macro_export]
@@ -72,12 +427,22 @@ macro_rules! impl_ipc_serialize(
     }
 );
 
-impl_ipc_serialize!(Packet);
+/// Serialized as an `IpcPacket`, so only the timestamp and data are kept, see `encode_to_vec`
+/// to keep the metadata too.
+impl<D: AsRef<[u8]>> Serialize for Packet<D> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let packet = IpcPacket::from(self);
+        packet.serialize(serializer)
+    }
+}
 
-impl<'de> Deserialize<'de> for Packet {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+impl<'de, D: FromPayload> Deserialize<'de> for Packet<D> {
+    fn deserialize<De>(deserializer: De) -> Result<Self, De::Error>
     where
-        D: serde::Deserializer<'de>,
+        De: serde::Deserializer<'de>,
     {
         let ipc_packet = IpcPacket::deserialize(deserializer)?;
         Ok(ipc_packet.into())
@@ -0,0 +1,207 @@
+use crate::errors::Error;
+use crate::packet::{AsIpcPacket, FromPayload, Packet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// Largest record `PcapReader` accepts, as libpcap does.
+const MAX_CAPLEN: u32 = 262_144;
+
+/// Resolution of the fractional part of a pcap record timestamp, as given by the file magic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimestampPrecision {
+    Micros,
+    Nanos,
+}
+
+impl TimestampPrecision {
+    fn per_second(self) -> u32 {
+        match self {
+            TimestampPrecision::Micros => 1_000_000,
+            TimestampPrecision::Nanos => 1_000_000_000,
+        }
+    }
+}
+
+/// Header preceding each packet in a pcap file, with fields in host byte order.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PcapRecordHeader {
+    pub ts_sec: u32,
+    /// Microseconds or nanoseconds past `ts_sec`, depending on the file's precision.
+    pub ts_frac: u32,
+    /// Bytes of the packet captured, and following this header.
+    pub caplen: u32,
+    /// Length of the packet on the wire.
+    pub origlen: u32,
+}
+
+impl PcapRecordHeader {
+    pub fn timestamp(&self, precision: TimestampPrecision) -> SystemTime {
+        let nanos = match precision {
+            TimestampPrecision::Micros => u64::from(self.ts_frac) * 1_000,
+            TimestampPrecision::Nanos => u64::from(self.ts_frac),
+        };
+        SystemTime::UNIX_EPOCH
+            + Duration::from_secs(u64::from(self.ts_sec))
+            + Duration::from_nanos(nanos)
+    }
+
+    /// Original length of a packet with `captured` bytes kept, if it was truncated.
+    fn orig_len(&self, captured: usize) -> Option<u32> {
+        Some(self.origlen).filter(|origlen| *origlen as usize > captured)
+    }
+
+    /// Header for a packet captured at `ts`. Times that don't fit in a pcap timestamp are
+    /// clamped to its range.
+    pub fn new(ts: SystemTime, caplen: u32, origlen: u32, precision: TimestampPrecision) -> Self {
+        let since_epoch = ts
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let (ts_sec, ts_frac) = if since_epoch.as_secs() > u64::from(u32::MAX) {
+            (u32::MAX, precision.per_second() - 1)
+        } else {
+            let frac = since_epoch.subsec_nanos() / (1_000_000_000 / precision.per_second());
+            (since_epoch.as_secs() as u32, frac)
+        };
+        PcapRecordHeader {
+            ts_sec,
+            ts_frac,
+            caplen,
+            origlen,
+        }
+    }
+}
+
+impl<D: AsRef<[u8]>> Packet<D> {
+    /// Header to write ahead of this packet's data in a pcap file.
+    pub fn to_pcap_record(&self, precision: TimestampPrecision) -> PcapRecordHeader {
+        let len = self.data().len() as u32;
+        let origlen = self.orig_len().unwrap_or(len);
+        PcapRecordHeader::new(*self.timestamp(), len, origlen, precision)
+    }
+}
+
+impl<D: FromPayload> Packet<D> {
+    /// Packet for a pcap record, keeping at most `caplen` bytes of `data`. The original length
+    /// is kept if longer than what was captured.
+    pub fn from_pcap_record(
+        header: &PcapRecordHeader,
+        data: &[u8],
+        precision: TimestampPrecision,
+    ) -> Packet<D> {
+        let caplen = data.len().min(header.caplen as usize);
+        Packet::new(
+            header.timestamp(precision),
+            D::from_payload(&data[..caplen]),
+        )
+        .with_orig_len(header.orig_len(caplen))
+    }
+}
+
+/// Reads packets from a pcap file, in either byte order and timestamp precision.
+pub struct PcapReader<R = BufReader<File>> {
+    reader: R,
+    swapped: bool,
+    precision: TimestampPrecision,
+    max_caplen: u32,
+}
+
+impl PcapReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PcapReader, Error> {
+        let file = File::open(path)?;
+        PcapReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the file header from `reader`, leaving it positioned at the first record.
+    pub fn new(mut reader: R) -> Result<PcapReader<R>, Error> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, precision) = match (magic, magic.swap_bytes()) {
+            (MAGIC_MICROS, _) => (false, TimestampPrecision::Micros),
+            (MAGIC_NANOS, _) => (false, TimestampPrecision::Nanos),
+            (_, MAGIC_MICROS) => (true, TimestampPrecision::Micros),
+            (_, MAGIC_NANOS) => (true, TimestampPrecision::Nanos),
+            (magic, _) => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Not a pcap file, magic {:#x}", magic),
+                )))
+            }
+        };
+        let snaplen = u32::from_ne_bytes([header[16], header[17], header[18], header[19]]);
+        let snaplen = if swapped {
+            snaplen.swap_bytes()
+        } else {
+            snaplen
+        };
+        Ok(PcapReader {
+            reader,
+            swapped,
+            precision,
+            max_caplen: if snaplen == 0 {
+                MAX_CAPLEN
+            } else {
+                snaplen.min(MAX_CAPLEN)
+            },
+        })
+    }
+
+    pub fn precision(&self) -> TimestampPrecision {
+        self.precision
+    }
+
+    /// Read the next packet, or `None` at the end of the file. Records longer than the file's
+    /// snaplen or 262144 bytes are rejected as corrupt.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        }
+        let field = |i: usize| {
+            let value =
+                u32::from_ne_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+            if self.swapped {
+                value.swap_bytes()
+            } else {
+                value
+            }
+        };
+        let header = PcapRecordHeader {
+            ts_sec: field(0),
+            ts_frac: field(4),
+            caplen: field(8),
+            origlen: field(12),
+        };
+        if header.caplen > self.max_caplen {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Record of {} bytes exceeds the limit of {}",
+                    header.caplen, self.max_caplen
+                ),
+            )));
+        }
+        let mut data = vec![0u8; header.caplen as usize];
+        self.reader.read_exact(&mut data)?;
+        let orig_len = header.orig_len(data.len());
+        Ok(Some(
+            Packet::new(header.timestamp(self.precision), data).with_orig_len(orig_len),
+        ))
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<Packet, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
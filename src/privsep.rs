@@ -0,0 +1,95 @@
+//! Privilege separation between a capture process and its consumers: the privileged parent
+//! opens the capture source and spawns the consumer as an unprivileged user, handing it the
+//! endpoint to connect to.
+
+use crate::client::Client;
+use crate::errors::Error;
+use crate::server::{ConnectedIpc, Server, ServerConfig};
+use log::*;
+use std::os::unix::process::CommandExt;
+#[cfg(not(target_os = "macos"))]
+use std::path::Path;
+use std::process::{Child, Command};
+
+/// Environment variable a spawned consumer finds its endpoint in.
+pub const ENDPOINT_VAR: &str = "PACKET_IPC_PRIVSEP_ENDPOINT";
+
+/// Spawns consumers as another user, connected to the spawning process.
+#[derive(Clone, Debug, Default)]
+pub struct Privsep {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    config: ServerConfig,
+}
+
+impl Privsep {
+    pub fn new() -> Privsep {
+        Privsep::default()
+    }
+
+    /// User the consumer runs as. Changing user needs the spawning process to be privileged.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Group the consumer runs as.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Options for accepting the consumer's connection.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Spawn `command` as the configured user and wait for it to connect with `connect`. The
+    /// endpoint is handed over to that user alone, so no one else can connect in its place.
+    /// Waits for as long as the consumer takes to connect, so the child should connect first
+    /// thing or exit.
+    ///
+    /// Changing user isn't supported on macOS, where the endpoint is a Mach service rather than
+    /// a file that can be handed over, and fails with `io::ErrorKind::Unsupported`.
+    pub fn spawn(&self, mut command: Command) -> Result<(ConnectedIpc, Child), Error> {
+        #[cfg(target_os = "macos")]
+        if self.uid.is_some() || self.gid.is_some() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Privsep can't hand the endpoint to another user on macOS",
+            )));
+        }
+        let server = Server::new()?.with_config(self.config.clone());
+        #[cfg(not(target_os = "macos"))]
+        if self.uid.is_some() || self.gid.is_some() {
+            let endpoint = Path::new(server.name());
+            std::os::unix::fs::chown(endpoint, self.uid, self.gid)?;
+            if let Some(dir) = endpoint.parent() {
+                std::os::unix::fs::chown(dir, self.uid, self.gid)?;
+            }
+        }
+        command.env(ENDPOINT_VAR, server.name());
+        if let Some(gid) = self.gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = self.uid {
+            command.uid(uid);
+        }
+        let mut child = command.spawn()?;
+        match server.accept() {
+            Ok(connection) => Ok((connection, child)),
+            Err(e) => {
+                if let Err(e) = child.kill() {
+                    debug!("Failed to kill consumer: {:?}", e);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Connect a consumer spawned with `Privsep::spawn` back to its parent.
+pub fn connect() -> Result<Client, Error> {
+    Client::connect_from_env(ENDPOINT_VAR)
+}
@@ -0,0 +1,156 @@
+use crate::alloc::PayloadAllocator;
+use crate::batch::{check_count, for_each_packet};
+use crate::errors::Error;
+use crate::message::{BatchInfo, EncodedBatch, WireFormat};
+use crate::packet::{AsIpcPacket, FromPayload, Packet, PacketView};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Received packets, taken from the front in parts without moving the packets that remain, e.g.
+/// to process up to a timestamp now and the rest later. See `Client::recv_batch`.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    packets: Vec<Arc<Packet>>,
+    taken: usize,
+}
+
+impl From<Vec<Arc<Packet>>> for Batch {
+    fn from(packets: Vec<Arc<Packet>>) -> Self {
+        Batch { packets, taken: 0 }
+    }
+}
+
+impl Batch {
+    /// Number of packets not yet taken.
+    pub fn len(&self) -> usize {
+        self.packets.len() - self.taken
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Packets not yet taken.
+    pub fn as_slice(&self) -> &[Arc<Packet>] {
+        &self.packets[self.taken..]
+    }
+
+    /// Take up to `n` packets from the front.
+    pub fn drain_up_to(&mut self, n: usize) -> &[Arc<Packet>] {
+        let start = self.taken;
+        self.taken += n.min(self.len());
+        &self.packets[start..self.taken]
+    }
+
+    /// Take the packets from the front stamped before `ts`, leaving those stamped at or after it.
+    /// Packets are assumed to be in timestamp order, as sent by a single producer.
+    pub fn split_at_timestamp(&mut self, ts: SystemTime) -> &[Arc<Packet>] {
+        let before = self.as_slice().partition_point(|p| *p.timestamp() < ts);
+        self.drain_up_to(before)
+    }
+
+    /// Packets not yet taken.
+    pub fn into_vec(mut self) -> Vec<Arc<Packet>> {
+        self.packets.drain(..self.taken);
+        self.packets
+    }
+}
+
+/// A received batch left as it was encoded, so its packets can be borrowed with `views` rather
+/// than copied out. See `Client::recv_views`.
+///
+/// The batch's buffer is held until this and every handle from `retain` are dropped, then handed
+/// to the client's `PayloadAllocator`, if it has one, to be reused.
+#[derive(Debug)]
+pub struct EncodedPackets {
+    held: Arc<Held>,
+}
+
+#[derive(Debug)]
+struct Held {
+    batch: EncodedBatch,
+    wire_format: WireFormat,
+    allocator: Option<Arc<dyn PayloadAllocator>>,
+    /// Batches the client has handed out and not yet had released.
+    holding: Arc<AtomicUsize>,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.holding.fetch_sub(1, Ordering::Relaxed);
+        if let Some(allocator) = &self.allocator {
+            allocator.release(std::mem::take(&mut self.batch.data));
+        }
+    }
+}
+
+impl EncodedPackets {
+    pub(crate) fn new(
+        batch: EncodedBatch,
+        wire_format: WireFormat,
+        allocator: Option<Arc<dyn PayloadAllocator>>,
+        holding: Arc<AtomicUsize>,
+    ) -> EncodedPackets {
+        holding.fetch_add(1, Ordering::Relaxed);
+        EncodedPackets {
+            held: Arc::new(Held {
+                batch,
+                wire_format,
+                allocator,
+                holding,
+            }),
+        }
+    }
+
+    pub fn info(&self) -> BatchInfo {
+        self.held.batch.info()
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.batch.header.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.batch.header.count == 0
+    }
+
+    /// Another handle to the batch, keeping its buffer held after this one is dropped, e.g. to
+    /// hand views to another thread. Still counts as one batch towards
+    /// `ClientConfig::max_held_batches`.
+    pub fn retain(&self) -> EncodedPackets {
+        EncodedPackets {
+            held: Arc::clone(&self.held),
+        }
+    }
+
+    /// Decode the packets, borrowing each payload from the batch's buffer.
+    pub fn views(&self) -> Result<Vec<PacketView<'_>>, Error> {
+        let batch = &self.held.batch;
+        check_count(&batch.data, batch.header.count, self.held.wire_format)?;
+        let mut views = Vec::with_capacity(batch.header.count);
+        for_each_packet(
+            &batch.data,
+            batch.header.count,
+            self.held.wire_format,
+            |ts, data, orig_len| {
+                let index = views.len();
+                views.push(PacketView::new(
+                    ts,
+                    data,
+                    batch.fingerprint(index),
+                    batch.interface(index),
+                    batch.segments(index),
+                    batch.orig_len(index).or(orig_len),
+                ));
+            },
+        )?;
+        Ok(views)
+    }
+
+    /// Decode the packets, copying each payload into a `D`, e.g. `SmallData` to keep small
+    /// payloads inline.
+    pub fn packets<D: FromPayload>(&self) -> Result<Vec<Packet<D>>, Error> {
+        Ok(self.views()?.iter().map(PacketView::copy_out).collect())
+    }
+}
@@ -0,0 +1,162 @@
+use crate::client::{Client, ClientConfig, StreamItem};
+use crate::errors::Error;
+use crate::message::CloseReason;
+use crate::packet::Packet;
+use log::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Decides how long a `ReconnectingClient` waits between connection attempts.
+pub trait Backoff {
+    /// Delay before the given attempt, counting from zero, or `None` to give up.
+    fn delay(&mut self, attempt: usize) -> Option<Duration>;
+}
+
+/// Doubles the delay after each failed attempt, up to a maximum.
+#[derive(Clone, Debug)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<usize>,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        ExponentialBackoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(5),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ExponentialBackoff {
+    pub fn initial(mut self, initial: Duration) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// Give up after this many attempts, or `None` to retry forever.
+    pub fn max_attempts(mut self, max_attempts: Option<usize>) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn delay(&mut self, attempt: usize) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        let factor = 1u32.checked_shl(attempt as u32).unwrap_or(u32::MAX);
+        Some(
+            self.initial
+                .checked_mul(factor)
+                .map_or(self.max, |d| d.min(self.max)),
+        )
+    }
+}
+
+/// A `Client` that connects again when the producer fails or the connection is lost.
+///
+/// A normal close from the producer still ends the stream.
+pub struct ReconnectingClient {
+    server_name: String,
+    config: ClientConfig,
+    backoff: Box<dyn Backoff + Send>,
+    client: Client,
+    last_received: Instant,
+}
+
+impl ReconnectingClient {
+    pub fn connect(server_name: String, config: ClientConfig) -> Result<ReconnectingClient, Error> {
+        let client = Client::connect(server_name.clone(), config.clone())?;
+        Ok(ReconnectingClient {
+            server_name,
+            config,
+            backoff: Box::new(ExponentialBackoff::default()),
+            client,
+            last_received: Instant::now(),
+        })
+    }
+
+    /// Use `backoff` between reconnection attempts, instead of `ExponentialBackoff::default()`.
+    pub fn backoff<B: Backoff + Send + 'static>(mut self, backoff: B) -> Self {
+        self.backoff = Box::new(backoff);
+        self
+    }
+
+    /// The current connection.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Receive the next item, reconnecting if needed. Each reconnection is reported with
+    /// `StreamItem::Reconnected` in place of the failed connection's `StreamItem::Closed`.
+    pub fn recv_item(&mut self) -> Result<Option<StreamItem>, Error> {
+        let reason = match self.client.recv_item() {
+            Ok(Some(StreamItem::Closed(reason))) if should_reconnect(&reason) => reason,
+            Ok(Some(item)) => {
+                self.last_received = Instant::now();
+                return Ok(Some(item));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => CloseReason::ReceiveError(e.to_string()),
+        };
+        match self.reconnect(&reason) {
+            Some(gap_estimate) => Ok(Some(StreamItem::Reconnected { gap_estimate })),
+            None => Ok(Some(StreamItem::Closed(reason))),
+        }
+    }
+
+    /// Receive up to `size` packets, reconnecting if needed. Returns `None` once the producer
+    /// closes normally or reconnecting is given up.
+    pub fn recv(&mut self, size: usize) -> Result<Option<Vec<Arc<Packet>>>, Error> {
+        loop {
+            let reason = match self.client.recv(size) {
+                Ok(Some(packets)) => {
+                    self.last_received = Instant::now();
+                    return Ok(Some(packets));
+                }
+                Ok(None) => match self.client.close_reason() {
+                    Some(reason) if should_reconnect(reason) => reason.clone(),
+                    _ => return Ok(None),
+                },
+                Err(e) => CloseReason::ReceiveError(e.to_string()),
+            };
+            if self.reconnect(&reason).is_none() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Connect again, returning how long nothing was received for, or `None` if the backoff gave
+    /// up.
+    fn reconnect(&mut self, reason: &CloseReason) -> Option<Duration> {
+        warn!("Connection to {} lost: {:?}", self.server_name, reason);
+        let mut attempt = 0;
+        loop {
+            std::thread::sleep(self.backoff.delay(attempt)?);
+            match Client::connect(self.server_name.clone(), self.config.clone()) {
+                Ok(client) => {
+                    info!("Reconnected to {}", self.server_name);
+                    self.client = client;
+                    let gap_estimate = self.last_received.elapsed();
+                    self.last_received = Instant::now();
+                    return Some(gap_estimate);
+                }
+                Err(e) => debug!("Failed to reconnect to {}: {:?}", self.server_name, e),
+            }
+            attempt += 1;
+        }
+    }
+}
+
+fn should_reconnect(reason: &CloseReason) -> bool {
+    !matches!(reason, CloseReason::Normal)
+}
@@ -0,0 +1,171 @@
+use crate::errors::Error;
+use crate::message::{Message, WireFormat};
+use crate::server::{ConnectedIpc, Server, ServerConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Bumped whenever the recording layout changes.
+const RECORDING_VERSION: u32 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct Header {
+    version: u32,
+    wire_format: WireFormat,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    offset: Duration,
+    message: &'a Message,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    offset: Duration,
+    message: Message,
+}
+
+/// Writes every message a connection sends, along with when it was sent, so the session can be
+/// fed to a consumer again with a `Replayer`. Attach with `ConnectedIpc::record`.
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+    started: Option<Instant>,
+}
+
+impl Recorder {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Recorder {
+        Recorder {
+            writer: Box::new(writer),
+            started: None,
+        }
+    }
+
+    /// Record to a newly created file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Recorder, Error> {
+        let file = File::create(path)?;
+        Ok(Recorder::new(BufWriter::new(file)))
+    }
+
+    pub(crate) fn start(&mut self, wire_format: WireFormat) -> Result<(), Error> {
+        let header = Header {
+            version: RECORDING_VERSION,
+            wire_format,
+        };
+        bincode::serialize_into(&mut self.writer, &header)?;
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    pub(crate) fn record(&mut self, message: &Message) -> Result<(), Error> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let entry = EntryRef {
+            offset: started.elapsed(),
+            message,
+        };
+        bincode::serialize_into(&mut self.writer, &entry)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(Error::Io)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            log::error!("Failed to flush recording: {:?}", e);
+        }
+    }
+}
+
+/// Sends a session captured by a `Recorder` to a client, in order and optionally with the
+/// original timing.
+pub struct Replayer {
+    reader: Box<dyn Read + Send>,
+    wire_format: WireFormat,
+}
+
+impl Replayer {
+    pub fn new<R: Read + Send + 'static>(reader: R) -> Result<Replayer, Error> {
+        let mut reader: Box<dyn Read + Send> = Box::new(reader);
+        let header: Header = bincode::deserialize_from(&mut reader)?;
+        if header.version != RECORDING_VERSION {
+            return Err(Error::InvalidRecording(format!(
+                "Unsupported version {}, expected {}",
+                header.version, RECORDING_VERSION
+            )));
+        }
+        Ok(Replayer {
+            reader,
+            wire_format: header.wire_format,
+        })
+    }
+
+    /// Replay the recording in the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Replayer, Error> {
+        let file = File::open(path)?;
+        Replayer::new(BufReader::new(file))
+    }
+
+    /// Wire format the recorded batches were encoded with.
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    /// Send every recorded message to `connection`, returning how many were sent. With `paced`,
+    /// messages are spaced out as they were when recorded, otherwise they are sent as fast as
+    /// the connection allows.
+    pub fn replay(self, connection: &ConnectedIpc, paced: bool) -> Result<u64, Error> {
+        let negotiated = connection.info().wire_format();
+        if negotiated != self.wire_format {
+            return Err(Error::WireFormatMismatch {
+                batch: self.wire_format,
+                connection: negotiated,
+            });
+        }
+        let Replayer { mut reader, .. } = self;
+        let started = Instant::now();
+        let mut sent = 0;
+        while let Some(entry) = read_entry(&mut reader)? {
+            if paced {
+                if let Some(wait) = entry.offset.checked_sub(started.elapsed()) {
+                    std::thread::sleep(wait);
+                }
+            }
+            connection.send_message(entry.message)?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Serve the recording to the first client that connects, returning the server name to
+    /// connect to and a handle resolving to the number of messages replayed.
+    pub fn serve(self, paced: bool) -> Result<(String, JoinHandle<Result<u64, Error>>), Error> {
+        let server =
+            Server::new()?.with_config(ServerConfig::default().wire_format(self.wire_format));
+        let name = server.name().clone();
+        let handle = std::thread::spawn(move || {
+            let connection = server.accept()?;
+            self.replay(&connection, paced)
+        });
+        Ok((name, handle))
+    }
+}
+
+/// Read the next entry, or `None` at the end of the recording.
+fn read_entry<R: Read>(reader: &mut R) -> Result<Option<Entry>, Error> {
+    match bincode::deserialize_from(reader) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(e) => match *e {
+            bincode::ErrorKind::Io(ref io) if io.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Ok(None)
+            }
+            _ => Err(Error::Bincode(e)),
+        },
+    }
+}
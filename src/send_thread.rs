@@ -0,0 +1,301 @@
+use crate::drops::DropReason;
+use crate::errors::Error;
+use crate::message::Priority;
+use crate::packet::{AsIpcPacket, Packet};
+use crate::server::{ConnectedIpc, Encoded};
+use crate::stats::CloseSummary;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Options for a `SendThread`.
+#[derive(Clone, Debug)]
+pub struct SendThreadConfig {
+    queue_capacity: usize,
+    max_packets: usize,
+    max_bytes: usize,
+    cpu: Option<usize>,
+    pipelined: bool,
+}
+
+impl Default for SendThreadConfig {
+    fn default() -> Self {
+        SendThreadConfig {
+            queue_capacity: 4096,
+            max_packets: 1024,
+            max_bytes: 1024 * 1024,
+            cpu: None,
+            pipelined: false,
+        }
+    }
+}
+
+impl SendThreadConfig {
+    /// Packets that can wait for the send thread before `send` blocks and `try_send` drops.
+    pub fn queue_capacity(mut self, packets: usize) -> Self {
+        self.queue_capacity = packets.max(1);
+        self
+    }
+
+    /// Most packets sent in one batch.
+    pub fn max_packets(mut self, max_packets: usize) -> Self {
+        self.max_packets = max_packets.max(1);
+        self
+    }
+
+    /// Most payload bytes sent in one batch, give or take the last packet.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Encode each batch while the previous one is still being written to the channel, on a
+    /// second thread, to keep both busy at high rates. At most two batches are in flight.
+    pub fn pipelined(mut self, pipelined: bool) -> Self {
+        self.pipelined = pipelined;
+        self
+    }
+
+    /// Pin the send thread to `cpu`. When pipelined, only the thread encoding is pinned. Only
+    /// supported on Linux, elsewhere a warning is logged. A `cpu` beyond what the platform can
+    /// address stops the send thread with an error.
+    pub fn pin_to_cpu(mut self, cpu: usize) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+}
+
+/// Result of the send thread: the connection back, and the error that stopped it, if any.
+type Sent = (ConnectedIpc, Result<(), Error>);
+
+/// Sends on a connection from a dedicated thread that owns encoding and the channel, fed through
+/// a bounded queue, so a capture thread only pays for handing packets over.
+///
+/// The thread sends whatever has queued up as one batch, so batches grow with load rather than
+/// waiting to fill. Everything configured on the connection, such as deduplication, still
+/// applies.
+pub struct SendThread {
+    queue: Option<Sender<Packet>>,
+    dropped: Arc<AtomicU64>,
+    handle: Option<JoinHandle<Sent>>,
+}
+
+impl SendThread {
+    pub fn new(connection: ConnectedIpc, config: SendThreadConfig) -> SendThread {
+        let (queue, packets) = crossbeam_channel::bounded(config.queue_capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let handle = {
+            let dropped = Arc::clone(&dropped);
+            std::thread::spawn(move || {
+                if let Some(cpu) = config.cpu {
+                    if let Err(e) = pin_to_cpu(cpu) {
+                        return (connection, Err(e));
+                    }
+                }
+                if config.pipelined {
+                    run_pipelined(connection, &packets, &config, &dropped)
+                } else {
+                    let result = run(&connection, &packets, &config, &dropped);
+                    (connection, result)
+                }
+            })
+        };
+        SendThread {
+            queue: Some(queue),
+            dropped,
+            handle: Some(handle),
+        }
+    }
+
+    fn queue(&self) -> &Sender<Packet> {
+        self.queue
+            .as_ref()
+            .expect("Queue is only taken when stopping")
+    }
+
+    /// Queue a packet, waiting for room if the send thread is behind. Fails with
+    /// `Error::Disconnected` once the send thread has stopped, see `close` for why.
+    pub fn send(&self, packet: Packet) -> Result<(), Error> {
+        self.queue().send(packet).map_err(|_| Error::Disconnected)
+    }
+
+    /// Queue a packet if there's room, otherwise drop it, returning false. Dropped packets are
+    /// reported to the client with `DropReason::Overflow`.
+    pub fn try_send(&self, packet: Packet) -> Result<bool, Error> {
+        match self.queue().try_send(packet) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(_)) => Err(Error::Disconnected),
+        }
+    }
+
+    /// Packets waiting for the send thread.
+    pub fn queued(&self) -> usize {
+        self.queue().len()
+    }
+
+    fn stop(&mut self) -> Option<Sent> {
+        self.queue.take();
+        let handle = self.handle.take()?;
+        match handle.join() {
+            Ok(sent) => Some(sent),
+            Err(_) => {
+                error!("Send thread panicked");
+                None
+            }
+        }
+    }
+
+    /// Send everything queued, stop the send thread and close the connection. Returns the error
+    /// that stopped the send thread early, if there was one.
+    pub fn close(mut self) -> Result<CloseSummary, Error> {
+        let (mut connection, result) = self.stop().ok_or(Error::Disconnected)?;
+        result?;
+        connection.close()
+    }
+
+    /// Send everything queued and stop the send thread, returning the connection.
+    pub fn into_inner(mut self) -> Result<ConnectedIpc, Error> {
+        let (connection, result) = self.stop().ok_or(Error::Disconnected)?;
+        result.map(|_| connection)
+    }
+}
+
+impl Drop for SendThread {
+    fn drop(&mut self) {
+        if let Some((_, Err(e))) = self.stop() {
+            error!("Send thread failed: {:?}", e);
+        }
+    }
+}
+
+/// Wait for a packet, then take whatever else has queued up to the batch limits. Returns false
+/// once the queue has closed and emptied.
+fn gather(packets: &Receiver<Packet>, batch: &mut Vec<Packet>, config: &SendThreadConfig) -> bool {
+    let packet = match packets.recv() {
+        Ok(packet) => packet,
+        Err(_) => return false,
+    };
+    let mut bytes = packet.data().len();
+    batch.push(packet);
+    while batch.len() < config.max_packets && bytes < config.max_bytes {
+        match packets.try_recv() {
+            Ok(packet) => {
+                bytes += packet.data().len();
+                batch.push(packet);
+            }
+            Err(_) => break,
+        }
+    }
+    true
+}
+
+fn record_drops(connection: &ConnectedIpc, count: u64) {
+    if count > 0 {
+        connection.record_drops(count, DropReason::Overflow);
+    }
+}
+
+fn run(
+    connection: &ConnectedIpc,
+    packets: &Receiver<Packet>,
+    config: &SendThreadConfig,
+    dropped: &AtomicU64,
+) -> Result<(), Error> {
+    let mut batch = Vec::with_capacity(config.max_packets);
+    while gather(packets, &mut batch, config) {
+        record_drops(connection, dropped.swap(0, Ordering::Relaxed));
+        connection.send(&batch)?;
+        batch.clear();
+    }
+    record_drops(connection, dropped.swap(0, Ordering::Relaxed));
+    Ok(())
+}
+
+/// Encode batches on this thread and write them from another. Batches are handed over without
+/// buffering, so the next batch is encoded while the previous one is written.
+fn run_pipelined(
+    connection: ConnectedIpc,
+    packets: &Receiver<Packet>,
+    config: &SendThreadConfig,
+    dropped: &AtomicU64,
+) -> Sent {
+    let mut encoder = connection.take_encoder();
+    let (encoded_tx, encoded_rx) = crossbeam_channel::bounded::<(Encoded, u64)>(0);
+    let writer = std::thread::spawn(move || {
+        let result = encoded_rx.iter().try_for_each(|(encoded, dropped)| {
+            record_drops(&connection, dropped);
+            connection.send_encoded(encoded, Priority::Normal)
+        });
+        (connection, result)
+    });
+    let mut result = Ok(());
+    let mut batch = Vec::with_capacity(config.max_packets);
+    while gather(packets, &mut batch, config) {
+        match encoder.encode(batch.drain(..)) {
+            Ok(encoded) => {
+                let count = dropped.swap(0, Ordering::Relaxed);
+                // The writer only hangs up once it has failed, which it returns below
+                if encoded_tx.send((encoded, count)).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    drop(encoded_tx);
+    let (connection, written) = writer
+        .join()
+        .unwrap_or_else(|e| std::panic::resume_unwind(e));
+    connection.restore_encoder(encoder);
+    record_drops(&connection, dropped.swap(0, Ordering::Relaxed));
+    (connection, result.and(written))
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> Result<(), Error> {
+    // CPU_SET panics past the end of the set
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Can't pin to cpu {}, at most {} are supported",
+                cpu,
+                libc::CPU_SETSIZE
+            ),
+        )));
+    }
+    // Safety: the set is zeroed before use and only read by the call, which affects just the
+    // calling thread.
+    let result = unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        warn!(
+            "Failed to pin send thread to cpu {}: {}",
+            cpu,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(cpu: usize) -> Result<(), Error> {
+    warn!(
+        "Pinning the send thread to cpu {} is only supported on Linux",
+        cpu
+    );
+    Ok(())
+}
@@ -1,55 +1,995 @@
 use crate::errors::Error;
 
-use crate::packet::{AsIpcPacket, IpcPacket};
-use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
+use crate::aggregate::FlowRecord;
+use crate::batch::{BatchBuilder, BatchSlot, EncodeErrorPolicy};
+use crate::coalesce::Coalesce;
+use crate::crc::crc32;
+use crate::dedup::Deduplicator;
+use crate::drops::{DropAccounting, DropReason};
+use crate::faults::{Action, FaultInjector, Faults};
+use crate::fingerprint::Fingerprint;
+use crate::info::{ConnectionInfo, ConsumerHealth, ConsumerInfo};
+use crate::keepalive::{Keepalive, KeepaliveState, Liveness};
+use crate::message::{
+    CloseReason, Control, Handshake, Hello, Message, Priority, RejectReason, WireFormat,
+    BATCH_FLAG_CRC, PROTOCOL_VERSION,
+};
+use crate::monitor::{Published, StatsMonitor};
+use crate::multi::{ClientSlot, ServerEvent};
+use crate::packet::AsIpcPacket;
+use crate::record::Recorder;
+use crate::stats::{CloseSummary, Stats, StatsSnapshot};
+use crate::timing::{Instrumentation, SendTimings};
+use ipc_channel::ipc::{self, IpcOneShotServer, IpcReceiver, IpcSender, TryRecvError};
 use log::*;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
-pub type SenderMessage<'a> = Option<Vec<IpcPacket<'a>>>;
-pub type Sender<'a> = IpcSender<SenderMessage<'a>>;
+pub type Sender = IpcSender<Message>;
 
-pub struct Server<'a> {
-    server: IpcOneShotServer<Sender<'a>>,
+/// Resolve a name given to `Server::new_with_name` to the link clients connect to. Relative names
+/// are placed in the temp directory so independently configured processes derive the same
+/// endpoint.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub(crate) fn resolve_name(name: &str) -> PathBuf {
+    let path = Path::new(name);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::temp_dir().join(path)
+    }
+}
+
+/// Link from a caller supplied name to the generated endpoint, removed once no longer needed.
+#[derive(Debug)]
+pub(crate) struct NameLink(pub PathBuf);
+
+impl NameLink {
+    /// Link `path` to `target`, failing with `Error::NameTaken` if `path` exists.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub fn create(target: &str, path: PathBuf) -> Result<NameLink, Error> {
+        std::os::unix::fs::symlink(target, &path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                Error::NameTaken(path.display().to_string())
+            } else {
+                Error::Io(e)
+            }
+        })?;
+        Ok(NameLink(path))
+    }
+}
+
+/// Point the link at `path` to a new target, replacing the old link atomically.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub(crate) fn relink(path: &Path, target: &str) -> Result<(), Error> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(format!(".{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    std::os::unix::fs::symlink(target, &tmp).map_err(Error::Io)?;
+    std::fs::rename(&tmp, path).map_err(Error::Io)
+}
+
+impl Drop for NameLink {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove server name {:?}: {:?}", self.0, e);
+        }
+    }
+}
+
+/// Options applied by a `Server` when accepting a connection.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    wire_format: Option<WireFormat>,
+    pub(crate) max_clients: Option<usize>,
+    runtime_dir: Option<PathBuf>,
+    permissions: Option<u32>,
+}
+
+impl ServerConfig {
+    /// Only accept clients that can use `wire_format`, rather than negotiating the best format
+    /// both ends support.
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = Some(wire_format);
+        self
+    }
+
+    /// Turn away clients of a `MultiServer` while this many of its connections are open.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// Place relative server names in `dir` rather than the shared temp directory, so other users
+    /// can't list them. The directory is created readable only by its owner if missing, and
+    /// servers fail with `Error::InsecureDirectory` if it is accessible to anyone else. Clients
+    /// must connect using the full name, see `Server::name`.
+    pub fn runtime_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.runtime_dir = Some(dir.into());
+        self
+    }
+
+    /// Unix permissions for endpoints, such as `0o660` to let the group connect. Directories
+    /// holding endpoints also get search permission for whoever is granted access. By default
+    /// endpoints are only reachable by the user that created them. Applied by
+    /// `Server::new_with_name_and_config` and `MultiServer`.
+    pub fn permissions(mut self, mode: u32) -> Self {
+        self.permissions = Some(mode & 0o777);
+        self
+    }
+
+    /// Where the name link for `name` goes, preparing the runtime directory if one is set.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub(crate) fn resolve_name(&self, name: &str) -> Result<PathBuf, Error> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        let dir = match &self.runtime_dir {
+            Some(dir) if !Path::new(name).is_absolute() => dir,
+            _ => return Ok(resolve_name(name)),
+        };
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let mode = std::fs::metadata(dir)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(Error::InsecureDirectory(dir.display().to_string()));
+        }
+        Ok(dir.join(name))
+    }
+
+    /// Apply the configured permissions to an endpoint created by ipc-channel.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub(crate) fn secure_endpoint(&self, endpoint: &str) -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = match self.permissions {
+            Some(mode) => mode,
+            None => return Ok(()),
+        };
+        let endpoint = Path::new(endpoint);
+        std::fs::set_permissions(endpoint, std::fs::Permissions::from_mode(mode))?;
+        if let Some(dir) = endpoint.parent() {
+            // Search permission for each class given any access to the endpoint
+            let search = (0..3)
+                .map(|class| 0o7 << (class * 3))
+                .filter(|bits| mode & bits != 0)
+                .fold(0, |acc, bits| acc | (bits & 0o111));
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700 | search))?;
+        }
+        Ok(())
+    }
+
+    fn select_wire_format(&self, client_formats: &[WireFormat]) -> Option<WireFormat> {
+        match self.wire_format {
+            Some(format) => client_formats.iter().find(|f| **f == format).copied(),
+            None => WireFormat::supported()
+                .into_iter()
+                .find(|f| client_formats.contains(f)),
+        }
+    }
+}
+
+pub struct Server {
+    server: IpcOneShotServer<Handshake>,
     name: String,
+    config: ServerConfig,
+    _link: Option<NameLink>,
 }
 
-impl<'a> Server<'a> {
+impl Server {
     pub fn name(&self) -> &String {
         &self.name
     }
 
-    pub fn new() -> Result<Server<'a>, Error> {
+    #[allow(clippy::redundant_field_names)]
+    pub fn new() -> Result<Server, Error> {
         let (server, server_name) = IpcOneShotServer::new().map_err(Error::Io)?;
 
         Ok(Server {
             server: server,
             name: server_name,
+            config: ServerConfig::default(),
+            _link: None,
         })
     }
 
-    pub fn accept(self) -> Result<ConnectedIpc<'a>, Error> {
-        let (_, tx) = self.server.accept().map_err(Error::Bincode)?;
+    /// Create a server reachable under a caller supplied name, rather than only the generated
+    /// one. Relative names are placed in the temp directory. Fails with `Error::NameTaken` if
+    /// something already exists under that name. Not available on macOS, where endpoints are
+    /// Mach port names rather than paths.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub fn new_with_name(name: &str) -> Result<Server, Error> {
+        Server::new_with_name_and_config(name, ServerConfig::default())
+    }
 
-        info!("Accepted connection from {:?}", tx);
+    /// Create a server reachable under `name`, resolved and secured as set in `config`.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    pub fn new_with_name_and_config(name: &str, config: ServerConfig) -> Result<Server, Error> {
+        let path = config.resolve_name(name)?;
+        let (server, server_name) = IpcOneShotServer::new().map_err(Error::Io)?;
+        config.secure_endpoint(&server_name)?;
+        let link = NameLink::create(&server_name, path)?;
 
-        Ok(ConnectedIpc { connection: tx })
+        Ok(Server {
+            server,
+            name: link.0.display().to_string(),
+            config,
+            _link: Some(link),
+        })
+    }
+
+    /// Apply `config` when accepting. Runtime directory and permissions settings only take
+    /// effect when the server is created, see `Server::new_with_name_and_config`.
+    pub fn with_config(mut self, config: ServerConfig) -> Server {
+        self.config = config;
+        self
+    }
+
+    /// Store the server name in an environment variable, so child processes spawned afterwards
+    /// can connect with `Client::connect_from_env`.
+    pub fn export_to_env(&self, var: &str) -> Result<(), Error> {
+        if var.is_empty() || var.contains(['=', '\0']) {
+            return Err(Error::InvalidEnv {
+                var: var.to_owned(),
+                reason: "not a valid variable name".to_owned(),
+            });
+        }
+        std::env::set_var(var, &self.name);
+        Ok(())
+    }
+
+    pub fn accept(self) -> Result<ConnectedIpc, Error> {
+        let (_, handshake) = self.server.accept().map_err(Error::Bincode)?;
+        self.config.connect(handshake)
     }
 }
 
-pub struct ConnectedIpc<'a> {
-    connection: Sender<'a>,
+impl ServerConfig {
+    /// Turn a client away, letting it know why. Returns the matching error.
+    pub(crate) fn reject(handshake: Handshake, reason: RejectReason) -> Error {
+        info!(
+            "Rejecting connection from pid {}: {:?}",
+            handshake.pid, reason
+        );
+        if let Err(e) = handshake.sender.send(Message::Rejected(reason.clone())) {
+            debug!("Failed to send rejection: {:?}", e);
+        }
+        Error::Rejected(reason)
+    }
+
+    /// Complete a client's handshake, replying with the negotiated options.
+    pub(crate) fn connect(&self, handshake: Handshake) -> Result<ConnectedIpc, Error> {
+        info!(
+            "Accepted connection from {:?} (pid {})",
+            handshake.sender, handshake.pid
+        );
+
+        let protocol_version = u32::min(handshake.protocol_version, PROTOCOL_VERSION);
+        let wire_format = match self.select_wire_format(&handshake.wire_formats) {
+            Some(wire_format) => wire_format,
+            None => {
+                let reason = RejectReason::WireFormat(handshake.wire_formats.clone());
+                return Err(ServerConfig::reject(handshake, reason));
+            }
+        };
+        let (control_tx, control_rx) = ipc::channel::<Control>().map_err(Error::Io)?;
+        let connection = ConnectedIpc {
+            connection: RefCell::new(Some(handshake.sender)),
+            control: control_rx,
+            acked: Cell::new((0, 0)),
+            close_acked: Cell::new(false),
+            consumer_gone: Cell::new(false),
+            stats_requested: Cell::new(false),
+            peer_stats: Cell::new(None),
+            health: Cell::new(None),
+            _slot: None,
+            info: ConnectionInfo {
+                connected_at: std::time::SystemTime::now(),
+                peer_pid: Some(handshake.pid),
+                protocol_version,
+                wire_format,
+            },
+            consumer: handshake.consumer,
+            drops: RefCell::new(DropAccounting::default()),
+            stats: Cell::new(Stats::default()),
+            dedup: RefCell::new(None),
+            recorder: RefCell::new(None),
+            instrumentation: RefCell::new(None),
+            fingerprint: RefCell::new(None),
+            batch_capacity: Cell::new(0),
+            max_pending_bytes: Cell::new(None),
+            permit_poll_interval: Cell::new(Duration::from_millis(1)),
+            coalesce: Cell::new(None),
+            keepalive: RefCell::new(None),
+            published: RefCell::new(None),
+            on_encode_error: Cell::new(EncodeErrorPolicy::default()),
+            sequence: Cell::new(0),
+            checksum_batches: Cell::new(false),
+            faults: RefCell::new(None),
+        };
+        connection.send_message(Message::Hello(Hello {
+            pid: std::process::id(),
+            protocol_version,
+            wire_format,
+            control: control_tx,
+            sent_at: SystemTime::now(),
+        }))?;
+        Ok(connection)
+    }
+}
+
+pub struct ConnectedIpc {
+    /// Taken when a fault injected disconnects the channel.
+    connection: RefCell<Option<Sender>>,
+    control: IpcReceiver<Control>,
+    /// Batches and bytes the client has acknowledged receiving.
+    acked: Cell<(u64, u64)>,
+    close_acked: Cell<bool>,
+    consumer_gone: Cell<bool>,
+    stats_requested: Cell<bool>,
+    peer_stats: Cell<Option<StatsSnapshot>>,
+    health: Cell<Option<ConsumerHealth>>,
+    _slot: Option<ClientSlot>,
+    info: ConnectionInfo,
+    consumer: ConsumerInfo,
+    drops: RefCell<DropAccounting>,
+    stats: Cell<Stats>,
+    dedup: RefCell<Option<Deduplicator>>,
+    recorder: RefCell<Option<Recorder>>,
+    instrumentation: RefCell<Option<Instrumentation>>,
+    fingerprint: RefCell<Option<Fingerprint>>,
+    /// Bytes to preallocate for encoding each batch, see `warmup`.
+    batch_capacity: Cell<usize>,
+    max_pending_bytes: Cell<Option<u64>>,
+    permit_poll_interval: Cell<Duration>,
+    coalesce: Cell<Option<usize>>,
+    keepalive: RefCell<Option<KeepaliveState>>,
+    /// Where stats are published for a `StatsMonitor`.
+    published: RefCell<Option<Published>>,
+    on_encode_error: Cell<EncodeErrorPolicy>,
+    /// Sequence of the next batch sent.
+    sequence: Cell<u64>,
+    checksum_batches: Cell<bool>,
+    faults: RefCell<Option<FaultInjector>>,
 }
 
-impl<'a> ConnectedIpc<'a> {
-    pub fn send<T: AsIpcPacket>(&'a self, packets: &'a [T]) -> Result<(), Error> {
-        let ipc_packets: Vec<_> = packets.iter().map(IpcPacket::from).collect();
-        self.connection.send(Some(ipc_packets)).map_err(|e| {
-            error!("Failed to send {:?}", e);
-            Error::Bincode(e)
+impl ConnectedIpc {
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Identity and options the client declared when connecting.
+    pub fn consumer(&self) -> &ConsumerInfo {
+        &self.consumer
+    }
+
+    pub fn send<T: AsIpcPacket>(&self, packets: &[T]) -> Result<(), Error> {
+        self.send_with_priority(packets, Priority::Normal)
+    }
+
+    /// Send packets on the given priority lane.
+    pub fn send_with_priority<T: AsIpcPacket>(
+        &self,
+        packets: &[T],
+        priority: Priority,
+    ) -> Result<(), Error> {
+        self.send_iter(packets, priority)
+    }
+
+    /// Send packets as they are iterated, e.g. views into a capture ring buffer, without
+    /// collecting them first. Each packet is encoded straight from the borrowed data.
+    pub fn send_borrowed<I, T>(&self, packets: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsIpcPacket,
+    {
+        self.send_iter(packets, Priority::Normal)
+    }
+
+    fn send_iter<I, T>(&self, packets: I, priority: Priority) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsIpcPacket,
+    {
+        self.encode(packets)?.flush_with_priority(self, priority)
+    }
+
+    /// An empty batch fingerprinting packets and handling encode errors as `send` does, for
+    /// senders pushing packets one at a time, see `is_duplicate`.
+    pub(crate) fn batch_builder(&self) -> BatchBuilder {
+        BatchBuilder::with_capacity(self.batch_capacity.get())
+            .wire_format(self.info.wire_format)
+            .fingerprint(self.fingerprint.borrow().clone())
+            .on_encode_error(self.on_encode_error.get())
+    }
+
+    /// Encode `packets` as `send` does, including deduplication and coalescing, for senders
+    /// delivering the batch themselves.
+    pub(crate) fn encode<I, T>(&self, packets: I) -> Result<BatchBuilder, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsIpcPacket,
+    {
+        let encoded = {
+            let mut encoder = self.take_encoder();
+            let encoded = encoder.encode(packets);
+            self.restore_encoder(encoder);
+            encoded?
+        };
+        Ok(self.account_encoded(encoded))
+    }
+
+    /// Whether `packet` should be left out of a batch from `batch_builder` as a duplicate,
+    /// counting it if so.
+    pub(crate) fn is_duplicate<T: AsIpcPacket + ?Sized>(&self, packet: &T) -> bool {
+        let duplicate = self
+            .dedup
+            .borrow_mut()
+            .as_mut()
+            .is_some_and(|dedup| dedup.is_duplicate(packet));
+        if duplicate {
+            self.update_stats(|stats| stats.duplicates += 1);
+        }
+        duplicate
+    }
+
+    /// Whether batches for this connection depend on more than its wire format, so can't be
+    /// shared with other connections.
+    pub(crate) fn custom_encoding(&self) -> bool {
+        self.fingerprint.borrow().is_some()
+            || self.dedup.borrow().is_some()
+            || self.coalesce.get().is_some()
+            || self.on_encode_error.get() != EncodeErrorPolicy::FailBatch
+    }
+
+    /// Take what's needed to encode batches for this connection, e.g. on another thread, leaving
+    /// it without deduplication until `restore_encoder`.
+    pub(crate) fn take_encoder(&self) -> Encoder {
+        Encoder {
+            wire_format: self.info.wire_format,
+            fingerprint: self.fingerprint.borrow().clone(),
+            dedup: self.dedup.borrow_mut().take(),
+            coalesce: self.coalesce.get(),
+            on_error: self.on_encode_error.get(),
+            capacity: self.batch_capacity.get(),
+        }
+    }
+
+    pub(crate) fn restore_encoder(&self, encoder: Encoder) {
+        *self.dedup.borrow_mut() = encoder.dedup;
+    }
+
+    /// Send a batch from `Encoder::encode`, accounting for what encoding it took.
+    pub(crate) fn send_encoded(&self, encoded: Encoded, priority: Priority) -> Result<(), Error> {
+        self.account_encoded(encoded)
+            .flush_with_priority(self, priority)
+    }
+
+    fn account_encoded(&self, encoded: Encoded) -> BatchBuilder {
+        let Encoded {
+            batch,
+            duplicates,
+            elapsed,
+        } = encoded;
+        self.update_stats(|stats| stats.duplicates += duplicates);
+        if let Some(instrumentation) = self.instrumentation.borrow_mut().as_mut() {
+            if !batch.is_empty() {
+                instrumentation.record_encode(elapsed);
+            }
+        }
+        batch
+    }
+
+    /// Coalesce runs of consecutive, in order TCP segments of a flow into one packet of up to
+    /// `max_bytes` IP bytes before sending, as GRO does, or stop with `None`. A coalesced packet
+    /// keeps the first segment's headers and timestamp with IP lengths fixed up, though not the
+    /// TCP checksum, and reports how many segments it holds with `AsIpcPacket::segments`.
+    pub fn coalesce_tcp(&self, max_bytes: Option<usize>) {
+        self.coalesce.set(max_bytes);
+    }
+
+    /// Send a CRC-32 of each batch's data for the client to check, failing the connection with
+    /// `Error::InvalidBatch` on a mismatch. Sent batches are already checked by the channel, so
+    /// this is for catching corruption between processes, such as by shared memory.
+    pub fn checksum_batches(&self, enabled: bool) {
+        self.checksum_batches.set(enabled);
+    }
+
+    /// Inject `faults` into batches sent from now on, or stop with `None`, e.g. to check a
+    /// consumer recovers from lost batches or a producer crash.
+    pub fn inject_faults(&self, faults: Option<Faults>) {
+        *self.faults.borrow_mut() = faults.map(FaultInjector::new);
+    }
+
+    /// Make room for a packet of up to `len` bytes, written in place and sent as its own batch
+    /// once committed, see `BatchSlot`. Committed packets aren't checked for duplicates.
+    pub fn reserve(&self, len: usize) -> Result<BatchSlot<'_>, Error> {
+        BatchSlot::for_connection(self.batch_builder(), self, len)
+    }
+
+    /// Send flow records, such as those produced by a `FlowAggregator`, in place of packets.
+    pub fn send_flows(&self, flows: Vec<FlowRecord>) -> Result<(), Error> {
+        if flows.is_empty() {
+            return Ok(());
+        }
+        self.send_message(Message::Flows(flows))
+    }
+
+    /// Fingerprint each packet sent with `send` and its variants, for the client to read with
+    /// `Packet::fingerprint`, or stop with `None`.
+    pub fn fingerprint(&self, fingerprint: Option<Fingerprint>) {
+        *self.fingerprint.borrow_mut() = fingerprint;
+    }
+
+    /// Suppress duplicate packets when sending, or stop suppressing them with `None`.
+    pub fn set_dedup(&self, dedup: Option<Deduplicator>) {
+        *self.dedup.borrow_mut() = dedup;
+    }
+
+    /// Record packets the producer dropped rather than sent. The client is told about them
+    /// in-band, ahead of the next batch once the drop report interval has elapsed.
+    pub fn record_drops(&self, count: u64, reason: DropReason) {
+        self.drops.borrow_mut().record(count, reason);
+        self.update_stats(|stats| stats.drops += count);
+    }
+
+    /// Count packets skipped because they couldn't be encoded, reporting them as drops.
+    pub(crate) fn record_encode_failures(&self, count: u64) {
+        self.record_drops(count, DropReason::EncodeFailed);
+        self.update_stats(|stats| stats.encode_failures += count);
+    }
+
+    /// What to do with packets that can't be encoded, see `EncodeErrorPolicy`. Defaults to
+    /// failing the send.
+    pub fn on_encode_error(&self, policy: EncodeErrorPolicy) {
+        self.on_encode_error.set(policy);
+    }
+
+    /// Minimum time between drop reports, defaults to one second.
+    pub fn set_drop_report_interval(&self, interval: Duration) {
+        self.drops.borrow_mut().set_interval(interval);
+    }
+
+    /// Send any recorded drops to the client now.
+    pub fn report_drops(&self) -> Result<(), Error> {
+        self.send_drop_reports(true)
+    }
+
+    fn send_drop_reports(&self, force: bool) -> Result<(), Error> {
+        let reports = self.drops.borrow_mut().take_due(force);
+        for report in reports {
+            self.send_message(Message::DropReport(report))?;
+        }
+        Ok(())
+    }
+
+    /// Counters for everything sent on this connection so far.
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    fn update_stats<F: FnOnce(&mut Stats)>(&self, f: F) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+        if let Some(published) = self.published.borrow().as_ref() {
+            *published.lock().unwrap_or_else(|e| e.into_inner()) = stats;
+        }
+    }
+
+    /// Have `monitor` periodically report this connection's stats, until it is dropped.
+    pub fn monitor(&self, monitor: &StatsMonitor) {
+        let published = monitor.add(self.consumer.clone(), self.stats.get());
+        *self.published.borrow_mut() = Some(published);
+    }
+
+    /// This connection's stats along with how many batches the client has yet to receive.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            stats: self.stats(),
+            queued_batches: self.pending_batches(),
+        }
+    }
+
+    /// Ask the client for its stats, which are available from `peer_stats` once it replies.
+    pub fn request_peer_stats(&self) -> Result<(), Error> {
+        self.send_message(Message::StatsRequest)
+    }
+
+    /// The most recent stats the client sent in reply to `request_peer_stats`.
+    pub fn peer_stats(&self) -> Option<StatsSnapshot> {
+        self.poll_acks();
+        self.peer_stats.get()
+    }
+
+    /// The most recent health report from the client, see `ClientConfig::health_interval`.
+    pub fn consumer_health(&self) -> Option<ConsumerHealth> {
+        self.poll_acks();
+        self.health.get()
+    }
+
+    /// Send a snapshot of this connection's stats to the client.
+    pub fn send_stats(&self) -> Result<(), Error> {
+        self.send_message(Message::Stats(self.stats()))
+    }
+
+    /// Let the client know the producer is still alive while there is nothing to send.
+    pub fn heartbeat(&self) -> Result<(), Error> {
+        self.send_message(Message::Heartbeat(SystemTime::now()))
+    }
+
+    /// Check the client is alive with heartbeats, see `Keepalive`, or stop with `None`. Needs
+    /// `maintain` to be called regularly.
+    pub fn keepalive(&self, keepalive: Option<Keepalive>) {
+        *self.keepalive.borrow_mut() = keepalive.map(KeepaliveState::new);
+    }
+
+    /// Read what the client has sent, work out how it looks and send a heartbeat if one is due,
+    /// as configured with `keepalive`. Call this at least every heartbeat interval from the
+    /// thread sending. Without keepalive the client always looks `Active`.
+    ///
+    /// For connections accepted by a `MultiServer`, a client becoming idle or timing out is
+    /// also reported as `ServerEvent::ClientIdle` or `ServerEvent::ClientTimedOut`.
+    pub fn maintain(&self) -> Result<Liveness, Error> {
+        self.poll_control()?;
+        let (changed, due) = match self.keepalive.borrow_mut().as_mut() {
+            Some(state) => (state.update(), state.heartbeat_due()),
+            None => return Ok(Liveness::Active),
+        };
+        if let (Some(liveness), Some(slot)) = (changed, &self._slot) {
+            let info = self.info.clone();
+            let consumer = self.consumer.clone();
+            match liveness {
+                Liveness::Idle(idle_for) => slot.emit(ServerEvent::ClientIdle {
+                    info,
+                    consumer,
+                    idle_for,
+                }),
+                Liveness::TimedOut(missed) => slot.emit(ServerEvent::ClientTimedOut {
+                    info,
+                    consumer,
+                    missed,
+                }),
+                Liveness::Active => {}
+            }
+        }
+        if due {
+            self.heartbeat()?;
+        }
+        Ok(self
+            .keepalive
+            .borrow()
+            .as_ref()
+            .map_or(Liveness::Active, KeepaliveState::liveness))
+    }
+
+    /// Send application data, such as a request to rotate output files, which the client receives
+    /// as `StreamItem::UserControl` after every packet sent before it.
+    pub fn send_user_control(&self, data: Vec<u8>) -> Result<(), Error> {
+        self.send_message(Message::UserControl(data))
+    }
+
+    pub fn close(&mut self) -> Result<CloseSummary, Error> {
+        self.close_with_reason(CloseReason::Normal)
+    }
+
+    /// Close the connection, telling the client why, and return what was sent over it.
+    pub fn close_with_reason(&mut self, reason: CloseReason) -> Result<CloseSummary, Error> {
+        self.send_drop_reports(true)?;
+        self.send_message(Message::Close(reason))?;
+        let stats = self.stats();
+        Ok(CloseSummary {
+            packets_sent: stats.packets,
+            bytes_sent: stats.bytes,
+            drops: stats.drops,
+            duration: SystemTime::now()
+                .duration_since(self.info.connected_at)
+                .unwrap_or_default(),
         })
     }
 
-    pub fn close(&mut self) -> Result<(), Error> {
-        self.connection.send(None).map_err(Error::Bincode)?;
+    /// Write every message sent from now on to `recorder`, replacing any recorder already
+    /// attached. Recording stops with a logged error if writing fails.
+    pub fn record(&self, mut recorder: Recorder) -> Result<(), Error> {
+        recorder.start(self.info.wire_format)?;
+        self.recorder.replace(Some(recorder));
         Ok(())
     }
+
+    /// Prepare for sending batches of around `expected_batch_bytes` once encoded. Batches are
+    /// then encoded into a buffer of that size allocated up front, rather than one grown and
+    /// copied as packets are pushed.
+    pub fn warmup(&self, expected_batch_bytes: usize) {
+        self.batch_capacity.set(expected_batch_bytes);
+    }
+
+    /// Only grant permits while fewer than `bytes` sent are still waiting to be received, or
+    /// always grant them with `None`. Sends made without a permit aren't limited.
+    pub fn max_pending_bytes(&self, bytes: Option<u64>) {
+        self.max_pending_bytes.set(bytes);
+    }
+
+    /// How often `permit` checks whether the client has caught up, defaults to a millisecond.
+    /// Longer intervals use less CPU while waiting, but can leave a permit waiting up to an
+    /// interval after there is room.
+    pub fn permit_poll_interval(&self, interval: Duration) {
+        self.permit_poll_interval.set(interval);
+    }
+
+    /// Reserve room to send, or `None` if the client is behind by `max_pending_bytes` or more. A
+    /// capture loop can check this before copying a packet out of the capture ring, skipping the
+    /// copy entirely under backpressure.
+    pub fn try_permit(&self) -> Option<Permit<'_>> {
+        match self.max_pending_bytes.get() {
+            Some(max) if self.pending_bytes() >= max => None,
+            _ => Some(Permit { connection: self }),
+        }
+    }
+
+    /// Wait up to `timeout` for the client to fall less than `max_pending_bytes` behind. The
+    /// client's acknowledgements can't be waited on directly, so they are checked every
+    /// `permit_poll_interval` instead.
+    pub fn permit(&self, timeout: Duration) -> Option<Permit<'_>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(permit) = self.try_permit() {
+                return Some(permit);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            std::thread::sleep(self.permit_poll_interval.get().min(deadline - now));
+        }
+    }
+
+    /// Time each stage of sending batches, summarizing the last `window` batches in
+    /// `send_timings`. Pass `None` to stop.
+    pub fn instrument(&self, window: Option<usize>) {
+        *self.instrumentation.borrow_mut() = window.map(Instrumentation::new);
+    }
+
+    /// Where recent batches spent their time, if instrumented.
+    pub fn send_timings(&self) -> Option<SendTimings> {
+        self.instrumentation
+            .borrow()
+            .as_ref()
+            .map(Instrumentation::summary)
+    }
+
+    /// Stop recording, returning the recorder so it can be flushed.
+    pub fn stop_recording(&self) -> Option<Recorder> {
+        self.recorder.take()
+    }
+
+    pub(crate) fn hold_slot(&mut self, slot: ClientSlot) {
+        self._slot = Some(slot);
+    }
+
+    /// Estimated number of batches sent that the client hasn't received yet.
+    pub fn pending_batches(&self) -> u64 {
+        self.poll_acks();
+        self.stats.get().batches.saturating_sub(self.acked.get().0)
+    }
+
+    /// Estimated number of encoded bytes sent that the client hasn't received yet.
+    pub fn pending_bytes(&self) -> u64 {
+        self.poll_acks();
+        self.stats.get().bytes.saturating_sub(self.acked.get().1)
+    }
+
+    fn poll_acks(&self) {
+        if let Err(e) = self.poll_control() {
+            debug!("Failed to read acknowledgements: {:?}", e);
+        }
+    }
+
+    /// Handle any messages waiting from the client.
+    pub(crate) fn poll_control(&self) -> Result<(), Error> {
+        loop {
+            let received = self.control.try_recv();
+            if received.is_ok() {
+                if let Some(state) = self.keepalive.borrow_mut().as_mut() {
+                    state.heard();
+                }
+            }
+            match received {
+                Ok(Control::Received { batches, bytes }) => self.acked.set((batches, bytes)),
+                // Once the client is dropped its receive thread acknowledges for nobody
+                Ok(Control::CloseAck) if !self.consumer_gone.get() => self.close_acked.set(true),
+                Ok(Control::CloseAck) => {}
+                Ok(Control::StatsRequest) => self.stats_requested.set(true),
+                Ok(Control::Stats(snapshot)) => self.peer_stats.set(Some(snapshot)),
+                Ok(Control::Health(health)) => self.health.set(Some(health)),
+                Ok(Control::Goodbye) => self.consumer_gone.set(true),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::IpcError(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Whether the client has acknowledged receiving the close, as of the last `poll_control`.
+    pub(crate) fn close_acked(&self) -> bool {
+        self.close_acked.get()
+    }
+
+    fn transmit(&self, message: Message) -> Result<(), Error> {
+        let mut recorder = self.recorder.borrow_mut();
+        if let Some(r) = recorder.as_mut() {
+            if let Err(e) = r.record(&message) {
+                error!("Failed to record, no longer recording: {:?}", e);
+                *recorder = None;
+            }
+        }
+        let kind = message.kind();
+        let (batch, packets, bytes) = match &message {
+            Message::Batch(batch) => (true, batch.header.count, batch.data.len()),
+            _ => (false, 0, 0),
+        };
+        let started = Instant::now();
+        let result = {
+            let mut faults = self.faults.borrow_mut();
+            let action = match faults.as_mut() {
+                Some(faults) => faults.apply(message),
+                None => Action::Send(vec![message]),
+            };
+            let mut connection = self.connection.borrow_mut();
+            match (action, connection.as_ref()) {
+                (Action::Send(messages), Some(sender)) => {
+                    messages.into_iter().try_for_each(|m| sender.send(m))
+                }
+                (Action::Disconnect, Some(_)) => {
+                    warn!("Disconnecting to inject a fault");
+                    *connection = None;
+                    return Err(Error::Disconnected);
+                }
+                (_, None) => return Err(Error::Disconnected),
+            }
+        };
+        if let Some(instrumentation) = self.instrumentation.borrow_mut().as_mut() {
+            if batch {
+                instrumentation.record_send(started.elapsed());
+            }
+        }
+        result.map_err(|source| {
+            // A client that went away on purpose says so before its channel closes
+            let _ = self.poll_control();
+            if self.consumer_gone.get() {
+                debug!("Failed to send {}, consumer has gone", kind);
+                return Error::ConsumerGone;
+            }
+            error!("Failed to send {}: {:?}", kind, source);
+            Error::Send {
+                message: kind,
+                packets,
+                bytes,
+                source,
+            }
+        })
+    }
+
+    pub(crate) fn send_message(&self, mut message: Message) -> Result<(), Error> {
+        self.poll_acks();
+        if self.stats_requested.replace(false) {
+            let snapshot = Message::Snapshot(self.snapshot());
+            self.transmit(snapshot)?;
+        }
+        let (batch, heartbeat) = (
+            matches!(message, Message::Batch(_)),
+            matches!(message, Message::Heartbeat(_)),
+        );
+        let sent = match &mut message {
+            Message::Batch(batch) => {
+                self.send_drop_reports(false)?;
+                let header = &mut batch.header;
+                header.sequence = self.sequence.get();
+                self.sequence.set(header.sequence + 1);
+                if self.checksum_batches.get() {
+                    header.flags |= BATCH_FLAG_CRC;
+                    header.crc = Some(crc32(&batch.data));
+                } else {
+                    header.flags &= !BATCH_FLAG_CRC;
+                    header.crc = None;
+                }
+                Some((batch.header.count as u64, batch.data.len() as u64))
+            }
+            _ => None,
+        };
+        self.transmit(message)?;
+        if let Some(state) = self.keepalive.borrow_mut().as_mut() {
+            state.sent(batch, heartbeat);
+        }
+        if let Some((count, len)) = sent {
+            self.update_stats(|stats| {
+                stats.batches += 1;
+                stats.packets += count;
+                stats.bytes += len;
+            });
+        }
+        Ok(())
+    }
+}
+
+/// How a connection encodes batches, see `ConnectedIpc::take_encoder`.
+pub(crate) struct Encoder {
+    wire_format: WireFormat,
+    fingerprint: Option<Fingerprint>,
+    dedup: Option<Deduplicator>,
+    coalesce: Option<usize>,
+    on_error: EncodeErrorPolicy,
+    capacity: usize,
+}
+
+/// An encoded batch along with what encoding it took.
+pub(crate) struct Encoded {
+    batch: BatchBuilder,
+    duplicates: u64,
+    elapsed: Duration,
+}
+
+impl Encoder {
+    /// Encode packets as `ConnectedIpc::send` would, filtering duplicates and coalescing.
+    pub fn encode<I, T>(&mut self, packets: I) -> Result<Encoded, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsIpcPacket,
+    {
+        let started = Instant::now();
+        let mut batch = BatchBuilder::with_capacity(self.capacity)
+            .wire_format(self.wire_format)
+            .fingerprint(self.fingerprint.clone())
+            .on_encode_error(self.on_error);
+        let mut duplicates = 0;
+        {
+            let dedup = &mut self.dedup;
+            let unique = packets.into_iter().filter(|packet| {
+                let duplicate = dedup
+                    .as_mut()
+                    .is_some_and(|dedup| dedup.is_duplicate(packet));
+                duplicates += u64::from(duplicate);
+                !duplicate
+            });
+            for packet in Coalesce::new(unique, self.coalesce) {
+                batch.push(&packet)?;
+            }
+        }
+        Ok(Encoded {
+            batch,
+            duplicates,
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Room to send on a connection, granted by `ConnectedIpc::try_permit`.
+pub struct Permit<'a> {
+    connection: &'a ConnectedIpc,
+}
+
+impl<'a> Permit<'a> {
+    pub fn send<T: AsIpcPacket>(self, packets: &[T]) -> Result<(), Error> {
+        self.connection.send(packets)
+    }
+
+    pub fn send_with_priority<T: AsIpcPacket>(
+        self,
+        packets: &[T],
+        priority: Priority,
+    ) -> Result<(), Error> {
+        self.connection.send_with_priority(packets, priority)
+    }
+}
+
+impl Drop for ConnectedIpc {
+    fn drop(&mut self) {
+        if let Some(slot) = &self._slot {
+            slot.emit(ServerEvent::Disconnected {
+                info: self.info.clone(),
+                consumer: self.consumer.clone(),
+                stats: self.stats.get(),
+            });
+        }
+    }
 }
@@ -0,0 +1,145 @@
+use crate::aggregate::FlowKey;
+use crate::client::Client;
+use crate::errors::Error;
+use crate::packet::{AsIpcPacket, Packet};
+use crossbeam_channel::{Receiver, Sender};
+use log::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Spreads packets from a client across local channels, one per worker, so that packets with
+/// the same key always go to the same worker.
+pub struct Shard {
+    receivers: Vec<Receiver<Arc<Packet>>>,
+    handle: JoinHandle<Result<u64, Error>>,
+}
+
+impl Shard {
+    /// Receive from `client` on a new thread, sending each packet to one of `shards` channels
+    /// holding up to `capacity` packets each, chosen by hashing `key`. Receiving stops while a
+    /// full channel is waited on.
+    pub fn new<F, K>(mut client: Client, shards: usize, capacity: usize, key: F) -> Shard
+    where
+        F: Fn(&Packet) -> K + Send + 'static,
+        K: Hash,
+    {
+        let (senders, receivers): (Vec<Sender<Arc<Packet>>>, Vec<_>) = (0..shards.max(1))
+            .map(|_| crossbeam_channel::bounded(capacity))
+            .unzip();
+        let handle = std::thread::spawn(move || {
+            let mut distributed = 0;
+            while let Some(packets) = client.recv(usize::MAX)? {
+                for packet in packets {
+                    let mut hasher = DefaultHasher::new();
+                    key(&packet).hash(&mut hasher);
+                    let shard = (hasher.finish() % senders.len() as u64) as usize;
+                    if senders[shard].send(packet).is_err() {
+                        return Err(Error::Disconnected);
+                    }
+                    distributed += 1;
+                }
+            }
+            Ok(distributed)
+        });
+        Shard { receivers, handle }
+    }
+
+    /// Shard ethernet frames by `FlowKey`. The two directions of a connection have different keys
+    /// so may go to different shards. Non IP traffic all goes to one shard.
+    pub fn by_flow(client: Client, shards: usize, capacity: usize) -> Shard {
+        Shard::new(client, shards, capacity, |packet| {
+            FlowKey::from_ethernet(packet.data())
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.receivers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receivers.is_empty()
+    }
+
+    /// Channel for shard `index`, to be handed to its worker. Disconnects once the client closes
+    /// and every queued packet has been taken.
+    pub fn receiver(&self, index: usize) -> Receiver<Arc<Packet>> {
+        self.receivers[index].clone()
+    }
+
+    /// Packets waiting in each shard's channel.
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.receivers.iter().map(|r| r.len()).collect()
+    }
+
+    /// Wait for the client to close, returning how many packets were distributed.
+    pub fn join(self) -> Result<u64, Error> {
+        self.handle.join().unwrap_or_else(|_| {
+            error!("Shard distributor panicked");
+            Err(Error::Disconnected)
+        })
+    }
+}
+
+/// A new interface's packets, as handed out by `Partitions`.
+pub type Partition = (Option<u32>, Receiver<Arc<Packet>>);
+
+/// Splits packets from a client into a stream per capture interface, see
+/// `AsIpcPacket::interface`, so each interface can be handled with its own state.
+pub struct Partitions {
+    streams: Receiver<Partition>,
+    handle: JoinHandle<Result<u64, Error>>,
+}
+
+impl Partitions {
+    /// Receive from `client` on a new thread, sending each packet to a channel for its interface
+    /// holding up to `capacity` packets. Each channel is created when its interface is first
+    /// seen, and packets without an interface share one under `None`. Receiving stops while a
+    /// full channel is waited on, and packets for a channel whose receiver was dropped are
+    /// discarded.
+    pub fn by_interface(mut client: Client, capacity: usize) -> Partitions {
+        let (streams_tx, streams) = crossbeam_channel::unbounded();
+        let handle = std::thread::spawn(move || {
+            let mut senders: HashMap<Option<u32>, Sender<Arc<Packet>>> = HashMap::new();
+            let mut distributed = 0;
+            while let Some(packets) = client.recv(usize::MAX)? {
+                for packet in packets {
+                    let interface = packet.interface();
+                    let sender = senders.entry(interface).or_insert_with(|| {
+                        let (tx, rx) = crossbeam_channel::bounded(capacity);
+                        if streams_tx.send((interface, rx)).is_err() {
+                            debug!("No one is taking partitions for new interfaces");
+                        }
+                        tx
+                    });
+                    if sender.send(packet).is_ok() {
+                        distributed += 1;
+                    }
+                }
+            }
+            Ok(distributed)
+        });
+        Partitions { streams, handle }
+    }
+
+    /// Wait for the next interface to be seen, returning its channel, or `None` once the client
+    /// has closed and every interface has been handed out.
+    pub fn next_partition(&self) -> Option<Partition> {
+        self.streams.recv().ok()
+    }
+
+    /// Channel of new interfaces' channels, e.g. to select on alongside them.
+    pub fn partitions(&self) -> Receiver<Partition> {
+        self.streams.clone()
+    }
+
+    /// Wait for the client to close, returning how many packets were distributed to a channel.
+    pub fn join(self) -> Result<u64, Error> {
+        self.handle.join().unwrap_or_else(|_| {
+            error!("Partition distributor panicked");
+            Err(Error::Disconnected)
+        })
+    }
+}
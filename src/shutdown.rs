@@ -0,0 +1,118 @@
+use crate::info::ConsumerInfo;
+use crate::server::ConnectedIpc;
+use crate::stats::CloseSummary;
+use log::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Outcome of closing connections with a `Shutdown`.
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// Consumers that acknowledged the close, so received everything sent to them.
+    pub flushed: Vec<ConsumerInfo>,
+    /// Consumers that failed or didn't acknowledge the close before the deadline.
+    pub abandoned: Vec<ConsumerInfo>,
+}
+
+/// Coordinates closing every connection of a producer, e.g. for a service restart.
+///
+/// Clones share the same trigger, so one can be handed to whatever decides when to stop while the
+/// producer checks `is_triggered`.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    timeout: Duration,
+}
+
+impl Shutdown {
+    /// Wait up to `timeout` for clients to acknowledge the close.
+    pub fn new(timeout: Duration) -> Shutdown {
+        Shutdown {
+            triggered: Arc::new(AtomicBool::new(false)),
+            timeout,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Flush and close each connection in order, then wait for the clients to acknowledge.
+    pub fn close<I: IntoIterator<Item = ConnectedIpc>>(&self, connections: I) -> ShutdownReport {
+        self.trigger();
+        let deadline = Instant::now() + self.timeout;
+        let mut report = ShutdownReport::default();
+        for ((), consumer, status) in drain(connections.into_iter().map(|c| ((), c)), deadline) {
+            match status {
+                DrainStatus::Drained(_) => report.flushed.push(consumer),
+                _ => report.abandoned.push(consumer),
+            }
+        }
+        report
+    }
+}
+
+/// How far a connection got in draining when it was closed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DrainStatus {
+    /// The client acknowledged the close, so received everything sent to it.
+    Drained(CloseSummary),
+    /// The close was sent but not acknowledged before the deadline.
+    TimedOut(CloseSummary),
+    /// Closing failed, or the connection was lost before the close was acknowledged.
+    Failed(String),
+}
+
+/// Close each connection in order, then wait until `deadline` for the clients to acknowledge,
+/// returning how each one fared in the same order.
+pub(crate) fn drain<K, I>(connections: I, deadline: Instant) -> Vec<(K, ConsumerInfo, DrainStatus)>
+where
+    I: IntoIterator<Item = (K, ConnectedIpc)>,
+{
+    let mut drained = vec![];
+    let mut pending = vec![];
+    for (key, mut connection) in connections {
+        let consumer = connection.consumer().clone();
+        let status = match connection.close() {
+            Ok(summary) => {
+                pending.push((drained.len(), connection));
+                DrainStatus::TimedOut(summary)
+            }
+            Err(e) => {
+                error!("Failed to close connection: {:?}", e);
+                DrainStatus::Failed(e.to_string())
+            }
+        };
+        drained.push((key, consumer, status));
+    }
+
+    while !pending.is_empty() {
+        let mut waiting = vec![];
+        for (index, connection) in pending {
+            let status = &mut drained[index].2;
+            // The client may disconnect straight after acknowledging
+            let polled = connection.poll_control();
+            if connection.close_acked() {
+                if let DrainStatus::TimedOut(summary) = status {
+                    *status = DrainStatus::Drained(*summary);
+                }
+            } else if let Err(e) = polled {
+                debug!("Connection lost before close acknowledged: {:?}", e);
+                *status = DrainStatus::Failed(e.to_string());
+            } else {
+                waiting.push((index, connection));
+            }
+        }
+        pending = waiting;
+        if Instant::now() >= deadline {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    drained
+}
@@ -0,0 +1,278 @@
+//! Pipeline skeleton for feeding a connection from an asynchronous packet source, enabled with
+//! the `async` feature.
+
+use crate::errors::Error;
+use crate::packet::{AsIpcPacket, Packet};
+use crate::pcap::PcapReader;
+use crate::server::ConnectedIpc;
+use crate::stats::Stats;
+use std::future::Future;
+use std::io::Read;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Somewhere packets come from, such as a capture device or file.
+pub trait PacketSource {
+    /// The next batch of packets, or `None` once the source is exhausted.
+    fn next_batch(&mut self) -> impl Future<Output = Result<Option<Vec<Packet>>, Error>> + Send;
+}
+
+/// Send every batch from `source` to `connection`, returning what was sent once the source is
+/// exhausted. Does not close the connection.
+pub async fn pump<S: PacketSource>(
+    source: &mut S,
+    connection: &ConnectedIpc,
+) -> Result<Stats, Error> {
+    let mut sent = Stats::default();
+    while let Some(packets) = source.next_batch().await? {
+        if packets.is_empty() {
+            continue;
+        }
+        let mut batch = connection.encode(&packets)?;
+        sent.batches += 1;
+        sent.packets += batch.len() as u64;
+        sent.bytes += batch.encoded_len() as u64;
+        batch.flush(connection)?;
+    }
+    Ok(sent)
+}
+
+/// How quickly a `PcapSource` replays a capture.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Pacing {
+    /// Read packets as fast as they're asked for.
+    #[default]
+    Unpaced,
+    /// Space packets out as they were captured.
+    Original,
+    /// Space packets out as they were captured, sped up by the given factor, so 2.0 replays twice
+    /// as fast and 0.5 at half speed. Factors that aren't positive replay unpaced.
+    Speed(f64),
+}
+
+impl Pacing {
+    /// How long after the replay of the first packet to replay one captured `elapsed` later.
+    fn delay(&self, elapsed: Duration) -> Option<Duration> {
+        match *self {
+            Pacing::Unpaced => None,
+            Pacing::Original => Some(elapsed),
+            Pacing::Speed(factor) if factor > 0.0 => {
+                Duration::try_from_secs_f64(elapsed.as_secs_f64() / factor).ok()
+            }
+            Pacing::Speed(_) => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReplayState {
+    paused: bool,
+    seek: Option<Duration>,
+}
+
+#[derive(Default)]
+struct ReplayShared {
+    state: Mutex<ReplayState>,
+    changed: Condvar,
+}
+
+impl ReplayShared {
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Pauses, resumes and seeks a `PcapSource` from another task or thread, see
+/// `PcapSource::control`.
+#[derive(Clone)]
+pub struct ReplayControl {
+    shared: Arc<ReplayShared>,
+}
+
+impl ReplayControl {
+    /// Stop replaying until `resume`. Packets already due are still returned, after which
+    /// `next_batch` blocks.
+    pub fn pause(&self) {
+        self.shared.lock().paused = true;
+        self.shared.changed.notify_all();
+    }
+
+    /// Carry on replaying after `pause`, with the pacing picking up from the next packet rather
+    /// than catching up on the time spent paused.
+    pub fn resume(&self) {
+        self.shared.lock().paused = false;
+        self.shared.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shared.lock().paused
+    }
+
+    /// Skip to the first packet captured at least `offset` after the first packet in the file.
+    /// The file is read as a stream, so this only seeks forward: an offset already replayed
+    /// carries on from the next packet.
+    pub fn seek(&self, offset: Duration) {
+        self.shared.lock().seek = Some(offset);
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Reads batches of up to `batch_size` packets from a pcap file, optionally paced to the capture's
+/// timing. Reads, pacing and pausing block the calling task.
+pub struct PcapSource<R> {
+    reader: PcapReader<R>,
+    batch_size: usize,
+    pacing: Pacing,
+    shared: Arc<ReplayShared>,
+    /// Packet read but not yet due.
+    pending: Option<Packet>,
+    /// When the first packet in the file was captured, which seeks are relative to.
+    first: Option<SystemTime>,
+    /// When a packet was captured and when it was replayed, which pacing is relative to.
+    anchor: Option<(SystemTime, Instant)>,
+}
+
+impl<R: Read> PcapSource<R> {
+    pub fn new(reader: PcapReader<R>, batch_size: usize) -> PcapSource<R> {
+        PcapSource {
+            reader,
+            batch_size: batch_size.max(1),
+            pacing: Pacing::default(),
+            shared: Arc::default(),
+            pending: None,
+            first: None,
+            anchor: None,
+        }
+    }
+
+    /// Replay with `pacing`, unpaced by default. A batch holds only packets that are due, so
+    /// paced batches can be smaller than `batch_size`.
+    pub fn with_pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Handle to pause, resume or seek the replay while it runs.
+    pub fn control(&self) -> ReplayControl {
+        ReplayControl {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        let packet = match self.pending.take() {
+            Some(packet) => Some(packet),
+            None => self.reader.next_packet()?,
+        };
+        if let Some(packet) = &packet {
+            self.first.get_or_insert(*packet.timestamp());
+        }
+        Ok(packet)
+    }
+
+    fn seek(&mut self, offset: Duration) -> Result<(), Error> {
+        self.anchor = None;
+        while let Some(packet) = self.next_packet()? {
+            let target = self.first.and_then(|first| first.checked_add(offset));
+            if target.is_some_and(|target| *packet.timestamp() >= target) {
+                self.pending = Some(packet);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// When `packet` should be replayed, or `None` to replay it now.
+    fn due(&mut self, packet: &Packet) -> Option<Instant> {
+        let ts = *packet.timestamp();
+        if self.pacing == Pacing::Unpaced {
+            return None;
+        }
+        let (captured, replayed) = *self.anchor.get_or_insert_with(|| (ts, Instant::now()));
+        let elapsed = ts.duration_since(captured).unwrap_or_default();
+        self.pacing
+            .delay(elapsed)
+            .and_then(|delay| replayed.checked_add(delay))
+    }
+
+    fn read_batch(&mut self) -> Result<Option<Vec<Packet>>, Error> {
+        let shared = Arc::clone(&self.shared);
+        let mut packets = Vec::with_capacity(self.batch_size);
+        while packets.len() < self.batch_size {
+            let mut state = shared.lock();
+            if let Some(offset) = state.seek.take() {
+                drop(state);
+                self.seek(offset)?;
+                continue;
+            }
+            if state.paused {
+                if !packets.is_empty() {
+                    break;
+                }
+                self.anchor = None;
+                drop(
+                    shared
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|e| e.into_inner()),
+                );
+                continue;
+            }
+            drop(state);
+            let packet = match self.next_packet()? {
+                Some(packet) => packet,
+                None => break,
+            };
+            if let Some(wait) = self
+                .due(&packet)
+                .and_then(|due| due.checked_duration_since(Instant::now()))
+            {
+                self.pending = Some(packet);
+                if !packets.is_empty() {
+                    break;
+                }
+                let state = shared.lock();
+                if !state.paused && state.seek.is_none() {
+                    drop(
+                        shared
+                            .changed
+                            .wait_timeout(state, wait)
+                            .unwrap_or_else(|e| e.into_inner()),
+                    );
+                }
+                continue;
+            }
+            packets.push(packet);
+        }
+        Ok(if packets.is_empty() {
+            None
+        } else {
+            Some(packets)
+        })
+    }
+}
+
+impl<R: Read + Send> PacketSource for PcapSource<R> {
+    fn next_batch(&mut self) -> impl Future<Output = Result<Option<Vec<Packet>>, Error>> + Send {
+        std::future::ready(self.read_batch())
+    }
+}
+
+/// Generates batches from an iterator, e.g. to drive a pipeline in tests.
+pub struct IterSource<I> {
+    batches: I,
+}
+
+impl<I: Iterator<Item = Vec<Packet>>> IterSource<I> {
+    pub fn new<T: IntoIterator<IntoIter = I>>(batches: T) -> IterSource<I> {
+        IterSource {
+            batches: batches.into_iter(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<Packet>> + Send> PacketSource for IterSource<I> {
+    fn next_batch(&mut self) -> impl Future<Output = Result<Option<Vec<Packet>>, Error>> + Send {
+        std::future::ready(Ok(self.batches.next()))
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Counters for what a connection has sent.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Stats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub batches: u64,
+    pub drops: u64,
+    /// Packets not sent because they duplicated a recent packet.
+    pub duplicates: u64,
+    /// Packets skipped because they could not be encoded, also counted in `drops`.
+    pub encode_failures: u64,
+}
+
+/// Final accounting for a connection, returned when it is closed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CloseSummary {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub drops: u64,
+    /// How long the connection was open for.
+    pub duration: Duration,
+}
+
+/// Counters from one end of a connection, sent when the other end asks for them.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StatsSnapshot {
+    /// What a server has sent, or what a client has received.
+    pub stats: Stats,
+    /// Batches a server has sent that the client hasn't received, or batches a client has
+    /// received that haven't been taken yet.
+    pub queued_batches: u64,
+}
@@ -0,0 +1,169 @@
+//! Synthetic traffic for load testing consumers through the real IPC path.
+
+use crate::packet::Packet;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Ethernet, IPv4 and UDP headers, the smallest frame a `Generator` produces.
+const HEADER_LEN: usize = 14 + 20 + 8;
+
+/// How the sizes of generated frames are chosen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SizeDistribution {
+    Fixed(usize),
+    /// Any size from `min` to `max` inclusive, equally likely.
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+}
+
+/// Produces ethernet frames carrying UDP over IPv4, spread across a number of flows so they can
+/// be told apart with `FlowKey::from_ethernet`. Sizes smaller than the headers are rounded up.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    sizes: SizeDistribution,
+    rate: Option<f64>,
+    flows: u32,
+    skew: Duration,
+    limit: Option<u64>,
+    state: u64,
+    generated: u64,
+    started: Option<Instant>,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            sizes: SizeDistribution::Fixed(64),
+            rate: None,
+            flows: 1,
+            skew: Duration::from_secs(0),
+            limit: None,
+            state: 0x853c_49e6_748f_ea9b,
+            generated: 0,
+            started: None,
+        }
+    }
+}
+
+impl Generator {
+    pub fn new() -> Generator {
+        Generator::default()
+    }
+
+    pub fn sizes(mut self, sizes: SizeDistribution) -> Self {
+        self.sizes = sizes;
+        self
+    }
+
+    /// Limit generation to `packets_per_second`, sleeping between packets as needed. Without a
+    /// rate packets are generated as fast as they are taken.
+    pub fn rate(mut self, packets_per_second: f64) -> Self {
+        self.rate = Some(packets_per_second).filter(|rate| *rate > 0.0);
+        self
+    }
+
+    /// Number of distinct flows packets are spread across, in turn, up to 2^24.
+    pub fn flows(mut self, flows: u32) -> Self {
+        self.flows = flows.clamp(1, 1 << 24);
+        self
+    }
+
+    /// Move each timestamp up to `skew` either side of the time the packet was generated.
+    pub fn timestamp_skew(mut self, skew: Duration) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Stop after this many packets.
+    pub fn limit(mut self, packets: u64) -> Self {
+        self.limit = Some(packets);
+        self
+    }
+
+    /// Seed for sizes and skew, so runs can be repeated.
+    pub fn seed(mut self, seed: u64) -> Self {
+        // Xorshift never leaves zero
+        self.state = seed.max(1);
+        self
+    }
+
+    pub fn generated(&self) -> u64 {
+        self.generated
+    }
+
+    fn random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn size(&mut self) -> usize {
+        let size = match self.sizes {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { min, max } if max > min => {
+                min + (self.random() % (max - min + 1) as u64) as usize
+            }
+            SizeDistribution::Uniform { min, .. } => min,
+        };
+        size.max(HEADER_LEN)
+    }
+
+    fn timestamp(&mut self) -> SystemTime {
+        let now = SystemTime::now();
+        let skew_nanos = self.skew.as_nanos() as u64;
+        if skew_nanos == 0 {
+            return now;
+        }
+        let offset = Duration::from_nanos(self.random() % (skew_nanos + 1));
+        if self.random() & 1 == 0 {
+            now + offset
+        } else {
+            now.checked_sub(offset).unwrap_or(now)
+        }
+    }
+
+    fn frame(&mut self, flow: u32) -> Vec<u8> {
+        let size = self.size();
+        let mut frame = vec![0u8; size];
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        let ip = &mut frame[14..];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&((size - 14).min(u16::MAX as usize) as u16).to_be_bytes());
+        ip[8] = 64;
+        ip[9] = 17;
+        // Flows differ by source address, 10.0.0.0/8 leaves room for 2^24 of them
+        let flow = flow.to_be_bytes();
+        ip[12..16].copy_from_slice(&[10, flow[1], flow[2], flow[3]]);
+        ip[16..20].copy_from_slice(&[192, 168, 0, 1]);
+        let udp = &mut ip[20..];
+        udp[0..2].copy_from_slice(&1024u16.to_be_bytes());
+        udp[2..4].copy_from_slice(&9u16.to_be_bytes());
+        udp[4..6].copy_from_slice(&((size - 34).min(u16::MAX as usize) as u16).to_be_bytes());
+        frame
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        if self.limit.is_some_and(|limit| self.generated >= limit) {
+            return None;
+        }
+        let started = *self.started.get_or_insert_with(Instant::now);
+        if let Some(rate) = self.rate {
+            let due = started + Duration::from_secs_f64(self.generated as f64 / rate);
+            let now = Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
+            }
+        }
+        let flow = (self.generated % u64::from(self.flows)) as u32;
+        let frame = self.frame(flow);
+        let ts = self.timestamp();
+        self.generated += 1;
+        Some(Packet::new(ts, frame))
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Time spent in one stage of the send path over recent batches.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StageTimings {
+    /// Batches the summary covers.
+    pub samples: usize,
+    pub total: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+impl StageTimings {
+    fn summarize(samples: &VecDeque<Duration>) -> StageTimings {
+        let total = samples.iter().sum::<Duration>();
+        StageTimings {
+            samples: samples.len(),
+            total,
+            mean: total.checked_div(samples.len() as u32).unwrap_or_default(),
+            max: samples.iter().max().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Where batches sent on a connection spend their time, see `ConnectedIpc::instrument`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SendTimings {
+    /// Filtering duplicates and serializing packets into the batch. Only covers batches built by
+    /// `ConnectedIpc::send`.
+    pub encode: StageTimings,
+    /// Handing the encoded batch to the channel, which includes waiting for space in the OS
+    /// buffer when the client falls behind.
+    pub send: StageTimings,
+}
+
+/// Rolling window of stage timings kept while a connection is instrumented.
+#[derive(Debug)]
+pub(crate) struct Instrumentation {
+    window: usize,
+    encode: VecDeque<Duration>,
+    send: VecDeque<Duration>,
+}
+
+impl Instrumentation {
+    pub fn new(window: usize) -> Instrumentation {
+        let window = window.max(1);
+        Instrumentation {
+            window,
+            encode: VecDeque::with_capacity(window),
+            send: VecDeque::with_capacity(window),
+        }
+    }
+
+    fn push(window: usize, samples: &mut VecDeque<Duration>, elapsed: Duration) {
+        if samples.len() == window {
+            samples.pop_front();
+        }
+        samples.push_back(elapsed);
+    }
+
+    pub fn record_encode(&mut self, elapsed: Duration) {
+        Self::push(self.window, &mut self.encode, elapsed);
+    }
+
+    pub fn record_send(&mut self, elapsed: Duration) {
+        Self::push(self.window, &mut self.send, elapsed);
+    }
+
+    pub fn summary(&self) -> SendTimings {
+        SendTimings {
+            encode: StageTimings::summarize(&self.encode),
+            send: StageTimings::summarize(&self.send),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! How packets are encoded in batches, with golden samples of every wire format so downstream
+//! crates can check they agree with this build.
+
+use crate::batch::{decode_packets, BatchBuilder};
+use crate::errors::Error;
+use crate::message::{WireFormat, PROTOCOL_VERSION};
+use crate::packet::{AsIpcPacket, Packet};
+use std::time::{Duration, SystemTime};
+
+/// Version of the protocol and packet encodings this build speaks, raised on any change that
+/// would stop an older peer decoding what this build sends.
+pub const WIRE_VERSION: u32 = PROTOCOL_VERSION;
+
+/// Encode `packets` back to back as they are sent in a batch.
+pub fn encode<T: AsIpcPacket>(packets: &[T], wire_format: WireFormat) -> Result<Vec<u8>, Error> {
+    let mut builder = BatchBuilder::new().wire_format(wire_format);
+    for packet in packets {
+        builder.push(packet)?;
+    }
+    Ok(builder.into_data())
+}
+
+/// Decode `count` packets encoded back to back in `data`.
+pub fn decode(data: &[u8], count: usize, wire_format: WireFormat) -> Result<Vec<Packet>, Error> {
+    decode_packets(data, count, wire_format, None)
+}
+
+/// Packets encoded in the golden samples. Only `WireFormat::Raw` carries the original length
+/// of the truncated one.
+pub fn sample_packets() -> Vec<Packet> {
+    vec![
+        Packet::new(
+            SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789),
+            vec![0xde, 0xad, 0xbe, 0xef],
+        ),
+        Packet::new(
+            SystemTime::UNIX_EPOCH + Duration::new(1_600_000_001, 1),
+            vec![],
+        ),
+        Packet::new(
+            SystemTime::UNIX_EPOCH + Duration::new(1_600_000_002, 0),
+            vec![0x45, 0x00],
+        )
+        .with_orig_len(Some(1500)),
+    ]
+}
+
+/// `sample_packets` as encoded in `wire_format` at `WIRE_VERSION`.
+pub fn golden(wire_format: WireFormat) -> &'static [u8] {
+    match wire_format {
+        WireFormat::Bincode => GOLDEN_BINCODE,
+        WireFormat::NanosTimestamps => GOLDEN_NANOS_TIMESTAMPS,
+        WireFormat::Raw => GOLDEN_RAW,
+    }
+}
+
+#[rustfmt::skip]
+const GOLDEN_BINCODE: &[u8] = &[
+    // Seconds and nanoseconds since the epoch, data length, data
+    0x00, 0x10, 0x5e, 0x5f, 0x00, 0x00, 0x00, 0x00,
+    0x15, 0xcd, 0x5b, 0x07,
+    0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xde, 0xad, 0xbe, 0xef,
+    0x01, 0x10, 0x5e, 0x5f, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x10, 0x5e, 0x5f, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x45, 0x00,
+];
+
+#[rustfmt::skip]
+const GOLDEN_NANOS_TIMESTAMPS: &[u8] = &[
+    // Nanoseconds since the epoch, data length, data
+    0x15, 0xcd, 0xfb, 0xdf, 0x85, 0x57, 0x34, 0x16,
+    0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xde, 0xad, 0xbe, 0xef,
+    0x01, 0xca, 0x3a, 0x14, 0x86, 0x57, 0x34, 0x16,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x94, 0xd5, 0x4f, 0x86, 0x57, 0x34, 0x16,
+    0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x45, 0x00,
+];
+
+#[rustfmt::skip]
+const GOLDEN_RAW: &[u8] = &[
+    // Nanoseconds since the epoch, captured length, original length, flags, data
+    0x15, 0xcd, 0xfb, 0xdf, 0x85, 0x57, 0x34, 0x16,
+    0x04, 0x00, 0x00, 0x00,
+    0x04, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0xde, 0xad, 0xbe, 0xef,
+    0x01, 0xca, 0x3a, 0x14, 0x86, 0x57, 0x34, 0x16,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x94, 0xd5, 0x4f, 0x86, 0x57, 0x34, 0x16,
+    0x02, 0x00, 0x00, 0x00,
+    0xdc, 0x05, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x45, 0x00,
+];
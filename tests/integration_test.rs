@@ -1,4 +1,18 @@
-use packet_ipc::{AsIpcPacket, Client, Error, IpcPacket, Packet, Server};
+// The original tests predate these lints
+#![allow(clippy::useless_vec, clippy::vec_init_then_push)]
+
+use packet_ipc::aggregate::FlowAggregator;
+use packet_ipc::{
+    forward_packets, AsIpcPacket, BatchBuilder, BatchConfig, BatchingSender, BroadcastEvent,
+    Broadcaster, BufferPool, BufferSet, Client, ClientConfig, ClockOffset, CloseReason, Collector,
+    ConnectedIpc, Deduplicator, Distributor, DrainStatus, DropReason, EncodeErrorPolicy,
+    EncodeOptions, Error, ExponentialBackoff, Faults, Fingerprint, ForwardConfig, FromPayload,
+    IpcPacket, Keepalive, Liveness, MultiServer, OnFailure, Order, Packet, Partitions,
+    PayloadAllocator, PcapReader, PcapRecordHeader, Policy, Priority, ReconnectingClient, Recorder,
+    RejectReason, ReorderStats, Replayer, SendThread, SendThreadConfig, SerializedBatch, Server,
+    ServerConfig, ServerEvent, Shard, Shutdown, SmallData, StatsMonitor, StreamItem,
+    TimestampPrecision, WireFormat,
+};
 
 #[test]
 fn test_roundtrip() {
@@ -127,3 +141,4334 @@ fn test_multiple_packet_receive() {
 
     assert!(res[2].is_none());
 }
+
+#[test]
+fn test_roundtrip_shared_data() {
+    let packets = [Packet::new(std::time::SystemTime::now(), vec![3u8, 4u8])];
+    let ipc_packets: Vec<_> = packets.iter().map(IpcPacket::from).collect();
+    let data = bincode::serialize(&ipc_packets).unwrap();
+    let out_packets: Vec<Packet<std::sync::Arc<[u8]>>> =
+        bincode::deserialize(data.as_slice()).unwrap();
+    assert_eq!(packets[0].data(), out_packets[0].data());
+    assert_eq!(packets[0].timestamp(), out_packets[0].timestamp());
+}
+
+/// Stands in for a payload type from another crate, such as `bytes::Bytes`.
+#[derive(Debug)]
+struct SharedBytes(std::sync::Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+impl FromPayload for SharedBytes {
+    fn from_payload(data: &[u8]) -> Self {
+        SharedBytes(std::sync::Arc::new(data.to_vec()))
+    }
+}
+
+#[test]
+fn test_roundtrip_custom_payload() {
+    let packet = Packet::new(std::time::SystemTime::now(), vec![3u8, 4u8]);
+    let encoded = packet.encode_to_vec().expect("Failed to encode");
+    let out_packet: Packet<SharedBytes> = Packet::decode(&encoded).expect("Failed to decode");
+    assert_eq!(packet.data(), out_packet.data());
+    assert_eq!(packet.timestamp(), out_packet.timestamp());
+}
+
+#[test]
+fn test_roundtrip_small_data() {
+    let packets = [
+        Packet::new(std::time::SystemTime::now(), vec![3u8; 60]),
+        Packet::new(std::time::SystemTime::now(), vec![4u8; 1500]),
+    ];
+    let ipc_packets: Vec<_> = packets.iter().map(IpcPacket::from).collect();
+    let data = bincode::serialize(&ipc_packets).unwrap();
+    let out_packets: Vec<Packet<SmallData>> = bincode::deserialize(data.as_slice()).unwrap();
+    assert_eq!(packets[0].data(), out_packets[0].data());
+    assert_eq!(packets[1].data(), out_packets[1].data());
+
+    let mut out_packets = out_packets.into_iter();
+    assert!(out_packets.next().unwrap().into_data().is_inline());
+    assert!(!out_packets.next().unwrap().into_data().is_inline());
+}
+
+#[test]
+fn test_payload_allocator() {
+    let _ = env_logger::try_init();
+
+    let pool = std::sync::Arc::new(BufferPool::with_preallocated(64, 4, 4));
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_pool = std::sync::Arc::clone(&pool);
+    let client_thread = std::thread::spawn(move || {
+        let config = ClientConfig::default().allocator(client_pool);
+        Client::connect(server_name, config).map(|mut cli| cli.recv(2))
+    });
+
+    let connection = server.accept().expect("Failed to accept connection");
+    connection
+        .send(&[
+            Packet::new(std::time::SystemTime::now(), vec![1u8, 2u8]),
+            Packet::new(std::time::SystemTime::now(), vec![3u8]),
+        ])
+        .expect("Failed to send");
+
+    let packets = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to receive")
+        .expect("No packets");
+    assert_eq!(pool.available(), 2);
+    assert_eq!(packets[0].data(), &[1u8, 2u8]);
+
+    for packet in packets {
+        let data = std::sync::Arc::try_unwrap(packet)
+            .expect("Packet still shared")
+            .into_data();
+        assert!(data.capacity() >= 64);
+        pool.release(data);
+    }
+    assert_eq!(pool.available(), 4);
+}
+
+#[test]
+fn test_recv_into() {
+    let _ = env_logger::try_init();
+
+    let pool = std::sync::Arc::new(BufferPool::with_preallocated(64, 4, 4));
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_pool = std::sync::Arc::clone(&pool);
+    let client_thread = std::thread::spawn(move || {
+        let config = ClientConfig::default().allocator(client_pool);
+        Client::connect(server_name, config).map(|mut cli| {
+            let mut buffers = BufferSet::new(2, 1024);
+            let mut received = vec![];
+            while let Some(count) = cli.recv_into(&mut buffers).expect("Failed to receive") {
+                assert_eq!(count, buffers.len());
+                received.extend(buffers.iter().map(|p| p.data().to_vec()));
+            }
+            received
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    for i in 0..3u8 {
+        connection
+            .send(&[Packet::new(std::time::SystemTime::now(), vec![i; 3])])
+            .expect("Failed to send");
+    }
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, vec![vec![0u8; 3], vec![1u8; 3], vec![2u8; 3]]);
+    assert_eq!(pool.available(), 4);
+}
+
+#[test]
+fn test_send_trait_objects() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread =
+        std::thread::spawn(move || Client::new(server_name).map(|mut cli| cli.recv(3)));
+
+    let connection = server.accept().expect("Failed to accept connection");
+    let packets: Vec<Box<dyn AsIpcPacket + Send>> = vec![
+        Box::new(Packet::new(std::time::SystemTime::now(), vec![1u8])),
+        Box::new(Packet::new(
+            std::time::SystemTime::now(),
+            SmallData::<8>::from(&[2u8][..]),
+        )),
+        Box::new(std::sync::Arc::new(Packet::new(
+            std::time::SystemTime::now(),
+            vec![3u8],
+        ))),
+    ];
+    connection.send(&packets).expect("Failed to send");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to receive")
+        .expect("No packets");
+    let data: Vec<_> = received.iter().map(|p| p.data()[0]).collect();
+    assert_eq!(data, vec![1u8, 2u8, 3u8]);
+}
+
+#[test]
+fn test_batch_builder() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| vec![cli.recv(2), cli.recv(2)])
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .push(&Packet::new(std::time::SystemTime::now(), vec![3u8]))
+        .expect("Failed to push");
+    batch
+        .push(&Packet::new(std::time::SystemTime::now(), vec![4u8, 5u8]))
+        .expect("Failed to push");
+    assert_eq!(batch.len(), 2);
+    batch.flush(&server_tx).expect("Failed to flush");
+    assert!(batch.is_empty());
+    assert_eq!(batch.encoded_len(), 0);
+
+    server_tx.close().expect("Failed to close");
+
+    let res = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    let res: Result<Vec<_>, Error> = res.into_iter().collect();
+    let res = res.expect("Failed to get packets");
+
+    let packets = res[0].as_ref().expect("No message");
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].data(), &[3u8]);
+    assert_eq!(packets[1].data(), &[4u8, 5u8]);
+
+    assert!(res[1].is_none());
+}
+
+#[test]
+fn test_serialized_batch() {
+    let _ = env_logger::try_init();
+
+    let packets = vec![
+        Packet::new(std::time::SystemTime::now(), vec![1u8]),
+        Packet::new(std::time::SystemTime::now(), vec![2u8]),
+    ];
+    let options = EncodeOptions::default().priority(Priority::High);
+    let batch = SerializedBatch::encode(&packets, options).expect("Failed to encode");
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch.wire_format(), WireFormat::Bincode);
+
+    let mut client_threads = vec![];
+    for _ in 0..2 {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| cli.recv_with_priority(2))
+        }));
+        let connection = server.accept().expect("Failed to accept");
+        batch.send(&connection).expect("Failed to send");
+        batch.send(&connection).expect("Failed to send");
+    }
+
+    for t in client_threads {
+        let (priority, received) = t
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client")
+            .expect("Failed to receive")
+            .expect("No packets");
+        assert_eq!(priority, Priority::High);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].data(), &[2u8]);
+    }
+}
+
+#[test]
+fn test_batch_info() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let packets = cli.recv(2).expect("Failed to receive");
+            assert!(packets.is_some());
+            cli.last_batch().cloned()
+        })
+    });
+
+    let first = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1);
+    let last = std::time::UNIX_EPOCH + std::time::Duration::from_secs(2);
+    let batch = SerializedBatch::encode(
+        &[Packet::new(first, vec![1u8]), Packet::new(last, vec![2u8])],
+        EncodeOptions::default(),
+    )
+    .expect("Failed to encode");
+    assert_eq!(batch.info().last, Some(last));
+
+    let connection = server.accept().expect("Failed to accept connection");
+    batch.send(&connection).expect("Failed to send");
+
+    let info = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("No batch info");
+    assert_eq!(info.count, 2);
+    assert_eq!(info.priority, Priority::Normal);
+    assert_eq!(info.first, Some(first));
+    assert_eq!(info.last, Some(last));
+    assert!(info.lag().expect("No lag") > std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn test_batching_sender_flushes_on_interval() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            tx.send(cli.recv(1)).expect("Failed to forward");
+            cli.recv(1)
+        })
+    });
+
+    let server_tx = server.accept().expect("Failed to accept connection");
+    let config = BatchConfig::default()
+        .max_packets(100)
+        .flush_interval(Some(std::time::Duration::from_millis(10)));
+    let sender = BatchingSender::new(server_tx, config);
+
+    sender
+        .push(&Packet::new(std::time::SystemTime::now(), vec![3u8]))
+        .expect("Failed to push");
+
+    let packets = rx
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("Batch was not flushed")
+        .expect("Failed to get packets")
+        .expect("No message");
+    assert_eq!(packets[0].data()[0], 3u8);
+
+    sender.close().expect("Failed to close");
+
+    let res = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to get packets");
+    assert!(res.is_none());
+}
+
+#[test]
+fn test_batching_sender_reports_failed_flush() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        let mut cli = Client::new(server_name).expect("Failed to connect client");
+        while let Ok(Some(_)) = cli.recv(1) {}
+    });
+    let connection = server.accept().expect("Failed to accept connection");
+    connection.inject_faults(Some(Faults::new().disconnect_after(0)));
+    let config = BatchConfig::default()
+        .max_packets(100)
+        .flush_interval(Some(std::time::Duration::from_millis(10)));
+    let sender = BatchingSender::new(connection, config);
+
+    // Pushes never fill the batch, so only the flusher sends
+    let packet = Packet::new(std::time::SystemTime::now(), vec![1u8]);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let error = loop {
+        if let Err(e) = sender.push(&packet) {
+            break e;
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "Failed flush not reported"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    };
+    assert!(matches!(error, Error::Disconnected), "{:?}", error);
+    drop(sender);
+    client_thread.join().expect("Failed to join");
+}
+
+#[test]
+fn test_forward_packets() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut batches = vec![];
+            while let Some(packets) = cli.recv(1).expect("Failed to receive") {
+                batches.push(packets.iter().map(|p| p.data()[0]).collect::<Vec<_>>());
+            }
+            batches
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let packets = (0..5u8).map(|i| Packet::new(std::time::SystemTime::now(), vec![i]));
+    let config = ForwardConfig::default()
+        .batch(BatchConfig::default().max_packets(2).flush_interval(None))
+        .on_congestion(std::time::Duration::from_secs(60), |_| {});
+    let stats = forward_packets(packets, &connection, config).expect("Failed to forward");
+    assert_eq!(stats.packets, 5);
+    assert_eq!(stats.batches, 3);
+    assert_eq!(connection.stats().packets, 5);
+    connection.close().expect("Failed to close");
+
+    let batches = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(batches.concat(), vec![0u8, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_recv_deadline() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let soon = std::time::Instant::now() + std::time::Duration::from_millis(10);
+            assert!(cli
+                .recv_deadline(10, soon)
+                .expect("Failed to receive")
+                .is_none());
+            tx.send(()).expect("Failed to signal");
+
+            let later = std::time::Instant::now() + std::time::Duration::from_secs(5);
+            let heartbeat = cli.recv_deadline(10, later).expect("Failed to receive");
+            assert!(heartbeat.is_none());
+            assert!(std::time::Instant::now() < later);
+            tx.send(()).expect("Failed to signal");
+
+            let packets = cli
+                .recv_deadline(10, later)
+                .expect("Failed to receive")
+                .expect("No packets");
+            assert_eq!(packets.len(), 1);
+            assert!(cli
+                .recv_deadline(10, later)
+                .expect("Failed to receive")
+                .is_none());
+            assert!(cli.close_reason().is_some());
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    rx.recv().expect("Client did not time out");
+    connection.heartbeat().expect("Failed to send heartbeat");
+    rx.recv().expect("Client did not wake on heartbeat");
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+}
+
+#[test]
+fn test_queue_depth() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            done_rx.recv().expect("Server did not finish");
+            tx.send(cli.buffered()).expect("Failed to send");
+            cli.recv(10)
+        })
+    });
+
+    let connection = server.accept().expect("Failed to accept connection");
+    let config = BatchConfig::default().flush_interval(None);
+    let sender = BatchingSender::new(connection, config);
+    assert_eq!(sender.pending_batches(), 0);
+
+    let packet = Packet::new(std::time::SystemTime::now(), vec![1u8, 2, 3]);
+    sender.push(&packet).expect("Failed to push");
+    sender.flush().expect("Failed to flush");
+    sender.push(&packet).expect("Failed to push");
+    sender.push(&packet).expect("Failed to push");
+    assert!(sender.pending_batches() >= 1);
+    assert!(sender.pending_bytes() > 0);
+
+    // The flushed batch is acknowledged once the client receives it, leaving the open batch
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while sender.pending_batches() > 1 {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "Batch not acknowledged"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(sender.pending_batches(), 1);
+
+    done_tx.send(()).expect("Failed to signal");
+    assert_eq!(rx.recv().expect("Failed to receive"), 1);
+    sender.close().expect("Failed to close");
+
+    let res = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to get packets")
+        .expect("No packets");
+    assert_eq!(res.len(), 3);
+}
+
+#[test]
+fn test_priority_receive() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            rx.recv().expect("Failed to wait for sends");
+            vec![
+                cli.recv_with_priority(1),
+                cli.recv_with_priority(1),
+                cli.recv_with_priority(1),
+            ]
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+
+    server_tx
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![3u8])])
+        .expect("Failed to send");
+    server_tx
+        .send_with_priority(
+            &[Packet::new(std::time::SystemTime::now(), vec![4u8])],
+            Priority::High,
+        )
+        .expect("Failed to send");
+    server_tx.close().expect("Failed to close");
+    // give the client's receive thread time to pick up both batches
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    tx.send(()).expect("Failed to signal");
+
+    let res = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    let res: Result<Vec<_>, Error> = res.into_iter().collect();
+    let res = res.expect("Failed to get packets");
+
+    let (priority, packets) = res[0].as_ref().expect("No message");
+    assert_eq!(*priority, Priority::High);
+    assert_eq!(packets[0].data()[0], 4u8);
+
+    let (priority, packets) = res[1].as_ref().expect("No message");
+    assert_eq!(*priority, Priority::Normal);
+    assert_eq!(packets[0].data()[0], 3u8);
+
+    assert!(res[2].is_none());
+}
+
+#[test]
+fn test_connection_info() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || Client::new(server_name));
+
+    let server_tx = server.accept().expect("Failed to accept connection");
+
+    let client = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    assert_eq!(server_tx.info().peer_pid(), Some(std::process::id()));
+    assert_eq!(client.info().peer_pid(), Some(std::process::id()));
+    assert_eq!(
+        server_tx.info().protocol_version(),
+        client.info().protocol_version()
+    );
+    assert!(server_tx.info().connected_at() <= client.info().connected_at());
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_connect_to_named_server() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-test-{}", std::process::id());
+    let server = Server::new_with_name(&name).expect("Failed to create server");
+
+    match Server::new_with_name(&name) {
+        Err(Error::NameTaken(_)) => {}
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Name should be taken"),
+    }
+
+    let client_thread = std::thread::spawn(move || Client::new(name));
+
+    let _server_tx = server.accept().expect("Failed to accept connection");
+
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_runtime_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _ = env_logger::try_init();
+
+    let dir = std::env::temp_dir().join(format!("packet-ipc-runtime-{}", std::process::id()));
+    let config = ServerConfig::default().runtime_dir(&dir).permissions(0o600);
+    let server =
+        Server::new_with_name_and_config("capture", config).expect("Failed to create server");
+    assert_eq!(server.name(), &dir.join("capture").display().to_string());
+
+    let mode = std::fs::metadata(&dir)
+        .expect("Failed to read runtime dir")
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o700);
+    let endpoint = std::fs::read_link(dir.join("capture")).expect("Failed to read link");
+    let mode = std::fs::metadata(&endpoint)
+        .expect("Failed to read endpoint")
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let name = server.name().clone();
+    let client_thread = std::thread::spawn(move || Client::new(name));
+    let _server_tx = server.accept().expect("Failed to accept connection");
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755))
+        .expect("Failed to loosen runtime dir");
+    let config = ServerConfig::default().runtime_dir(&dir);
+    match Server::new_with_name_and_config("capture", config) {
+        Err(Error::InsecureDirectory(_)) => {}
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Runtime dir should be rejected"),
+    }
+    std::fs::remove_dir_all(&dir).expect("Failed to remove runtime dir");
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_multi_server() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-multi-test-{}", std::process::id());
+    let server = MultiServer::new(&name, ServerConfig::default()).expect("Failed to create server");
+
+    let clients: Vec<_> = (0..4)
+        .map(|i| {
+            let name = name.clone();
+            std::thread::spawn(move || {
+                let config = ClientConfig::default()
+                    .name(&format!("client-{}", i))
+                    .retry_connect(std::time::Duration::from_secs(5));
+                let mut client = Client::connect(name, config).expect("Failed to connect client");
+                let packets = client.recv(1).expect("Failed to receive");
+                assert_eq!(packets.map(|p| p.len()), Some(1));
+                client
+            })
+        })
+        .collect();
+
+    let mut names: Vec<_> = server
+        .incoming()
+        .take(4)
+        .map(|connection| {
+            let packet = Packet::new(std::time::SystemTime::now(), vec![1u8, 2, 3]);
+            connection.send(&[packet]).expect("Failed to send");
+            connection.consumer().name().map(str::to_owned)
+        })
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        (0..4)
+            .map(|i| Some(format!("client-{}", i)))
+            .collect::<Vec<_>>()
+    );
+
+    for client in clients {
+        client.join().expect("Failed to join");
+    }
+
+    drop(server);
+    assert!(!std::env::temp_dir().join(&name).exists());
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_multi_server_concurrent_connects() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-multi-concurrent-{}", std::process::id());
+    let server = MultiServer::new(&name, ServerConfig::default()).expect("Failed to create server");
+
+    // Clients connecting while the name moves on retry without being told to
+    let clients = 16;
+    let start = std::sync::Arc::new(std::sync::Barrier::new(clients));
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            let name = name.clone();
+            let start = std::sync::Arc::clone(&start);
+            std::thread::spawn(move || {
+                start.wait();
+                let mut client = Client::new(name).expect("Failed to connect client");
+                let packets = client.recv(1).expect("Failed to receive");
+                assert_eq!(packets.map(|p| p.len()), Some(1));
+            })
+        })
+        .collect();
+
+    for _ in 0..clients {
+        let connection = server
+            .accept_timeout(std::time::Duration::from_secs(5))
+            .expect("Failed to accept")
+            .expect("Client never connected");
+        let packet = Packet::new(std::time::SystemTime::now(), vec![1u8]);
+        connection.send(&[packet]).expect("Failed to send");
+    }
+    for thread in threads {
+        thread.join().expect("Failed to join");
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_multi_server_pause() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-pause-test-{}", std::process::id());
+    let server = MultiServer::new(&name, ServerConfig::default()).expect("Failed to create server");
+
+    server.pause_accept();
+    assert!(server.is_paused());
+    match Client::new(name.clone()) {
+        Err(Error::Rejected(RejectReason::Paused)) => {}
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Client should have been turned away"),
+    }
+
+    server.resume_accept();
+    let config = ClientConfig::default().retry_connect(std::time::Duration::from_secs(5));
+    let client_thread = std::thread::spawn(move || Client::connect(name, config));
+    let _connection = server.accept().expect("Failed to accept");
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_multi_server_capacity() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-capacity-test-{}", std::process::id());
+    let config = ServerConfig::default().max_clients(1);
+    let server = MultiServer::new(&name, config).expect("Failed to create server");
+    let retry = ClientConfig::default().retry_connect(std::time::Duration::from_secs(5));
+
+    let _client = Client::connect(name.clone(), retry.clone()).expect("Failed to connect");
+    let connection = server.accept().expect("Failed to accept");
+    assert_eq!(server.clients(), 1);
+
+    match Client::connect(name.clone(), retry.clone()) {
+        Err(Error::Rejected(RejectReason::AtCapacity)) => {}
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Client should have been turned away"),
+    }
+    let events: Vec<_> = server.events().collect();
+    assert_eq!(events.len(), 2);
+    match &events[0] {
+        ServerEvent::Connected { info, .. } => assert_eq!(info, connection.info()),
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert_eq!(
+        events[1],
+        ServerEvent::Rejected {
+            pid: std::process::id(),
+            reason: RejectReason::AtCapacity
+        }
+    );
+
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+    drop(connection);
+    assert_eq!(server.clients(), 0);
+    let event = server
+        .event_stream()
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("Failed to receive event");
+    match event {
+        ServerEvent::Disconnected { stats, .. } => assert_eq!(stats.packets, 1),
+        e => panic!("Unexpected event {:?}", e),
+    }
+    let _client = Client::connect(name, retry).expect("Failed to connect");
+    let _connection = server.accept().expect("Failed to accept");
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_reconnecting_client() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-reconnect-test-{}", std::process::id());
+    let server = Server::new_with_name(&name).expect("Failed to create server");
+
+    let client_name = name.clone();
+    let client_thread = std::thread::spawn(move || {
+        let backoff = ExponentialBackoff::default()
+            .initial(std::time::Duration::from_millis(1))
+            .max_attempts(Some(20));
+        let mut cli = ReconnectingClient::connect(client_name, ClientConfig::default())
+            .expect("Failed to connect")
+            .backoff(backoff);
+        let mut items = vec![];
+        while let Some(item) = cli.recv_item().expect("Failed to receive") {
+            items.push(item);
+        }
+        items
+    });
+
+    let mut connection = server.accept().expect("Failed to accept");
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+    connection
+        .close_with_reason(CloseReason::ProducerError("restarting".to_owned()))
+        .expect("Failed to close");
+
+    let server = Server::new_with_name(&name).expect("Failed to create server");
+    let mut connection = server.accept().expect("Failed to accept");
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![2u8])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let items = client_thread.join().expect("Failed to join");
+    assert_eq!(items.len(), 4, "{:?}", items);
+    assert!(matches!(&items[0], StreamItem::Packets(p) if p[0].data() == [1u8]));
+    assert!(matches!(items[1], StreamItem::Reconnected { .. }));
+    assert!(matches!(&items[2], StreamItem::Packets(p) if p[0].data() == [2u8]));
+    assert!(matches!(items[3], StreamItem::Closed(CloseReason::Normal)));
+}
+
+#[test]
+fn test_connect_from_env() {
+    let _ = env_logger::try_init();
+
+    let var = "PACKET_IPC_TEST_CONNECT_FROM_ENV";
+
+    match Client::connect_from_env(var) {
+        Err(Error::MissingEnv(missing)) => assert_eq!(missing, var),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Variable should be missing"),
+    }
+
+    let server = Server::new().expect("Failed to create server");
+    server.export_to_env(var).expect("Failed to export");
+
+    let client_thread = std::thread::spawn(move || Client::connect_from_env(var));
+
+    let _server_tx = server.accept().expect("Failed to accept connection");
+
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+}
+
+#[test]
+fn test_consumer_info() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        let config = ClientConfig::default()
+            .name("recorder")
+            .version("1.2.3")
+            .snaplen(128)
+            .filter("tcp port 80");
+        Client::connect(server_name, config)
+    });
+
+    let server_tx = server.accept().expect("Failed to accept connection");
+
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    let consumer = server_tx.consumer();
+    assert_eq!(consumer.name(), Some("recorder"));
+    assert_eq!(consumer.version(), Some("1.2.3"));
+    assert_eq!(consumer.snaplen(), Some(128));
+    assert_eq!(consumer.filter(), Some("tcp port 80"));
+    assert_eq!(consumer.format(), None);
+}
+
+#[test]
+fn test_broadcast_policies() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let mut client_threads = vec![];
+    let policies = vec![
+        Policy::default(),
+        Policy::default().snaplen(2),
+        Policy::default().sample(2),
+        Policy::default().filter(|p| p.data()[0] != 3u8),
+    ];
+    for policy in policies {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| {
+                let mut received = vec![];
+                while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                    received.extend(packets.iter().map(|p| p.data().to_vec()));
+                }
+                received
+            })
+        }));
+        broadcaster.add(server.accept().expect("Failed to accept"), policy);
+    }
+    assert_eq!(broadcaster.len(), 4);
+
+    broadcaster
+        .send(&[
+            Packet::new(std::time::SystemTime::now(), vec![3u8, 3u8, 3u8]),
+            Packet::new(std::time::SystemTime::now(), vec![4u8, 4u8, 4u8]),
+        ])
+        .expect("Failed to send");
+    broadcaster.close().expect("Failed to close");
+
+    let received: Vec<_> = client_threads
+        .into_iter()
+        .map(|t| {
+            t.join()
+                .expect("Failed to join")
+                .expect("Failed to connect client")
+        })
+        .collect();
+
+    assert_eq!(received[0], vec![vec![3u8, 3u8, 3u8], vec![4u8, 4u8, 4u8]]);
+    assert_eq!(received[1], vec![vec![3u8, 3u8], vec![4u8, 4u8]]);
+    assert_eq!(received[2], vec![vec![3u8, 3u8, 3u8]]);
+    assert_eq!(received[3], vec![vec![4u8, 4u8, 4u8]]);
+}
+
+#[test]
+fn test_collector() {
+    let _ = env_logger::try_init();
+
+    let base = std::time::UNIX_EPOCH;
+    let mut server_threads = vec![];
+    let mut collector = Collector::new(Order::Timestamp);
+    for offset in 0..2u64 {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        server_threads.push(std::thread::spawn(move || {
+            let mut connection = server.accept().expect("Failed to accept");
+            for i in 0..3u64 {
+                let ts = base + std::time::Duration::from_secs(i * 2 + offset);
+                connection
+                    .send(&[Packet::new(ts, vec![offset as u8])])
+                    .expect("Failed to send");
+            }
+            connection.close().expect("Failed to close");
+        }));
+        let config = ClientConfig::default().channel_size(Some(1));
+        let client = Client::connect(server_name, config).expect("Failed to connect");
+        assert_eq!(collector.add(client), offset as usize);
+    }
+    for t in server_threads {
+        t.join().expect("Failed to join");
+    }
+
+    let mut received = vec![];
+    while let Some((source, packets)) = collector.recv(1).expect("Failed to receive") {
+        assert_eq!(packets[0].data()[0] as usize, source);
+        received.push(*packets[0].timestamp());
+    }
+    assert_eq!(received.len(), 6);
+
+    let lag = collector.lag();
+    assert!(lag.iter().all(|l| l.closed && l.buffered == 0));
+    assert_eq!(lag[0].behind, Some(std::time::Duration::from_secs(1)));
+    assert_eq!(lag[1].behind, Some(std::time::Duration::from_secs(0)));
+}
+
+#[test]
+fn test_collector_reorder_stats() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let server_thread = std::thread::spawn(move || {
+        let mut connection = server.accept().expect("Failed to accept");
+        for secs in [10u64, 0, 11, 5, 12, 30] {
+            let ts = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            connection
+                .send(&[Packet::new(ts, vec![0u8])])
+                .expect("Failed to send");
+        }
+        connection.close().expect("Failed to close");
+    });
+    let mut collector =
+        Collector::new(Order::Timestamp).reorder_window(std::time::Duration::from_secs(8));
+    collector.add(Client::new(server_name).expect("Failed to connect"));
+    server_thread.join().expect("Failed to join");
+
+    while collector.recv(1).expect("Failed to receive").is_some() {}
+    assert_eq!(
+        collector.reorder_stats(),
+        ReorderStats {
+            packets: 6,
+            late: 2,
+            outside_window: 1,
+            max_lateness: std::time::Duration::from_secs(10),
+        }
+    );
+}
+
+#[test]
+fn test_broadcast_shared_encoding() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let mut client_threads = vec![];
+    let policies = vec![
+        Policy::default(),
+        Policy::default().snaplen(1),
+        Policy::default(),
+        Policy::default().snaplen(1),
+        Policy::default(),
+    ];
+    for (i, policy) in policies.into_iter().enumerate() {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| {
+                let mut received = vec![];
+                while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                    received.extend(packets.iter().map(|p| {
+                        assert_eq!(p.interface(), Some(3));
+                        let truncated = p.data().len() == 1;
+                        assert_eq!(p.orig_len(), Some(2).filter(|_| truncated));
+                        p.data().to_vec()
+                    }));
+                }
+                received
+            })
+        }));
+        let connection = server.accept().expect("Failed to accept");
+        if i == 4 {
+            // Can't share the others' batches
+            connection.set_dedup(Some(Deduplicator::new(
+                8,
+                std::time::Duration::from_secs(1),
+            )));
+        }
+        broadcaster.add(connection, policy);
+    }
+
+    let ts = std::time::SystemTime::now();
+    for _ in 0..2 {
+        broadcaster
+            .send(&[Packet::new(ts, vec![1u8, 2u8]).with_interface(Some(3))])
+            .expect("Failed to send");
+    }
+    broadcaster.close().expect("Failed to close");
+
+    let received: Vec<_> = client_threads
+        .into_iter()
+        .map(|t| {
+            t.join()
+                .expect("Failed to join")
+                .expect("Failed to connect client")
+        })
+        .collect();
+    assert_eq!(received[0], vec![vec![1u8, 2u8]; 2]);
+    assert_eq!(received[1], vec![vec![1u8]; 2]);
+    assert_eq!(received[2], received[0]);
+    assert_eq!(received[3], received[1]);
+    assert_eq!(received[4], vec![vec![1u8, 2u8]]);
+}
+
+#[test]
+fn test_broadcast_quotas() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let mut client_threads = vec![];
+    let policies = vec![
+        Policy::default(),
+        Policy::default().max_rate(10),
+        Policy::default().max_queued_bytes(0),
+    ];
+    for policy in policies {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| {
+                let mut received = 0;
+                while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                    received += packets.len();
+                }
+                (received, cli.dropped())
+            })
+        }));
+        broadcaster.add(server.accept().expect("Failed to accept"), policy);
+    }
+
+    let ts = std::time::SystemTime::now();
+    let packets = (0..4u8)
+        .map(|i| Packet::new(ts, vec![i; 5]))
+        .collect::<Vec<_>>();
+    broadcaster.send(&packets).expect("Failed to send");
+    let throttled = broadcaster
+        .events()
+        .filter_map(|e| match e {
+            BroadcastEvent::Throttled { id, packets } => Some((id, packets)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(throttled, vec![(1, 2), (2, 4)]);
+    broadcaster.close().expect("Failed to close");
+
+    let received: Vec<_> = client_threads
+        .into_iter()
+        .map(|t| {
+            t.join()
+                .expect("Failed to join")
+                .expect("Failed to connect client")
+        })
+        .collect();
+    assert_eq!(received, vec![(4, 0), (2, 2), (0, 4)]);
+}
+
+#[test]
+fn test_broadcast_policy_reload() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data().to_vec()));
+            }
+            received
+        })
+    });
+    let id = broadcaster.add(
+        server.accept().expect("Failed to accept"),
+        Policy::default(),
+    );
+    let handle = broadcaster.policy(id).expect("Destination not found");
+    assert!(broadcaster.policy(id + 1).is_none());
+
+    let ts = std::time::SystemTime::now();
+    let packets = vec![Packet::new(ts, vec![1u8, 2, 3])];
+    broadcaster.send(&packets).expect("Failed to send");
+
+    let reload = std::thread::spawn(move || handle.set(Policy::default().snaplen(1)));
+    reload.join().expect("Failed to join");
+    broadcaster.send(&packets).expect("Failed to send");
+
+    let handle = broadcaster.policy(id).expect("Destination not found");
+    handle.set(Policy::default().filter(|_| false));
+    broadcaster.send(&packets).expect("Failed to send");
+    broadcaster.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, vec![vec![1u8, 2, 3], vec![1u8]]);
+}
+
+#[test]
+fn test_broadcast_failures() {
+    let _ = env_logger::try_init();
+
+    let add_dropped = |broadcaster: &mut Broadcaster, on_failure: OnFailure| {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        let client_thread = std::thread::spawn(move || Client::new(server_name).map(|_| ()));
+        let id = broadcaster.add(
+            server.accept().expect("Failed to accept"),
+            Policy::default().on_failure(on_failure),
+        );
+        client_thread
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client");
+        id
+    };
+
+    let mut broadcaster = Broadcaster::new();
+    let removed = add_dropped(&mut broadcaster, OnFailure::Remove);
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let live_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = 0;
+            while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                received += packets.len();
+            }
+            received
+        })
+    });
+    let retry = OnFailure::Retry {
+        retries: 2,
+        backoff: std::time::Duration::from_millis(1),
+    };
+    let live = broadcaster.add(
+        server.accept().expect("Failed to accept"),
+        Policy::default().on_failure(retry),
+    );
+    let failing = add_dropped(&mut broadcaster, OnFailure::Fail);
+
+    let packets = [Packet::new(std::time::SystemTime::now(), vec![1u8])];
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let mut sent = 1;
+    while broadcaster.send(&packets).is_ok() {
+        assert!(std::time::Instant::now() < deadline, "Send did not fail");
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        sent += 1;
+    }
+    // Failing destinations that were to be removed are, while Fail leaves them in place
+    assert_eq!(broadcaster.len(), 2);
+    assert!(broadcaster.send(&packets).is_err());
+    sent += 1;
+
+    let events = broadcaster.events().collect::<Vec<_>>();
+    let added = events
+        .iter()
+        .filter_map(|e| match e {
+            BroadcastEvent::Added { id, .. } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(added, vec![removed, live, failing]);
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, BroadcastEvent::Removed { id, .. } if *id == removed)));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, BroadcastEvent::Failed { id, .. } if *id == failing)));
+    assert!(events.iter().all(|e| match e {
+        BroadcastEvent::Failed { id, .. } | BroadcastEvent::Removed { id, .. } => *id != live,
+        _ => true,
+    }));
+
+    // The live destination is closed before reaching the failing one
+    assert!(broadcaster.close().is_err());
+    let received = live_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn test_broadcast_encode_failures() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let mut clients = vec![];
+    let mut ids = vec![];
+    for on_failure in [OnFailure::Remove, OnFailure::Fail] {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        clients.push(std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| {
+                let mut received = 0;
+                while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                    received += packets.len();
+                }
+                received
+            })
+        }));
+        ids.push(broadcaster.add(
+            server.accept().expect("Failed to accept"),
+            Policy::default().on_failure(on_failure),
+        ));
+    }
+
+    // Bincode can't encode a timestamp before the epoch
+    let ts = std::time::SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+    assert!(broadcaster.send(&[Packet::new(ts, vec![1u8])]).is_err());
+    assert_eq!(broadcaster.len(), 1);
+
+    let events = broadcaster.events().collect::<Vec<_>>();
+    let failed = events
+        .iter()
+        .filter_map(|e| match e {
+            BroadcastEvent::Failed { id, .. } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(failed, ids);
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, BroadcastEvent::Removed { id, .. } if *id == ids[0])));
+
+    broadcaster.close().expect("Failed to close");
+    for client in clients {
+        client
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client");
+    }
+}
+
+#[test]
+fn test_broadcast_skipped_packets() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let mut clients = vec![];
+    let mut ids = vec![];
+    // Two destinations share an encoded batch, the filtered one encodes its own
+    let policies = [
+        Policy::default(),
+        Policy::default(),
+        Policy::default().filter(|_| true),
+    ];
+    for policy in policies {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        clients.push(std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| {
+                let mut received = 0;
+                while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                    received += packets.len();
+                }
+                received
+            })
+        }));
+        let connection = server.accept().expect("Failed to accept");
+        connection.on_encode_error(EncodeErrorPolicy::SkipPacket { log: false });
+        ids.push(broadcaster.add(connection, policy));
+    }
+
+    let before_epoch = std::time::SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+    broadcaster
+        .send(&[
+            Packet::new(before_epoch, vec![1u8]),
+            Packet::new(std::time::SystemTime::now(), vec![2u8]),
+        ])
+        .expect("Failed to send");
+
+    for (id, client) in ids.into_iter().zip(clients) {
+        let mut connection = broadcaster.remove(id).expect("No destination");
+        let stats = connection.stats();
+        assert_eq!(stats.encode_failures, 1);
+        assert_eq!(stats.drops, 1);
+        connection.close().expect("Failed to close");
+        drop(connection);
+        let received = client
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client");
+        assert_eq!(received, 1);
+    }
+}
+
+#[test]
+fn test_broadcast_membership() {
+    let _ = env_logger::try_init();
+
+    let connect = |name: &str| {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        let config = ClientConfig::default().name(name);
+        let client_thread = std::thread::spawn(move || {
+            let mut cli = Client::connect(server_name, config).expect("Failed to connect");
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data()[0]));
+            }
+            received
+        });
+        (server.accept().expect("Failed to accept"), client_thread)
+    };
+    let send = |broadcaster: &mut Broadcaster, value: u8| {
+        broadcaster
+            .send(&[Packet::new(std::time::SystemTime::now(), vec![value])])
+            .expect("Failed to send")
+    };
+
+    let mut broadcaster = Broadcaster::new();
+    let (connection, first) = connect("first");
+    let first_id = broadcaster.add(connection, Policy::default());
+    send(&mut broadcaster, 1);
+
+    let (connection, second) = connect("second");
+    broadcaster.add(connection, Policy::default());
+    send(&mut broadcaster, 2);
+
+    let removed = broadcaster.remove(first_id).expect("Destination not found");
+    assert!(broadcaster.remove(first_id).is_none());
+    send(&mut broadcaster, 3);
+    let report = Shutdown::new(std::time::Duration::from_secs(5)).close(vec![removed]);
+    assert_eq!(report.flushed[0].name(), Some("first"));
+
+    broadcaster.close().expect("Failed to close");
+    assert_eq!(first.join().expect("Failed to join"), vec![1, 2]);
+    assert_eq!(second.join().expect("Failed to join"), vec![2, 3]);
+    assert!(broadcaster.events().any(
+        |e| matches!(e, BroadcastEvent::Removed { id, consumer } if id == first_id && consumer.name() == Some("first"))
+    ));
+}
+
+#[test]
+fn test_distributor_rebalancing() {
+    use packet_ipc::testing::Generator;
+
+    let _ = env_logger::try_init();
+
+    let mut distributor = Distributor::by_flow();
+    let mut client_threads = vec![];
+    let mut ids = vec![];
+    for _ in 0..3 {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            let mut cli = Client::new(server_name).expect("Failed to connect");
+            let mut received = 0;
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received += packets.len();
+            }
+            received
+        }));
+        ids.push(distributor.add(server.accept().expect("Failed to accept")));
+    }
+
+    let packets = Generator::new().flows(300).limit(300).collect::<Vec<_>>();
+    let before = packets
+        .iter()
+        .map(|p| distributor.destination_for(p).expect("No destination"))
+        .collect::<Vec<_>>();
+    for id in &ids {
+        assert!(before.contains(id), "Destination {} was given no flows", id);
+    }
+    distributor.send(&packets).expect("Failed to send");
+
+    let mut removed = distributor.remove(ids[1]).expect("Destination not found");
+    removed.close().expect("Failed to close");
+    let after = packets
+        .iter()
+        .map(|p| distributor.destination_for(p).expect("No destination"))
+        .collect::<Vec<_>>();
+    for (before, after) in before.iter().zip(after.iter()) {
+        assert_ne!(*after, ids[1]);
+        if *before != ids[1] {
+            assert_eq!(before, after, "Flow moved between surviving destinations");
+        }
+    }
+    distributor.send(&packets).expect("Failed to send");
+    distributor.close().expect("Failed to close");
+
+    let received = client_threads
+        .into_iter()
+        .map(|t| t.join().expect("Failed to join"))
+        .collect::<Vec<_>>();
+    assert_eq!(received.iter().sum::<usize>(), 600);
+    assert_eq!(
+        received[1],
+        before.iter().filter(|id| **id == ids[1]).count()
+    );
+}
+
+#[test]
+fn test_distributor_shutdown() {
+    use packet_ipc::testing::Generator;
+
+    let _ = env_logger::try_init();
+
+    let mut distributor = Distributor::by_flow();
+    let mut client_threads = vec![];
+    for i in 0..2 {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            let mut cli = Client::new(server_name).expect("Failed to connect");
+            if i == 1 {
+                // Gone before shutdown
+                return 0;
+            }
+            let mut received = 0;
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received += packets.len();
+            }
+            received
+        }));
+        distributor.add(server.accept().expect("Failed to accept"));
+    }
+    let gone = client_threads.pop().expect("No client");
+    gone.join().expect("Failed to join");
+
+    let packets = Generator::new().flows(10).limit(10).collect::<Vec<_>>();
+    let to_live = packets
+        .into_iter()
+        .filter(|p| distributor.destination_for(p) == Some(0))
+        .collect::<Vec<_>>();
+    distributor.send(&to_live).expect("Failed to send");
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let drains = distributor.shutdown(deadline);
+    assert_eq!(drains.iter().map(|d| d.id).collect::<Vec<_>>(), vec![0, 1]);
+    match &drains[0].status {
+        DrainStatus::Drained(summary) => {
+            assert_eq!(summary.packets_sent, to_live.len() as u64)
+        }
+        status => panic!("Unexpected status {:?}", status),
+    }
+    assert!(!matches!(drains[1].status, DrainStatus::Drained(_)));
+
+    let received = client_threads
+        .pop()
+        .expect("No client")
+        .join()
+        .expect("Failed to join");
+    assert_eq!(received, to_live.len());
+}
+
+#[test]
+fn test_shutdown() {
+    let _ = env_logger::try_init();
+
+    let mut broadcaster = Broadcaster::new();
+    let mut client_threads = vec![];
+    for i in 0..2 {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        client_threads.push(std::thread::spawn(move || {
+            let config = ClientConfig::default().name(&format!("client-{}", i));
+            let mut cli = Client::connect(server_name, config).expect("Failed to connect");
+            let mut received = 0;
+            while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                received += packets.len();
+            }
+            (received, cli.close_reason().cloned())
+        }));
+        broadcaster.add(
+            server.accept().expect("Failed to accept"),
+            Policy::default(),
+        );
+    }
+
+    broadcaster
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+
+    let shutdown = Shutdown::new(std::time::Duration::from_secs(5));
+    let trigger = shutdown.clone();
+    trigger.trigger();
+    assert!(shutdown.is_triggered());
+
+    let report = broadcaster.shutdown(&shutdown);
+    assert!(report.abandoned.is_empty());
+    let mut names: Vec<_> = report.flushed.iter().map(|c| c.name()).collect();
+    names.sort();
+    assert_eq!(names, vec![Some("client-0"), Some("client-1")]);
+
+    for t in client_threads {
+        let (received, reason) = t.join().expect("Failed to join");
+        assert_eq!(received, 1);
+        assert_eq!(reason, Some(CloseReason::Normal));
+    }
+}
+
+#[test]
+fn test_drop_report_receive() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut items = vec![];
+            while let Some(item) = cli.recv_item().expect("Failed to receive") {
+                items.push(item);
+            }
+            (items, cli.dropped())
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+    server_tx.set_drop_report_interval(std::time::Duration::from_secs(0));
+
+    server_tx.record_drops(2, DropReason::Overflow);
+    server_tx.record_drops(3, DropReason::Overflow);
+    server_tx
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![3u8])])
+        .expect("Failed to send");
+    server_tx.record_drops(1, DropReason::Degraded);
+    server_tx.close().expect("Failed to close");
+
+    let (items, dropped) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    assert_eq!(dropped, 6);
+    assert_eq!(items.len(), 4);
+    match &items[0] {
+        StreamItem::DropReport(report) => {
+            assert_eq!(report.count, 5);
+            assert_eq!(report.reason, DropReason::Overflow);
+        }
+        i => panic!("Unexpected item {:?}", i),
+    }
+    match &items[1] {
+        StreamItem::Packets(packets) => assert_eq!(packets[0].data()[0], 3u8),
+        i => panic!("Unexpected item {:?}", i),
+    }
+    match &items[2] {
+        StreamItem::DropReport(report) => {
+            assert_eq!(report.count, 1);
+            assert_eq!(report.reason, DropReason::Degraded);
+        }
+        i => panic!("Unexpected item {:?}", i),
+    }
+    match &items[3] {
+        StreamItem::Closed(CloseReason::Normal) => {}
+        i => panic!("Unexpected item {:?}", i),
+    }
+}
+
+#[test]
+fn test_recv_item_keeps_buffered_order() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            // Buffer everything before taking any of it
+            let start = std::time::Instant::now();
+            while cli.buffered() < 3 {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            let mut items = vec![];
+            while let Some(item) = cli.recv_item().expect("Failed to receive") {
+                items.push(item);
+            }
+            items
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+    server_tx.set_drop_report_interval(std::time::Duration::from_secs(0));
+
+    let ts = std::time::SystemTime::now();
+    server_tx
+        .send(&[Packet::new(ts, vec![1u8])])
+        .expect("Failed to send");
+    server_tx.record_drops(2, DropReason::Overflow);
+    server_tx
+        .send(&[Packet::new(ts, vec![2u8])])
+        .expect("Failed to send");
+    server_tx
+        .send_with_priority(&[Packet::new(ts, vec![3u8])], Priority::High)
+        .expect("Failed to send");
+    server_tx.close().expect("Failed to close");
+
+    let items = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    let received: Vec<_> = items
+        .iter()
+        .map(|item| match item {
+            StreamItem::Packets(packets) => packets.iter().map(|p| p.data()[0] as u64).collect(),
+            StreamItem::DropReport(report) => vec![100 + report.count],
+            StreamItem::Closed(CloseReason::Normal) => vec![],
+            i => panic!("Unexpected item {:?}", i),
+        })
+        .collect();
+    assert_eq!(received, vec![vec![1], vec![102], vec![3], vec![2], vec![]]);
+}
+
+#[test]
+fn test_recv_item_after_take() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let start = std::time::Instant::now();
+            while cli.buffered() < 4 {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            // Taking packets from after the drop report leaves it next
+            let taken = cli.take(4).len();
+            let mut items = vec![];
+            while let Some(item) = cli.recv_item().expect("Failed to receive") {
+                items.push(item);
+            }
+            (taken, items)
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+    server_tx.set_drop_report_interval(std::time::Duration::from_secs(0));
+
+    let ts = std::time::SystemTime::now();
+    server_tx
+        .send(&[Packet::new(ts, vec![1u8]), Packet::new(ts, vec![2u8])])
+        .expect("Failed to send");
+    server_tx.record_drops(2, DropReason::Overflow);
+    server_tx
+        .send(&[Packet::new(ts, vec![3u8]), Packet::new(ts, vec![4u8])])
+        .expect("Failed to send");
+    server_tx.close().expect("Failed to close");
+
+    let (taken, items) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(taken, 4);
+    assert_eq!(items.len(), 2);
+    assert!(matches!(&items[0], StreamItem::DropReport(report) if report.count == 2));
+    assert!(matches!(&items[1], StreamItem::Closed(CloseReason::Normal)));
+}
+
+#[test]
+fn test_stream_items() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let items: Result<Vec<_>, Error> = cli.items().collect();
+            (items, cli.close_reason().cloned())
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+
+    server_tx.heartbeat().expect("Failed to send heartbeat");
+    server_tx
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![3u8, 4u8])])
+        .expect("Failed to send");
+    server_tx.send_stats().expect("Failed to send stats");
+    server_tx
+        .close_with_reason(CloseReason::ProducerError("capture failed".to_owned()))
+        .expect("Failed to close");
+
+    let (items, close_reason) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    let items = items.expect("Failed to receive");
+
+    assert_eq!(items.len(), 4);
+    assert!(matches!(items[0], StreamItem::Heartbeat));
+    assert!(matches!(items[1], StreamItem::Packets(_)));
+    match &items[2] {
+        StreamItem::Stats(stats) => {
+            assert_eq!(stats.packets, 1);
+            assert_eq!(stats.batches, 1);
+            assert_eq!(*stats, server_tx.stats());
+        }
+        i => panic!("Unexpected item {:?}", i),
+    }
+    let reason = CloseReason::ProducerError("capture failed".to_owned());
+    match &items[3] {
+        StreamItem::Closed(r) => assert_eq!(*r, reason),
+        i => panic!("Unexpected item {:?}", i),
+    }
+    assert_eq!(close_reason, Some(reason));
+}
+
+#[test]
+fn test_stats_snapshots() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (requested_tx, requested_rx) = std::sync::mpsc::channel();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            rx.recv().expect("Server did not signal");
+            cli.request_stats().expect("Failed to request stats");
+            requested_tx.send(()).expect("Failed to signal");
+            cli.items()
+                .filter_map(|item| match item.expect("Failed to receive") {
+                    StreamItem::Snapshot(snapshot) => Some(snapshot),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let packets = vec![
+        Packet::new(std::time::SystemTime::now(), vec![1u8]),
+        Packet::new(std::time::SystemTime::now(), vec![2u8]),
+    ];
+    connection.send(&packets).expect("Failed to send");
+    connection
+        .request_peer_stats()
+        .expect("Failed to request stats");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let peer = loop {
+        if let Some(peer) = connection.peer_stats() {
+            break peer;
+        }
+        assert!(std::time::Instant::now() < deadline, "No stats from client");
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    assert_eq!(peer.stats.packets, 2);
+    assert_eq!(peer.stats.batches, 1);
+
+    tx.send(()).expect("Failed to signal");
+    requested_rx.recv().expect("Client did not request stats");
+    connection.heartbeat().expect("Failed to send heartbeat");
+    connection.close().expect("Failed to close");
+
+    let snapshots = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].stats.packets, 2);
+}
+
+#[test]
+fn test_consumer_health() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let client_thread = std::thread::spawn(move || {
+        let config = ClientConfig::default().health_interval(Some(std::time::Duration::ZERO));
+        Client::connect(server_name, config).map(|mut cli| {
+            let packets = cli
+                .recv(1)
+                .expect("Failed to receive")
+                .expect("Connection closed");
+            tx.send(()).expect("Failed to signal");
+            while cli.recv(1).expect("Failed to receive").is_some() {}
+            packets.len()
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let timestamp = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(10);
+    let packets = vec![
+        Packet::new(timestamp, vec![1u8]),
+        Packet::new(timestamp, vec![2u8]),
+    ];
+    connection.send(&packets).expect("Failed to send");
+    rx.recv().expect("Client did not receive");
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let health = loop {
+        if let Some(health) = connection.consumer_health() {
+            break health;
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "No health from client"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    assert_eq!(health.newest_processed, Some(timestamp));
+    assert!(health.lag().expect("No lag") > std::time::Duration::from_secs(10));
+
+    connection.close().expect("Failed to close");
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, 1);
+}
+
+#[test]
+fn test_record_replay() {
+    let _ = env_logger::try_init();
+
+    let path = std::env::temp_dir().join(format!("packet-ipc-{}.rec", std::process::id()));
+    let receive = |cli: &mut Client| {
+        let mut packets = vec![];
+        let mut heartbeats = 0;
+        for item in cli.items() {
+            match item.expect("Failed to receive") {
+                StreamItem::Packets(received) => {
+                    packets.extend(received.iter().map(|p| p.data().to_vec()))
+                }
+                StreamItem::Heartbeat => heartbeats += 1,
+                _ => {}
+            }
+        }
+        (packets, heartbeats)
+    };
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread =
+        std::thread::spawn(move || Client::new(server_name).map(|mut cli| receive(&mut cli)));
+    let mut connection = server.accept().expect("Failed to accept connection");
+    connection
+        .record(Recorder::create(&path).expect("Failed to create recording"))
+        .expect("Failed to start recording");
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+    connection.heartbeat().expect("Failed to send heartbeat");
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![2u8])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+    connection
+        .stop_recording()
+        .expect("Not recording")
+        .flush()
+        .expect("Failed to flush");
+    let original = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(original, (vec![vec![1u8], vec![2u8]], 1));
+
+    let replayer = Replayer::open(&path).expect("Failed to open recording");
+    assert_eq!(replayer.wire_format(), WireFormat::Bincode);
+    let (name, replay) = replayer.serve(true).expect("Failed to serve recording");
+    let mut cli = Client::new(name).expect("Failed to connect client");
+    let replayed = receive(&mut cli);
+    assert_eq!(
+        replay
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to replay"),
+        4
+    );
+    assert_eq!(replayed, original);
+    std::fs::remove_file(&path).expect("Failed to remove recording");
+}
+
+#[test]
+fn test_packet_builder() {
+    let ts = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+    let packet: Packet = Packet::builder().timestamp(ts).data(vec![1u8, 2u8]).build();
+    assert_eq!(*packet.timestamp(), ts);
+    assert_eq!(packet.data(), &[1u8, 2u8]);
+    assert_eq!(packet.orig_len(), None);
+
+    let packet: Packet = Packet::builder().data(vec![1u8]).orig_len(1500).build();
+    assert_eq!(packet.orig_len(), Some(1500));
+
+    let before = std::time::SystemTime::now();
+    let packet: Packet<Box<[u8]>> = Packet::builder().build();
+    assert!(*packet.timestamp() >= before);
+    assert!(packet.data().is_empty());
+
+    let packet: Packet<SmallData> = Packet::builder()
+        .data(SmallData::from(&[1u8, 2u8][..]))
+        .build();
+    assert_eq!(packet.data(), &[1u8, 2u8]);
+    let packet: Packet<SmallData> = Packet::builder().build();
+    assert!(packet.data().is_empty());
+    assert!(packet.into_data().is_inline());
+}
+
+#[test]
+fn test_pcap_records() {
+    let ts =
+        std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+    let packet = Packet::new(ts, vec![1u8, 2u8, 3u8]);
+
+    let header = packet.to_pcap_record(TimestampPrecision::Micros);
+    assert_eq!(
+        header,
+        PcapRecordHeader {
+            ts_sec: 1_600_000_000,
+            ts_frac: 123_456,
+            caplen: 3,
+            origlen: 3,
+        }
+    );
+    let nanos = packet.to_pcap_record(TimestampPrecision::Nanos);
+    assert_eq!(nanos.ts_frac, 123_456_789);
+
+    let truncated = PcapRecordHeader { caplen: 2, ..nanos };
+    let decoded: Packet =
+        Packet::from_pcap_record(&truncated, packet.data(), TimestampPrecision::Nanos);
+    assert_eq!(*decoded.timestamp(), ts);
+    assert_eq!(decoded.data(), &[1u8, 2u8]);
+    assert_eq!(decoded.orig_len(), Some(3));
+    assert_eq!(decoded.to_pcap_record(TimestampPrecision::Nanos), truncated);
+
+    let decoded: Packet =
+        Packet::from_pcap_record(&header, packet.data(), TimestampPrecision::Micros);
+    assert_eq!(decoded.orig_len(), None);
+    assert_eq!(
+        *decoded.timestamp(),
+        std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_000)
+    );
+}
+
+/// A little-endian, microsecond pcap file holding `packets`.
+fn pcap_file(packets: &[(u32, u32, &[u8])]) -> Vec<u8> {
+    let mut file = vec![];
+    file.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    file.extend_from_slice(&2u16.to_le_bytes());
+    file.extend_from_slice(&4u16.to_le_bytes());
+    for field in &[0u32, 0, 65535, 1] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+    for (sec, usec, data) in packets {
+        for field in &[*sec, *usec, data.len() as u32, data.len() as u32] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+        file.extend_from_slice(data);
+    }
+    file
+}
+
+#[test]
+fn test_pcap_reader() {
+    let file = pcap_file(&[(1, 2, &[1u8, 2u8]), (3, 4, &[3u8])]);
+    let reader = PcapReader::new(file.as_slice()).expect("Failed to read header");
+    assert_eq!(reader.precision(), TimestampPrecision::Micros);
+    let packets = reader
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to read packets");
+    assert_eq!(packets.len(), 2);
+    assert_eq!(
+        *packets[0].timestamp(),
+        std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1, 2_000)
+    );
+    assert_eq!(packets[0].data(), &[1u8, 2u8]);
+    assert_eq!(packets[1].data(), &[3u8]);
+
+    assert!(PcapReader::new(&file[4..]).is_err());
+    let truncated = PcapReader::new(&file[..file.len() - 1]).expect("Failed to read header");
+    assert!(truncated.collect::<Result<Vec<_>, _>>().is_err());
+
+    // A caplen beyond the snaplen of 65535 fails rather than allocating for it
+    let mut oversized = pcap_file(&[(1, 2, &[1u8])]);
+    oversized[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut reader = PcapReader::new(oversized.as_slice()).expect("Failed to read header");
+    match reader.next_packet() {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_wire_golden_samples() {
+    use packet_ipc::wire;
+
+    assert_eq!(wire::WIRE_VERSION, 2);
+    let samples = wire::sample_packets();
+    for format in [
+        WireFormat::Bincode,
+        WireFormat::NanosTimestamps,
+        WireFormat::Raw,
+    ] {
+        let encoded = wire::encode(&samples, format).expect("Failed to encode");
+        assert_eq!(
+            encoded,
+            wire::golden(format),
+            "{:?} encoding changed",
+            format
+        );
+
+        let decoded =
+            wire::decode(wire::golden(format), samples.len(), format).expect("Failed to decode");
+        assert_eq!(decoded.len(), samples.len());
+        for (decoded, sample) in decoded.iter().zip(samples.iter()) {
+            assert_eq!(decoded.timestamp(), sample.timestamp());
+            assert_eq!(decoded.data(), sample.data());
+            if format == WireFormat::Raw {
+                assert_eq!(decoded.orig_len(), sample.orig_len());
+            } else {
+                assert_eq!(decoded.orig_len(), None);
+            }
+        }
+
+        let truncated = &wire::golden(format)[..wire::golden(format).len() - 1];
+        match wire::decode(truncated, samples.len(), format) {
+            Err(Error::Decode { index: 2, .. }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    // Raw frames carry the original length after the captured length
+    let truncated = Packet::new(std::time::SystemTime::now(), vec![1u8]).with_orig_len(Some(60));
+    let encoded = wire::encode(&[truncated], WireFormat::Raw).expect("Failed to encode");
+    assert_eq!(encoded[8..16], [1, 0, 0, 0, 60, 0, 0, 0]);
+
+    // Unknown flags are rejected rather than ignored
+    let mut flagged = wire::golden(WireFormat::Raw).to_vec();
+    flagged[16] = 1;
+    match wire::decode(&flagged, samples.len(), WireFormat::Raw) {
+        Err(Error::Decode { index: 0, .. }) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+/// Packets of random length, contents and timestamp, including empty ones, from `seed`.
+fn random_packets(seed: u64, count: usize) -> Vec<Packet> {
+    let mut state = seed.max(1);
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    (0..count)
+        .map(|_| {
+            let len = match next() % 8 {
+                0 => 0,
+                1 => 1,
+                _ => (next() % 9000) as usize,
+            };
+            let ts = std::time::UNIX_EPOCH
+                + std::time::Duration::new(next() % 4_000_000_000, (next() % 1_000_000_000) as u32);
+            let data = (0..len).map(|_| next() as u8).collect();
+            Packet::new(ts, data)
+        })
+        .collect()
+}
+
+#[test]
+fn test_wire_roundtrip_random() {
+    use packet_ipc::wire;
+
+    for seed in 1..=64 {
+        let packets = random_packets(seed, (seed % 17) as usize);
+        for format in [
+            WireFormat::Bincode,
+            WireFormat::NanosTimestamps,
+            WireFormat::Raw,
+        ] {
+            let encoded = wire::encode(&packets, format).expect("Failed to encode");
+            let decoded = wire::decode(&encoded, packets.len(), format).expect("Failed to decode");
+            assert_eq!(decoded.len(), packets.len(), "seed {} {:?}", seed, format);
+            for (decoded, packet) in decoded.iter().zip(packets.iter()) {
+                assert_eq!(decoded.timestamp(), packet.timestamp(), "seed {}", seed);
+                assert_eq!(decoded.data(), packet.data(), "seed {}", seed);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_standalone_packet_encoding() {
+    use std::convert::TryFrom;
+
+    let ts = std::time::SystemTime::now();
+    let packet = Packet::new(ts, vec![1u8, 2u8, 3u8]);
+    let encoded = packet.encode_to_vec().expect("Failed to encode");
+
+    let decoded: Packet = Packet::decode(&encoded).expect("Failed to decode");
+    assert_eq!(*decoded.timestamp(), ts);
+    assert_eq!(decoded.data(), packet.data());
+
+    let decoded = Packet::<Box<[u8]>>::try_from(encoded.as_slice()).expect("Failed to decode");
+    assert_eq!(decoded.data(), packet.data());
+
+    let packet = Packet::new(ts, vec![4u8; 10])
+        .with_fingerprint(Some(0xfeed))
+        .with_interface(Some(2))
+        .with_segments(3)
+        .with_orig_len(Some(1500));
+    let decoded: Packet = Packet::decode(&packet.encode_to_vec().expect("Failed to encode"))
+        .expect("Failed to decode");
+    assert_eq!(*decoded.timestamp(), ts);
+    assert_eq!(decoded.data(), packet.data());
+    assert_eq!(decoded.fingerprint(), Some(0xfeed));
+    assert_eq!(decoded.interface(), Some(2));
+    assert_eq!(decoded.segments(), 3);
+    assert_eq!(decoded.orig_len(), Some(1500));
+
+    let mut trailing = encoded.clone();
+    trailing.push(0);
+    assert!(Packet::<Vec<u8>>::decode(&trailing).is_err());
+    assert!(Packet::<Vec<u8>>::decode(&encoded[..encoded.len() - 1]).is_err());
+}
+
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        std::thread::yield_now();
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_pump() {
+    use packet_ipc::{pump, IterSource, PcapSource};
+
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data().to_vec()));
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+
+    let file = pcap_file(&[(1, 0, &[1u8]), (2, 0, &[2u8]), (3, 0, &[3u8])]);
+    let reader = PcapReader::new(std::io::Cursor::new(file)).expect("Failed to read header");
+    let mut source = PcapSource::new(reader, 2);
+    let sent = block_on(pump(&mut source, &connection)).expect("Failed to pump");
+    assert_eq!(sent.batches, 2);
+    assert_eq!(sent.packets, 3);
+
+    let mut source = IterSource::new(vec![vec![Packet::new(
+        std::time::SystemTime::now(),
+        vec![4u8],
+    )]]);
+    let sent = block_on(pump(&mut source, &connection)).expect("Failed to pump");
+    assert_eq!(sent.packets, 1);
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, vec![vec![1u8], vec![2u8], vec![3u8], vec![4u8]]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_pcap_source_pacing() {
+    use packet_ipc::{Pacing, PacketSource, PcapSource};
+    use std::time::{Duration, Instant};
+
+    let _ = env_logger::try_init();
+
+    let file = pcap_file(&[(1, 0, &[1u8]), (2, 0, &[2u8]), (3, 0, &[3u8])]);
+    let source = |pacing| {
+        let reader =
+            PcapReader::new(std::io::Cursor::new(file.clone())).expect("Failed to read header");
+        PcapSource::new(reader, 10).with_pacing(pacing)
+    };
+    let batches = |source: &mut PcapSource<_>| {
+        let mut batches = vec![];
+        while let Some(packets) = block_on(source.next_batch()).expect("Failed to read") {
+            batches.push(packets.iter().map(|p| p.data()[0]).collect::<Vec<_>>());
+        }
+        batches
+    };
+
+    for pacing in [Pacing::Unpaced, Pacing::Speed(0.0)] {
+        let started = Instant::now();
+        assert_eq!(batches(&mut source(pacing)), vec![vec![1u8, 2, 3]]);
+        assert!(started.elapsed() < Duration::from_millis(150));
+    }
+
+    let started = Instant::now();
+    assert_eq!(
+        batches(&mut source(Pacing::Speed(10.0))),
+        vec![vec![1u8], vec![2], vec![3]]
+    );
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+    let mut seeking = source(Pacing::Unpaced);
+    seeking.control().seek(Duration::from_secs(1));
+    assert_eq!(batches(&mut seeking), vec![vec![2u8, 3]]);
+
+    let mut paused = source(Pacing::Original);
+    let control = paused.control();
+    control.pause();
+    assert!(control.is_paused());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let reader = std::thread::spawn(move || {
+        let first = block_on(paused.next_batch()).expect("Failed to read");
+        tx.send(first).expect("Failed to send");
+        batches(&mut paused)
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    control.resume();
+    let first = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("Source stayed paused")
+        .expect("Source was empty");
+    assert_eq!(
+        first.iter().map(|p| p.data()[0]).collect::<Vec<_>>(),
+        vec![1u8]
+    );
+    // Seeking interrupts the wait for the second packet
+    control.seek(Duration::from_secs(2));
+    assert_eq!(reader.join().expect("Failed to join"), vec![vec![3u8]]);
+}
+
+#[test]
+fn test_generator() {
+    use packet_ipc::aggregate::FlowKey;
+    use packet_ipc::testing::{Generator, SizeDistribution};
+
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data().to_vec()));
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+
+    let generator = Generator::new()
+        .sizes(SizeDistribution::Uniform { min: 60, max: 100 })
+        .flows(4)
+        .rate(1000.0)
+        .timestamp_skew(std::time::Duration::from_millis(5))
+        .limit(20)
+        .seed(7);
+    let started = std::time::Instant::now();
+    let sent = forward_packets(generator, &connection, ForwardConfig::default())
+        .expect("Failed to forward");
+    assert!(started.elapsed() >= std::time::Duration::from_millis(19));
+    assert_eq!(sent.packets, 20);
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received.len(), 20);
+    assert!(received.iter().all(|p| p.len() >= 60 && p.len() <= 100));
+    let flows = received
+        .iter()
+        .map(|p| FlowKey::from_ethernet(p).expect("Failed to parse frame"))
+        .collect::<std::collections::HashSet<_>>();
+    assert_eq!(flows.len(), 4);
+
+    let first = Generator::new()
+        .sizes(SizeDistribution::Uniform { min: 60, max: 1500 })
+        .seed(3);
+    let second = first.clone();
+    let sizes = |g: Generator| g.take(10).map(|p| p.data().len()).collect::<Vec<_>>();
+    assert_eq!(sizes(first), sizes(second));
+}
+
+#[test]
+fn test_shard() {
+    use packet_ipc::aggregate::FlowKey;
+    use packet_ipc::testing::Generator;
+
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let client_thread = std::thread::spawn(move || {
+        let cli = Client::new(server_name).expect("Failed to connect client");
+        let shard = Shard::by_flow(cli, 3, 100);
+        rx.recv().expect("Server did not signal");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while shard.queue_depths().iter().sum::<usize>() < 30 {
+            assert!(std::time::Instant::now() < deadline, "Packets not sharded");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let shards = (0..shard.len())
+            .map(|i| shard.receiver(i).iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        (shard.join(), shards)
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let packets = Generator::new().flows(6).limit(30).collect::<Vec<_>>();
+    connection.send(&packets).expect("Failed to send");
+    connection.close().expect("Failed to close");
+    tx.send(()).expect("Failed to signal");
+
+    let (distributed, shards) = client_thread.join().expect("Failed to join");
+    assert_eq!(distributed.expect("Failed to distribute"), 30);
+    assert_eq!(shards.iter().map(|s| s.len()).sum::<usize>(), 30);
+    let mut seen = std::collections::HashMap::new();
+    for (index, shard) in shards.iter().enumerate() {
+        for packet in shard {
+            let flow = FlowKey::from_ethernet(packet.data()).expect("Failed to parse frame");
+            assert_eq!(*seen.entry(flow).or_insert(index), index);
+        }
+    }
+    assert_eq!(seen.len(), 6);
+}
+
+#[test]
+fn test_error_sources() {
+    fn assert_composable<E: std::error::Error + Send + Sync + 'static>() {}
+    assert_composable::<Error>();
+
+    let error = match PcapReader::new(&[0u8; 24][..]) {
+        Err(e) => e,
+        Ok(_) => panic!("Should not read a header without a magic"),
+    };
+    assert!(matches!(error, Error::Io(_)));
+    assert!(std::error::Error::source(&error).is_some());
+}
+
+#[test]
+fn test_consumer_gone() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || Client::new(server_name).map(|_| ()));
+    let connection = server.accept().expect("Failed to accept connection");
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    let packets = vec![Packet::new(std::time::SystemTime::now(), vec![1u8, 2u8])];
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let error = loop {
+        if let Err(e) = connection.send(&packets) {
+            break e;
+        }
+        assert!(std::time::Instant::now() < deadline, "Send did not fail");
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    assert!(
+        matches!(error, Error::ConsumerGone),
+        "Unexpected error {:?}",
+        error
+    );
+}
+
+#[test]
+fn test_send_timings() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = 0;
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received += packets.len();
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+    assert!(connection.send_timings().is_none());
+
+    connection.instrument(Some(2));
+    let packets = vec![Packet::new(std::time::SystemTime::now(), vec![1u8; 100])];
+    for _ in 0..3 {
+        connection.send(&packets).expect("Failed to send");
+    }
+    connection.heartbeat().expect("Failed to send heartbeat");
+    let timings = connection.send_timings().expect("Not instrumented");
+    assert_eq!(timings.encode.samples, 2);
+    assert_eq!(timings.send.samples, 2);
+    assert!(timings.send.max >= timings.send.mean);
+    assert!(timings.send.total >= timings.send.max);
+
+    connection.instrument(None);
+    assert!(connection.send_timings().is_none());
+    connection.close().expect("Failed to close");
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, 3);
+}
+
+#[test]
+fn test_warmup() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data().to_vec()));
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+    connection.warmup(64 * 1024);
+    let packets = vec![
+        Packet::new(std::time::SystemTime::now(), vec![1u8; 1500]),
+        Packet::new(std::time::SystemTime::now(), vec![2u8; 100]),
+    ];
+    connection.send(&packets).expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, vec![vec![1u8; 1500], vec![2u8; 100]]);
+}
+
+#[test]
+fn test_send_borrowed() {
+    let _ = env_logger::try_init();
+
+    struct Slot<'a> {
+        ts: std::time::SystemTime,
+        data: &'a [u8],
+    }
+
+    impl<'a> AsIpcPacket for Slot<'a> {
+        fn timestamp(&self) -> &std::time::SystemTime {
+            &self.ts
+        }
+        fn data(&self) -> &[u8] {
+            self.data
+        }
+    }
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data().to_vec()));
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+
+    let ring = [1u8, 2, 3, 4, 5, 6];
+    let ts = std::time::SystemTime::now();
+    connection
+        .send_borrowed(ring.chunks(2).map(|data| Slot { ts, data }))
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, vec![vec![1u8, 2], vec![3, 4], vec![5, 6]]);
+}
+
+#[test]
+fn test_close_summary() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name)
+            .map(|mut cli| while cli.recv_item().expect("Failed to receive").is_some() {})
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let packets = vec![
+        Packet::new(std::time::SystemTime::now(), vec![1u8]),
+        Packet::new(std::time::SystemTime::now(), vec![2u8, 3u8]),
+    ];
+    connection.send(&packets).expect("Failed to send");
+    connection.record_drops(3, DropReason::Overflow);
+    std::thread::sleep(std::time::Duration::from_millis(5));
+
+    let summary = connection.close().expect("Failed to close");
+    assert_eq!(summary.packets_sent, 2);
+    assert_eq!(summary.bytes_sent, connection.stats().bytes);
+    assert_eq!(summary.drops, 3);
+    assert!(summary.duration >= std::time::Duration::from_millis(5));
+    client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+}
+
+#[test]
+fn test_recv_many() {
+    let _ = env_logger::try_init();
+
+    for packet_views in &[false, true] {
+        let server = Server::new().expect("Failed to create server");
+        let server_name = server.name().clone();
+        let config = ClientConfig::default().packet_views(*packet_views);
+        let (closed_tx, closed_rx) = crossbeam_channel::bounded::<()>(1);
+        let client_thread = std::thread::spawn(move || {
+            Client::connect(server_name, config).map(|mut cli| {
+                let _ = closed_rx.recv();
+                let mut calls = vec![];
+                while let Some(packets) = cli.recv_many(3).expect("Failed to receive") {
+                    calls.push(packets.len());
+                }
+                calls
+            })
+        });
+        let connection = server.accept().expect("Failed to accept connection");
+
+        for i in 0..5u8 {
+            let ts = std::time::SystemTime::now();
+            connection
+                .send(&[Packet::new(ts, vec![i]), Packet::new(ts, vec![i])])
+                .expect("Failed to send");
+        }
+        // Once the close is acknowledged the client has everything queued
+        let report = Shutdown::new(std::time::Duration::from_secs(5)).close(vec![connection]);
+        assert_eq!(report.flushed.len(), 1);
+        closed_tx.send(()).expect("Failed to signal client");
+
+        let calls = client_thread
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client");
+        assert_eq!(calls, vec![6, 4], "packet views {}", packet_views);
+    }
+}
+
+#[test]
+fn test_user_control() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut items = vec![];
+            while let Some(item) = cli.recv_item().expect("Failed to receive") {
+                match item {
+                    StreamItem::Packets(packets) => {
+                        items.extend(packets.iter().map(|p| p.data().to_vec()))
+                    }
+                    StreamItem::UserControl(data) => items.push(data),
+                    _ => {}
+                }
+            }
+            items
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+
+    let ts = std::time::SystemTime::now();
+    connection
+        .send(&[Packet::new(ts, vec![1u8])])
+        .expect("Failed to send");
+    connection
+        .send_user_control(b"rotate".to_vec())
+        .expect("Failed to send control");
+    connection
+        .send(&[Packet::new(ts, vec![2u8])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let items = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(items, vec![vec![1u8], b"rotate".to_vec(), vec![2u8]]);
+}
+
+#[test]
+fn test_clock_offset() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut heartbeats = 0;
+            while let Some(item) = cli.recv_item().expect("Failed to receive") {
+                if let StreamItem::Heartbeat = item {
+                    heartbeats += 1;
+                }
+            }
+            (heartbeats, cli.clock_offset())
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+    for _ in 0..3 {
+        connection.heartbeat().expect("Failed to send heartbeat");
+    }
+    connection.close().expect("Failed to close");
+
+    let (heartbeats, offset) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(heartbeats, 3);
+    // Same host, so the clocks agree up to transit time
+    let error = match offset {
+        ClockOffset::Ahead(d) | ClockOffset::Behind(d) => d,
+    };
+    assert!(error < std::time::Duration::from_secs(1), "{:?}", offset);
+
+    let ts = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+    let ahead = ClockOffset::Ahead(std::time::Duration::from_millis(5));
+    assert_eq!(
+        ahead.normalize(ts),
+        ts - std::time::Duration::from_millis(5)
+    );
+    let behind = ClockOffset::Behind(std::time::Duration::from_millis(5));
+    assert_eq!(
+        behind.normalize(ts),
+        ts + std::time::Duration::from_millis(5)
+    );
+}
+
+#[test]
+fn test_fingerprints() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.fingerprint()));
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+
+    let ts = std::time::SystemTime::now();
+    let packets = vec![
+        Packet::new(ts, vec![1u8, 2, 3, 0xff]),
+        Packet::new(ts, vec![1u8, 2, 3, 0xee]),
+        Packet::new(ts, vec![4u8]),
+    ];
+    connection.send(&packets[..1]).expect("Failed to send");
+    let fingerprint = Fingerprint::headers(3);
+    connection.fingerprint(Some(fingerprint.clone()));
+    connection.send(&packets).expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    let expected = fingerprint.compute(&[1u8, 2, 3]);
+    assert_eq!(
+        received,
+        vec![
+            None,
+            Some(expected),
+            Some(expected),
+            Some(fingerprint.compute(&[4u8]))
+        ]
+    );
+    assert_ne!(expected, fingerprint.compute(&[4u8]));
+}
+
+#[test]
+fn test_privsep() {
+    use packet_ipc::privsep::Privsep;
+
+    let _ = env_logger::try_init();
+
+    let mut command =
+        std::process::Command::new(std::env::current_exe().expect("Failed to find test binary"));
+    command
+        .args(["privsep_consumer", "--exact"])
+        .stdout(std::process::Stdio::null());
+    let (mut connection, mut child) = Privsep::new()
+        .spawn(command)
+        .expect("Failed to spawn consumer");
+    assert_eq!(connection.info().peer_pid(), Some(child.id()));
+
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![7u8; 3])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+    let status = child.wait().expect("Failed to wait for consumer");
+    assert!(status.success());
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn test_privsep_change_user_unsupported() {
+    use packet_ipc::privsep::Privsep;
+
+    let command = std::process::Command::new("true");
+    match Privsep::new().uid(1).spawn(command) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported),
+        r => panic!("Unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+/// Consumer half of `test_privsep`, which does nothing unless spawned by it.
+#[test]
+fn privsep_consumer() {
+    if std::env::var_os(packet_ipc::privsep::ENDPOINT_VAR).is_none() {
+        return;
+    }
+    let mut client = packet_ipc::privsep::connect().expect("Failed to connect to parent");
+    let mut received = vec![];
+    while let Some(packets) = client.recv(10).expect("Failed to receive") {
+        received.extend(packets.iter().map(|p| p.data().to_vec()));
+    }
+    assert_eq!(received, vec![vec![7u8; 3]]);
+}
+
+#[test]
+fn test_batch_slicing() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut batch = cli
+                .recv_batch(10)
+                .expect("Failed to receive")
+                .expect("No batch received");
+            let secs = |packets: &[std::sync::Arc<Packet>]| {
+                packets.iter().map(|p| p.data()[0]).collect::<Vec<_>>()
+            };
+            let before = secs(
+                batch.split_at_timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_secs(2)),
+            );
+            let next = secs(batch.drain_up_to(1));
+            let remaining = batch.len();
+            let rest = secs(&batch.into_vec());
+            (before, next, remaining, rest)
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let packets = (0..5u8)
+        .map(|i| {
+            let ts = std::time::UNIX_EPOCH + std::time::Duration::from_secs(u64::from(i));
+            Packet::new(ts, vec![i])
+        })
+        .collect::<Vec<_>>();
+    connection.send(&packets).expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let (before, next, remaining, rest) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(before, vec![0, 1]);
+    assert_eq!(next, vec![2]);
+    assert_eq!(remaining, 2);
+    assert_eq!(rest, vec![3, 4]);
+}
+
+#[test]
+fn test_permits() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = 0;
+            while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+                received += packets.len();
+            }
+            received
+        })
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+
+    let packet = Packet::new(std::time::SystemTime::now(), vec![1u8; 100]);
+    connection
+        .try_permit()
+        .expect("No limit set")
+        .send(&[&packet])
+        .expect("Failed to send");
+
+    connection.max_pending_bytes(Some(0));
+    assert!(connection.try_permit().is_none());
+    assert!(connection
+        .permit(std::time::Duration::from_millis(10))
+        .is_none());
+    // Waiting never outlasts the timeout, however long the poll interval
+    connection.permit_poll_interval(std::time::Duration::from_secs(3600));
+    let start = std::time::Instant::now();
+    assert!(connection
+        .permit(std::time::Duration::from_millis(10))
+        .is_none());
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    connection.permit_poll_interval(std::time::Duration::from_micros(100));
+
+    // Granted once the client has received what was sent
+    connection.max_pending_bytes(Some(1));
+    connection
+        .permit(std::time::Duration::from_secs(5))
+        .expect("Client never caught up")
+        .send(&[&packet])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received, 2);
+}
+
+#[test]
+fn test_reserve_commit() {
+    let _ = env_logger::try_init();
+
+    for wire_format in &[
+        WireFormat::Bincode,
+        WireFormat::NanosTimestamps,
+        WireFormat::Raw,
+    ] {
+        let server = Server::new()
+            .expect("Failed to create server")
+            .with_config(ServerConfig::default().wire_format(*wire_format));
+        let server_name = server.name().clone();
+
+        let client_thread = std::thread::spawn(move || {
+            Client::new(server_name).map(|mut cli| {
+                let mut received = vec![];
+                while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                    received.extend(packets.iter().map(|p| (*p.timestamp(), p.data().to_vec())));
+                }
+                received
+            })
+        });
+
+        let mut connection = server.accept().expect("Failed to accept connection");
+        let ts = std::time::SystemTime::UNIX_EPOCH
+            + std::time::Duration::new(1_600_000_000, 123_456_789);
+
+        let mut slot = connection.reserve(4).expect("Failed to reserve");
+        assert_eq!(slot.len(), 4);
+        slot.data().copy_from_slice(&[1u8, 2u8, 3u8, 4u8]);
+        slot.commit(ts, 3).expect("Failed to commit");
+
+        let mut pushed = BatchBuilder::new().wire_format(*wire_format);
+        pushed
+            .push(&Packet::new(ts, vec![5u8; 100]))
+            .expect("Failed to push");
+        let mut builder = BatchBuilder::new().wire_format(*wire_format);
+        let mut abandoned = builder.reserve(10).expect("Failed to reserve");
+        abandoned.data()[0] = 9u8;
+        drop(abandoned);
+        assert!(builder.is_empty());
+        assert_eq!(builder.encoded_len(), 0);
+        let mut slot = builder.reserve(1500).expect("Failed to reserve");
+        for byte in slot.data().iter_mut() {
+            *byte = 5u8;
+        }
+        slot.commit(ts, 100).expect("Failed to commit");
+        assert_eq!(builder.len(), 1);
+        assert_eq!(builder.encoded_len(), pushed.encoded_len());
+        builder.flush(&connection).expect("Failed to flush");
+        connection.close().expect("Failed to close");
+
+        let received = client_thread
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client");
+        assert_eq!(
+            received,
+            vec![(ts, vec![1u8, 2u8, 3u8]), (ts, vec![5u8; 100])],
+            "{:?}",
+            wire_format
+        );
+    }
+}
+
+#[test]
+fn test_reserve_commit_out_of_range() {
+    let before_epoch = std::time::SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+    // Past the last timestamp nanoseconds since the epoch fit in a u64
+    let too_late = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 35);
+    let cases: &[(WireFormat, &[std::time::SystemTime])] = &[
+        (WireFormat::Bincode, &[before_epoch]),
+        (WireFormat::NanosTimestamps, &[before_epoch, too_late]),
+        (WireFormat::Raw, &[before_epoch, too_late]),
+    ];
+    for (wire_format, timestamps) in cases {
+        for ts in timestamps.iter() {
+            let mut builder = BatchBuilder::new().wire_format(*wire_format);
+            assert!(builder.push(&Packet::new(*ts, vec![1u8])).is_err());
+            let slot = builder.reserve(4).expect("Failed to reserve");
+            assert!(slot.commit(*ts, 4).is_err(), "{:?} {:?}", wire_format, ts);
+            assert!(builder.is_empty());
+            assert_eq!(builder.encoded_len(), 0);
+
+            let mut builder = BatchBuilder::new()
+                .wire_format(*wire_format)
+                .on_encode_error(EncodeErrorPolicy::SkipPacket { log: true });
+            let slot = builder.reserve(4).expect("Failed to reserve");
+            slot.commit(*ts, 4).expect("Failed to skip");
+            assert!(builder.is_empty());
+            assert_eq!(builder.encoded_len(), 0);
+            assert_eq!(builder.skipped(), 1);
+        }
+    }
+}
+
+#[test]
+fn test_packet_views() {
+    let _ = env_logger::try_init();
+
+    for wire_format in &[WireFormat::Bincode, WireFormat::Raw] {
+        let server = Server::new()
+            .expect("Failed to create server")
+            .with_config(ServerConfig::default().wire_format(*wire_format));
+        let server_name = server.name().clone();
+
+        let client_thread = std::thread::spawn(move || {
+            let config = ClientConfig::default().packet_views(true);
+            Client::connect(server_name, config).map(|mut cli| {
+                let batch = cli
+                    .recv_views()
+                    .expect("Failed to receive")
+                    .expect("No batch");
+                assert_eq!(batch.len(), 2);
+                assert_eq!(batch.info().count, 2);
+                let viewed = batch
+                    .views()
+                    .expect("Failed to decode")
+                    .iter()
+                    .map(|view| (*view.timestamp(), view.data().to_vec()))
+                    .collect::<Vec<_>>();
+                let inline = batch
+                    .packets::<SmallData>()
+                    .expect("Failed to decode")
+                    .into_iter()
+                    .map(|p| p.into_data().is_inline())
+                    .collect::<Vec<_>>();
+                assert_eq!(inline, vec![true, false]);
+                // Batches are still decoded for other ways of receiving
+                let mut received = vec![];
+                while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                    received.extend(packets.iter().map(|p| p.data().to_vec()));
+                }
+                (viewed, received)
+            })
+        });
+
+        let mut connection = server.accept().expect("Failed to accept connection");
+        let ts = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 7);
+        connection
+            .send(&[
+                Packet::new(ts, vec![1u8, 2u8]),
+                Packet::new(ts, vec![3u8; 100]),
+            ])
+            .expect("Failed to send");
+        connection
+            .send(&[Packet::new(ts, vec![4u8])])
+            .expect("Failed to send");
+        connection.close().expect("Failed to close");
+
+        let (viewed, received) = client_thread
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to connect client");
+        assert_eq!(viewed, vec![(ts, vec![1u8, 2u8]), (ts, vec![3u8; 100])]);
+        assert_eq!(received, vec![vec![4u8]]);
+    }
+}
+
+#[test]
+fn test_held_batches() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        let pool = std::sync::Arc::new(BufferPool::new(0, 4));
+        let config = ClientConfig::default()
+            .packet_views(true)
+            .max_held_batches(Some(1))
+            .allocator(pool.clone());
+        Client::connect(server_name, config).map(|mut cli| {
+            let first = cli
+                .recv_views()
+                .expect("Failed to receive")
+                .expect("No batch");
+            assert_eq!(cli.held_batches(), 1);
+            match cli.recv_views() {
+                Err(Error::TooManyHeld(1)) => {}
+                r => panic!("Expected too many held, got {:?}", r),
+            }
+            let retained = first.retain();
+            drop(first);
+            assert_eq!(cli.held_batches(), 1);
+            assert_eq!(pool.available(), 0);
+            let data = retained.views().expect("Failed to decode")[0]
+                .data()
+                .to_vec();
+            drop(retained);
+            assert_eq!(cli.held_batches(), 0);
+            assert_eq!(pool.available(), 1);
+            let second = cli
+                .recv_views()
+                .expect("Failed to receive")
+                .expect("No batch");
+            assert_eq!(second.len(), 1);
+            data
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let ts = std::time::SystemTime::now();
+    connection
+        .send(&[Packet::new(ts, vec![1u8, 2u8])])
+        .expect("Failed to send");
+    connection
+        .send(&[Packet::new(ts, vec![3u8])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let data = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(data, vec![1u8, 2u8]);
+}
+
+#[test]
+fn test_partition_by_interface() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        let client = Client::new(server_name).expect("Failed to connect client");
+        let partitions = Partitions::by_interface(client, 16);
+        let mut received = vec![];
+        while let Some((interface, packets)) = partitions.next_partition() {
+            let data = packets.iter().map(|p| p.data()[0]).collect::<Vec<_>>();
+            received.push((interface, data));
+        }
+        (received, partitions.join().expect("Failed to partition"))
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    let ts = std::time::SystemTime::now();
+    let packets = vec![
+        Packet::new(ts, vec![0u8]),
+        Packet::new(ts, vec![1u8]).with_interface(Some(1)),
+        Packet::new(ts, vec![2u8]).with_interface(Some(2)),
+        Packet::new(ts, vec![3u8]).with_interface(Some(1)),
+        Packet::new(ts, vec![4u8]),
+    ];
+    connection.send(&packets).expect("Failed to send");
+    connection.close().expect("Failed to close");
+    drop(connection);
+
+    let (mut received, distributed) = client_thread.join().expect("Failed to join");
+    received.sort();
+    assert_eq!(
+        received,
+        vec![
+            (None, vec![0u8, 4u8]),
+            (Some(1), vec![1u8, 3u8]),
+            (Some(2), vec![2u8])
+        ]
+    );
+    assert_eq!(distributed, 5);
+}
+
+fn tcp_segment(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; 14 + 20 + 20];
+    frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+    let total_len = (40 + payload.len()) as u16;
+    let ip = &mut frame[14..34];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&total_len.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 6;
+    ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    let tcp = &mut frame[34..54];
+    tcp[0..2].copy_from_slice(&1234u16.to_be_bytes());
+    tcp[2..4].copy_from_slice(&80u16.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&7u32.to_be_bytes());
+    tcp[12] = 5 << 4;
+    tcp[13] = flags;
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[test]
+fn test_coalesce_tcp() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| (p.segments(), p.data().to_vec())));
+            }
+            received
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    connection.coalesce_tcp(Some(65535));
+    let ts = std::time::SystemTime::now();
+    let packets = vec![
+        Packet::new(ts, tcp_segment(1000, 0x10, &[1u8; 100])),
+        Packet::new(ts, tcp_segment(1100, 0x10, &[2u8; 100])),
+        Packet::new(ts, tcp_segment(1200, 0x18, &[3u8; 50])),
+        // Out of order, so sent on its own
+        Packet::new(ts, tcp_segment(2000, 0x10, &[4u8; 10])),
+        Packet::new(ts, vec![0u8; 20]),
+    ];
+    connection.send(&packets).expect("Failed to send");
+    connection.coalesce_tcp(None);
+    connection.send(&packets[..2]).expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let received = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(received.len(), 5);
+    let (segments, merged) = &received[0];
+    assert_eq!(*segments, 3);
+    assert_eq!(merged.len(), 54 + 250);
+    assert_eq!(&merged[16..18], &290u16.to_be_bytes());
+    assert_eq!(ipv4_checksum(&merged[14..34]), 0);
+    // PSH from the last segment
+    assert_eq!(merged[47], 0x18);
+    assert_eq!(&merged[54..154], &[1u8; 100][..]);
+    assert_eq!(&merged[254..], &[3u8; 50][..]);
+    let rest = received[1..]
+        .iter()
+        .map(|(segments, data)| (*segments, data.len()))
+        .collect::<Vec<_>>();
+    assert_eq!(rest, vec![(1, 64), (1, 20), (1, 154), (1, 154)]);
+}
+
+#[test]
+fn test_send_thread() {
+    let _ = env_logger::try_init();
+
+    for pipelined in &[false, true] {
+        send_thread(*pipelined);
+    }
+}
+
+fn send_thread(pipelined: bool) {
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| p.data()[0]));
+            }
+            (received, cli.dropped())
+        })
+    });
+
+    let connection = server.accept().expect("Failed to accept connection");
+    let config = SendThreadConfig::default()
+        .queue_capacity(8)
+        .max_packets(3)
+        .pin_to_cpu(0)
+        .pipelined(pipelined);
+    let sender = SendThread::new(connection, config);
+    let ts = std::time::SystemTime::now();
+    for i in 0..10u8 {
+        sender
+            .send(Packet::new(ts, vec![i]))
+            .expect("Failed to send");
+    }
+    let mut dropped = 0;
+    for i in 10..100u8 {
+        if !sender
+            .try_send(Packet::new(ts, vec![i]))
+            .expect("Failed to send")
+        {
+            dropped += 1;
+        }
+    }
+    let summary = sender.close().expect("Failed to close");
+    assert_eq!(summary.packets_sent + dropped, 100);
+    assert_eq!(summary.drops, dropped);
+
+    let (received, client_dropped) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(&received[..10], &(0..10u8).collect::<Vec<_>>()[..]);
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(received.len() as u64 + dropped, 100);
+    assert_eq!(client_dropped, dropped);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_send_thread_invalid_cpu() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        let mut cli = Client::new(server_name).expect("Failed to connect client");
+        while let Ok(Some(_)) = cli.recv(1) {}
+    });
+    let connection = server.accept().expect("Failed to accept connection");
+    let sender = SendThread::new(
+        connection,
+        SendThreadConfig::default().pin_to_cpu(usize::MAX),
+    );
+    match sender.close() {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    client_thread.join().expect("Failed to join");
+}
+
+/// Send ten single packet batches numbered in order through `faults`, returning the numbers
+/// received, why the client stopped, and whether every send succeeded.
+fn send_with_faults(faults: Faults) -> (Vec<u8>, Option<CloseReason>, bool) {
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client = std::thread::spawn(move || {
+        let mut cli =
+            Client::connect(server_name, ClientConfig::default()).expect("Failed to connect");
+        let mut received = vec![];
+        while let Some(packets) = cli.recv(1).expect("Failed to receive") {
+            received.extend(packets.iter().map(|p| p.data()[0]));
+        }
+        (received, cli.close_reason().cloned())
+    });
+    let mut connection = server.accept().expect("Failed to accept");
+    connection.inject_faults(Some(faults));
+    let mut sent = true;
+    for i in 0..10u8 {
+        let packet = Packet::new(std::time::SystemTime::now(), vec![i]);
+        if connection.send(&[packet]).is_err() {
+            sent = false;
+            break;
+        }
+    }
+    if sent {
+        connection.close().expect("Failed to close");
+    }
+    drop(connection);
+    let (received, reason) = client.join().expect("Failed to join");
+    (received, reason, sent)
+}
+
+#[test]
+fn test_fault_injection() {
+    let _ = env_logger::try_init();
+
+    let (received, reason, sent) = send_with_faults(Faults::new());
+    assert_eq!(received, (0..10).collect::<Vec<u8>>());
+    assert_eq!(reason, Some(CloseReason::Normal));
+    assert!(sent);
+
+    let (received, reason, sent) = send_with_faults(Faults::new().drop_probability(1.0));
+    assert!(received.is_empty());
+    assert_eq!(reason, Some(CloseReason::Normal));
+    assert!(sent);
+
+    let (received, _, _) = send_with_faults(Faults::new().drop_probability(0.5).seed(7));
+    assert!(!received.is_empty() && received.len() < 10);
+    assert!(received.windows(2).all(|w| w[0] < w[1]));
+
+    let (received, reason, _) = send_with_faults(Faults::new().reorder_probability(1.0));
+    assert_eq!(received, vec![1, 0, 3, 2, 5, 4, 7, 6, 9, 8]);
+    assert_eq!(reason, Some(CloseReason::Normal));
+
+    let (received, reason, sent) = send_with_faults(Faults::new().disconnect_after(4));
+    assert_eq!(received, vec![0, 1, 2, 3]);
+    assert_eq!(reason, Some(CloseReason::Disconnected));
+    assert!(!sent);
+
+    let started = std::time::Instant::now();
+    let (received, _, _) = send_with_faults(
+        Faults::new()
+            .latency(std::time::Duration::from_millis(5))
+            .jitter(std::time::Duration::from_millis(5)),
+    );
+    assert_eq!(received.len(), 10);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+}
+
+#[test]
+fn test_batch_sequence_and_checksum() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client = std::thread::spawn(move || {
+        let config = ClientConfig::default().packet_views(true);
+        let mut cli = Client::connect(server_name, config).expect("Failed to connect");
+        let mut received = vec![];
+        while let Some(batch) = cli.recv_views().expect("Failed to receive") {
+            let data = batch.views().expect("Failed to decode")[0].data()[0];
+            received.push((batch.info().sequence, data));
+        }
+        (received, cli.close_reason().cloned())
+    });
+    let mut connection = server.accept().expect("Failed to accept");
+    connection.checksum_batches(true);
+    connection.inject_faults(Some(Faults::new().drop_probability(0.5).seed(3)));
+    for i in 0..10u8 {
+        let packet = Packet::new(std::time::SystemTime::now(), vec![i; 100]);
+        connection.send(&[packet]).expect("Failed to send");
+    }
+    connection.close().expect("Failed to close");
+
+    let (received, reason) = client.join().expect("Failed to join");
+    assert_eq!(reason, Some(CloseReason::Normal));
+    assert!(!received.is_empty() && received.len() < 10);
+    // Sequences count every batch sent, so the gaps show which were lost
+    for (sequence, data) in received {
+        assert_eq!(sequence, u64::from(data));
+    }
+}
+
+/// Send ten batches through `Faults` that corrupt about half of them, returning every item the
+/// client received.
+fn send_corrupt(resync: bool) -> Vec<StreamItem> {
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client = std::thread::spawn(move || {
+        let config = ClientConfig::default().resync(resync);
+        let mut cli = Client::connect(server_name, config).expect("Failed to connect");
+        let mut items = vec![];
+        while let Some(item) = cli.recv_item().expect("Failed to receive") {
+            let closed = matches!(item, StreamItem::Closed(_));
+            items.push(item);
+            if closed {
+                break;
+            }
+        }
+        items
+    });
+    let mut connection = server.accept().expect("Failed to accept");
+    connection.checksum_batches(true);
+    connection.inject_faults(Some(Faults::new().corrupt_probability(0.5).seed(5)));
+    for i in 0..10u8 {
+        let packet = Packet::new(std::time::SystemTime::now(), vec![i; 100]);
+        if connection.send(&[packet]).is_err() {
+            break;
+        }
+    }
+    let _ = connection.close();
+    client.join().expect("Failed to join")
+}
+
+#[test]
+fn test_resync_after_corrupt_batch() {
+    let _ = env_logger::try_init();
+
+    let items = send_corrupt(true);
+    let corrupt = items
+        .iter()
+        .filter(
+            |item| matches!(item, StreamItem::Corrupt { bytes_skipped } if *bytes_skipped > 100),
+        )
+        .count();
+    let received = items
+        .iter()
+        .filter_map(|item| match item {
+            StreamItem::Packets(packets) => Some(packets.len()),
+            _ => None,
+        })
+        .sum::<usize>();
+    assert!(corrupt > 0 && corrupt < 10, "{:?}", items);
+    assert_eq!(received + corrupt, 10);
+    assert!(matches!(
+        items.last(),
+        Some(StreamItem::Closed(CloseReason::Normal))
+    ));
+
+    let items = send_corrupt(false);
+    assert!(!items
+        .iter()
+        .any(|item| matches!(item, StreamItem::Corrupt { .. })));
+    assert!(matches!(
+        items.last(),
+        Some(StreamItem::Closed(CloseReason::ReceiveError(_)))
+    ));
+}
+
+#[test]
+fn test_encode_error_policy() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client = std::thread::spawn(move || {
+        let mut cli = Client::new(server_name).expect("Failed to connect");
+        let mut items = vec![];
+        while let Some(item) = cli.recv_item().expect("Failed to receive") {
+            let closed = matches!(item, StreamItem::Closed(_));
+            items.push(item);
+            if closed {
+                break;
+            }
+        }
+        items
+    });
+    let mut connection = server.accept().expect("Failed to accept");
+    connection.set_drop_report_interval(std::time::Duration::from_secs(0));
+    // Bincode can't encode timestamps before the epoch
+    let packets = vec![
+        Packet::new(std::time::SystemTime::now(), vec![1u8]),
+        Packet::new(
+            std::time::UNIX_EPOCH - std::time::Duration::from_secs(1),
+            vec![2u8],
+        ),
+        Packet::new(std::time::SystemTime::now(), vec![3u8]),
+    ];
+    assert!(connection.send(&packets).is_err());
+    connection.on_encode_error(EncodeErrorPolicy::SkipPacket { log: true });
+    connection.send(&packets).expect("Failed to send");
+    assert_eq!(connection.stats().encode_failures, 1);
+    assert_eq!(connection.stats().drops, 1);
+    connection.close().expect("Failed to close");
+
+    let items = client.join().expect("Failed to join");
+    assert_eq!(items.len(), 3, "{:?}", items);
+    match &items[0] {
+        StreamItem::DropReport(report) => {
+            assert_eq!(report.count, 1);
+            assert_eq!(report.reason, DropReason::EncodeFailed);
+        }
+        item => panic!("Unexpected item {:?}", item),
+    }
+    match &items[1] {
+        StreamItem::Packets(packets) => {
+            let data = packets.iter().map(|p| p.data()[0]).collect::<Vec<_>>();
+            assert_eq!(data, vec![1, 3]);
+        }
+        item => panic!("Unexpected item {:?}", item),
+    }
+}
+
+#[test]
+fn test_hand_off() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let accept = std::thread::spawn(move || server.accept().expect("Failed to accept"));
+    let client = Client::new(server_name).expect("Failed to connect");
+    let mut connection = accept.join().expect("Failed to join");
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    let handle = client.hand_off(move |item| tx.send(item).is_ok());
+
+    for i in 0..3u8 {
+        connection
+            .send(&[Packet::new(std::time::SystemTime::now(), vec![i])])
+            .expect("Failed to send");
+    }
+    connection.close().expect("Failed to close");
+
+    let items = rx.iter().collect::<Vec<_>>();
+    assert_eq!(items.len(), 4, "{:?}", items);
+    for (i, item) in items[..3].iter().enumerate() {
+        assert!(matches!(item, StreamItem::Packets(p) if p[0].data() == [i as u8]));
+    }
+    assert!(matches!(items[3], StreamItem::Closed(CloseReason::Normal)));
+    let client = handle
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to receive");
+    assert_eq!(client.close_reason(), Some(&CloseReason::Normal));
+
+    // Dropping the receiving end stops the hand off
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let accept = std::thread::spawn(move || server.accept().expect("Failed to accept"));
+    let client = Client::new(server_name).expect("Failed to connect");
+    let connection = accept.join().expect("Failed to join");
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    let handle = client.hand_off(move |item| tx.send(item).is_ok());
+    drop(rx);
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![0u8])])
+        .expect("Failed to send");
+    let client = handle
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to receive");
+    assert!(client.close_reason().is_none());
+}
+
+#[test]
+fn test_stats_monitor() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client = std::thread::spawn(move || {
+        let config = ClientConfig::default().name("monitored");
+        let mut cli = Client::connect(server_name, config).expect("Failed to connect");
+        let mut received = 0;
+        while let Some(packets) = cli.recv(10).expect("Failed to receive") {
+            received += packets.len();
+        }
+        received
+    });
+    let mut connection = server.accept().expect("Failed to accept");
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let monitor =
+        StatsMonitor::with_callback(std::time::Duration::from_millis(10), move |reports| {
+            let _ = tx.send(reports.to_vec());
+        });
+    connection.monitor(&monitor);
+    assert_eq!(monitor.len(), 1);
+    for i in 0..4u8 {
+        connection
+            .send(&[Packet::new(std::time::SystemTime::now(), vec![i; 10])])
+            .expect("Failed to send");
+    }
+
+    // Reports keep coming, and add up to what was sent
+    let mut packets = 0;
+    let mut bytes = 0;
+    while packets < 4 {
+        let reports = rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("No report");
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].consumer.name(), Some("monitored"));
+        packets += reports[0].delta.packets;
+        bytes += reports[0].delta.bytes;
+        assert_eq!(reports[0].stats.packets, packets);
+    }
+    assert_eq!(packets, 4);
+    assert!(bytes >= 40);
+
+    connection.close().expect("Failed to close");
+    drop(connection);
+    assert!(monitor.is_empty());
+    assert_eq!(client.join().expect("Failed to join"), 4);
+}
+
+/// Call `maintain` until `done` is satisfied with what it returns, failing after five seconds.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn maintain_until<F: Fn(Liveness) -> bool>(connection: &ConnectedIpc, done: F) -> Liveness {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    loop {
+        let liveness = connection.maintain().expect("Failed to maintain");
+        if done(liveness) {
+            return liveness;
+        }
+        assert!(std::time::Instant::now() < deadline, "Still {:?}", liveness);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_keepalive_idle() {
+    let _ = env_logger::try_init();
+
+    let name = format!("packet-ipc-keepalive-test-{}", std::process::id());
+    let server = MultiServer::new(&name, ServerConfig::default()).expect("Failed to create server");
+    let mut client =
+        Client::connect(name, ClientConfig::default().name("quiet")).expect("Failed to connect");
+    let connection = server.accept().expect("Failed to accept");
+    assert!(matches!(
+        server.events().next(),
+        Some(ServerEvent::Connected { .. })
+    ));
+
+    assert_eq!(
+        connection.maintain().expect("Failed to maintain"),
+        Liveness::Active
+    );
+    connection.keepalive(Some(
+        Keepalive::new()
+            .heartbeat_interval(std::time::Duration::from_millis(10))
+            .miss_threshold(50)
+            .idle_timeout(Some(std::time::Duration::from_millis(50))),
+    ));
+    // Heartbeats are answered by the receive thread without the consumer taking anything
+    let liveness = maintain_until(&connection, |l| l != Liveness::Active);
+    assert!(
+        matches!(liveness, Liveness::Idle(idle) if idle >= std::time::Duration::from_millis(50))
+    );
+    let events: Vec<_> = server.events().collect();
+    assert_eq!(events.len(), 1, "{:?}", events);
+    match &events[0] {
+        ServerEvent::ClientIdle {
+            consumer, idle_for, ..
+        } => {
+            assert_eq!(consumer.name(), Some("quiet"));
+            assert!(*idle_for >= std::time::Duration::from_millis(50));
+        }
+        e => panic!("Unexpected event {:?}", e),
+    }
+
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+    assert_eq!(
+        connection.maintain().expect("Failed to maintain"),
+        Liveness::Active
+    );
+    assert_eq!(
+        client.recv(1).expect("Failed to receive").map(|p| p.len()),
+        Some(1)
+    );
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn test_keepalive_timeout() {
+    use packet_ipc::privsep::Privsep;
+
+    let _ = env_logger::try_init();
+
+    let mut command =
+        std::process::Command::new(std::env::current_exe().expect("Failed to find test binary"));
+    command
+        .args(["privsep_consumer", "--exact"])
+        .stdout(std::process::Stdio::null());
+    let (mut connection, mut child) = Privsep::new()
+        .spawn(command)
+        .expect("Failed to spawn consumer");
+    let signal = |signal: &str| {
+        let status = std::process::Command::new("kill")
+            .args([signal, &child.id().to_string()])
+            .status()
+            .expect("Failed to signal consumer");
+        assert!(status.success());
+    };
+    connection.keepalive(Some(
+        Keepalive::new()
+            .heartbeat_interval(std::time::Duration::from_millis(10))
+            .miss_threshold(3),
+    ));
+    maintain_until(&connection, |l| l == Liveness::Active);
+
+    // A stopped consumer can't answer, unlike one that is only quiet
+    signal("-STOP");
+    let liveness = maintain_until(&connection, |l| l != Liveness::Active);
+    assert!(matches!(liveness, Liveness::TimedOut(missed) if missed >= 3));
+    signal("-CONT");
+    maintain_until(&connection, |l| l == Liveness::Active);
+
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![7u8; 3])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+    let status = child.wait().expect("Failed to wait for consumer");
+    assert!(status.success());
+}
+
+#[test]
+fn test_batch_count_beyond_data() {
+    use packet_ipc::wire;
+
+    let _ = env_logger::try_init();
+
+    for format in [
+        WireFormat::Bincode,
+        WireFormat::NanosTimestamps,
+        WireFormat::Raw,
+    ] {
+        let samples = wire::sample_packets();
+        let encoded = wire::encode(&samples, format).expect("Failed to encode");
+        for count in &[samples.len() + 1, usize::MAX] {
+            match wire::decode(&encoded, *count, format) {
+                Err(Error::Decode { .. }) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+    }
+
+    // Record a batch, then claim it holds usize::MAX packets
+    let path = std::env::temp_dir().join(format!("packet-ipc-count-{}", std::process::id()));
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+    let client_thread = std::thread::spawn(move || {
+        let mut cli = Client::new(server_name).expect("Failed to connect client");
+        while cli.recv(1).expect("Failed to receive").is_some() {}
+    });
+    let mut connection = server.accept().expect("Failed to accept connection");
+    connection
+        .record(Recorder::create(&path).expect("Failed to create recording"))
+        .expect("Failed to start recording");
+    connection
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![1u8])])
+        .expect("Failed to send");
+    connection.close().expect("Failed to close");
+    connection
+        .stop_recording()
+        .expect("Not recording")
+        .flush()
+        .expect("Failed to flush");
+    client_thread.join().expect("Failed to join");
+    let mut recording = std::fs::read(&path).expect("Failed to read recording");
+    std::fs::remove_file(&path).expect("Failed to remove recording");
+    // Recording header, entry offset, message tag, then the batch header's version and flags
+    let count = 8 + 12 + 4 + 1 + 4;
+    assert_eq!(recording[count..count + 8], 1u64.to_le_bytes());
+    recording[count..count + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+    for packet_views in &[false, true] {
+        let replayer = Replayer::new(std::io::Cursor::new(recording.clone()))
+            .expect("Failed to open recording");
+        let (name, replay) = replayer.serve(false).expect("Failed to serve recording");
+        let config = ClientConfig::default().packet_views(*packet_views);
+        let mut cli = Client::connect(name, config).expect("Failed to connect client");
+        if *packet_views {
+            let batch = cli
+                .recv_views()
+                .expect("Failed to receive")
+                .expect("Closed early");
+            assert!(matches!(batch.views(), Err(Error::Decode { .. })));
+        } else {
+            let mut items = vec![];
+            while let Some(item) = cli.recv_item().expect("Failed to receive") {
+                items.push(item);
+            }
+            assert!(
+                matches!(
+                    items.last(),
+                    Some(StreamItem::Closed(CloseReason::ReceiveError(_)))
+                ),
+                "{:?}",
+                items
+            );
+        }
+        replay
+            .join()
+            .expect("Failed to join")
+            .expect("Failed to replay");
+    }
+}
+
+#[test]
+fn test_filter_packets() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let packets: Result<Vec<_>, Error> = cli.items().filter_packets().collect();
+            packets
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+
+    server_tx.heartbeat().expect("Failed to send heartbeat");
+    server_tx
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![3u8])])
+        .expect("Failed to send");
+    server_tx.heartbeat().expect("Failed to send heartbeat");
+    server_tx
+        .send(&[Packet::new(std::time::SystemTime::now(), vec![4u8])])
+        .expect("Failed to send");
+    server_tx.close().expect("Failed to close");
+
+    let batches = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to receive");
+
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0][0].data()[0], 3u8);
+    assert_eq!(batches[1][0].data()[0], 4u8);
+}
+
+#[test]
+fn test_dedup() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let packets: Result<Vec<_>, Error> = cli.items().filter_packets().collect();
+            packets
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+    server_tx.set_dedup(Some(Deduplicator::new(
+        8,
+        std::time::Duration::from_secs(1),
+    )));
+
+    let ts = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1500);
+    server_tx
+        .send(&[
+            Packet::new(ts, vec![3u8]),
+            Packet::new(ts, vec![3u8]),
+            Packet::new(ts, vec![4u8]),
+        ])
+        .expect("Failed to send");
+    server_tx
+        .send(&[Packet::new(ts, vec![3u8])])
+        .expect("Failed to send");
+    server_tx.close().expect("Failed to close");
+
+    assert_eq!(server_tx.stats().duplicates, 2);
+    assert_eq!(server_tx.stats().packets, 2);
+
+    let batches = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to receive");
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].len(), 2);
+}
+
+fn tcp_frame(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    let mut ip = vec![
+        0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+    ];
+    let total_len = (ip.len() + 20 + payload.len()) as u16;
+    ip[2..4].copy_from_slice(&total_len.to_be_bytes());
+    frame.extend_from_slice(&ip);
+    let mut tcp = vec![0u8; 20];
+    tcp[0..2].copy_from_slice(&src_port.to_be_bytes());
+    tcp[2..4].copy_from_slice(&dst_port.to_be_bytes());
+    tcp[12] = 0x50;
+    frame.extend_from_slice(&tcp);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn test_flow_aggregation() {
+    let _ = env_logger::try_init();
+
+    let mut aggregator = FlowAggregator::new();
+    let now = std::time::SystemTime::now();
+    assert!(aggregator.add(&Packet::new(now, tcp_frame(1234, 80, &[1u8; 10]))));
+    assert!(aggregator.add(&Packet::new(now, tcp_frame(1234, 80, &[1u8; 20]))));
+    assert!(aggregator.add(&Packet::new(now, tcp_frame(4321, 443, &[]))));
+    assert!(!aggregator.add(&Packet::new(now, vec![0u8; 14])));
+    assert_eq!(aggregator.len(), 2);
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let items: Result<Vec<_>, Error> = cli.items().collect();
+            items
+        })
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+    server_tx
+        .send_flows(aggregator.take_all())
+        .expect("Failed to send flows");
+    server_tx.close().expect("Failed to close");
+    assert!(aggregator.is_empty());
+
+    let items = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to receive");
+    let mut flows = match &items[0] {
+        StreamItem::Flows(flows) => flows.clone(),
+        i => panic!("Unexpected item {:?}", i),
+    };
+    flows.sort_by_key(|f| f.key.src_port);
+    assert_eq!(flows.len(), 2);
+    assert_eq!(flows[0].key.src_port, 1234);
+    assert_eq!(flows[0].key.dst_port, 80);
+    assert_eq!(flows[0].key.protocol, 6);
+    assert_eq!(flows[0].key.src, std::net::IpAddr::from([10, 0, 0, 1]));
+    assert_eq!(flows[0].packets, 2);
+    assert_eq!(flows[0].bytes, 2 * 54 + 30);
+    assert_eq!(flows[1].packets, 1);
+}
+
+#[test]
+fn test_wire_format_negotiation() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new()
+        .expect("Failed to create server")
+        .with_config(ServerConfig::default().wire_format(WireFormat::Bincode));
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || Client::new(server_name));
+
+    let server_tx = server.accept().expect("Failed to accept connection");
+    let client = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+
+    assert_eq!(server_tx.info().wire_format(), WireFormat::Bincode);
+    assert_eq!(client.info().wire_format(), WireFormat::Bincode);
+
+    let server = Server::new()
+        .expect("Failed to create server")
+        .with_config(ServerConfig::default().wire_format(WireFormat::Bincode));
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::connect(server_name, ClientConfig::default().wire_formats(vec![]))
+    });
+
+    match server.accept() {
+        Err(Error::Rejected(RejectReason::WireFormat(_))) => {}
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Connection should be refused"),
+    }
+    match client_thread.join().expect("Failed to join") {
+        Err(Error::Rejected(RejectReason::WireFormat(offered))) => assert!(offered.is_empty()),
+        Err(e) => panic!("Unexpected error {:?}", e),
+        Ok(_) => panic!("Connection should be refused"),
+    }
+}
+
+#[test]
+fn test_nanos_timestamps() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new()
+        .expect("Failed to create server")
+        .with_config(ServerConfig::default().wire_format(WireFormat::NanosTimestamps));
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let format = cli.info().wire_format();
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| (*p.timestamp(), p.data().to_vec())));
+            }
+            (format, received)
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    assert_eq!(connection.info().wire_format(), WireFormat::NanosTimestamps);
+    let ts =
+        std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+    connection
+        .send(&[Packet::new(ts, vec![1u8, 2u8])])
+        .expect("Failed to send");
+
+    let mut builder = BatchBuilder::new();
+    builder
+        .push(&Packet::new(ts, vec![3u8]))
+        .expect("Failed to push");
+    match builder.flush(&connection) {
+        Err(Error::WireFormatMismatch { batch, connection }) => {
+            assert_eq!(batch, WireFormat::Bincode);
+            assert_eq!(connection, WireFormat::NanosTimestamps);
+        }
+        r => panic!("Unexpected result {:?}", r),
+    }
+    let mut builder = BatchBuilder::new().wire_format(WireFormat::NanosTimestamps);
+    builder
+        .push(&Packet::new(ts, vec![4u8]))
+        .expect("Failed to push");
+    builder.flush(&connection).expect("Failed to flush");
+    connection.close().expect("Failed to close");
+
+    let (format, received) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(format, WireFormat::NanosTimestamps);
+    assert_eq!(received, vec![(ts, vec![1u8, 2u8]), (ts, vec![4u8])]);
+}
+
+#[test]
+fn test_raw_wire_format() {
+    let _ = env_logger::try_init();
+
+    let server = Server::new()
+        .expect("Failed to create server")
+        .with_config(ServerConfig::default().wire_format(WireFormat::Raw));
+    let server_name = server.name().clone();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let format = cli.info().wire_format();
+            let mut received = vec![];
+            while let Some(packets) = cli.recv(100).expect("Failed to receive") {
+                received.extend(packets.iter().map(|p| (*p.timestamp(), p.data().to_vec())));
+            }
+            (format, received)
+        })
+    });
+
+    let mut connection = server.accept().expect("Failed to accept connection");
+    assert_eq!(connection.info().wire_format(), WireFormat::Raw);
+    let ts =
+        std::time::SystemTime::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+    let packets = vec![
+        Packet::new(ts, vec![1u8, 2u8]),
+        Packet::new(ts, vec![]),
+        Packet::new(ts, vec![3u8; 1500]),
+    ];
+
+    let mut builder = BatchBuilder::new().wire_format(WireFormat::Raw);
+    for packet in &packets {
+        builder.push(packet).expect("Failed to push");
+    }
+    assert_eq!(builder.encoded_len(), 3 * 20 + 2 + 1500);
+    builder.flush(&connection).expect("Failed to flush");
+    connection.send(&packets).expect("Failed to send");
+    connection.close().expect("Failed to close");
+
+    let (format, received) = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client");
+    assert_eq!(format, WireFormat::Raw);
+    let expected = packets
+        .iter()
+        .map(|p| (*p.timestamp(), p.data().to_vec()))
+        .collect::<Vec<_>>();
+    assert_eq!(received.len(), 6);
+    assert_eq!(received[..3], expected[..]);
+    assert_eq!(received[3..], expected[..]);
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_client_receive() {
+    use packet_ipc::capi::*;
+
+    let _ = env_logger::try_init();
+
+    let server = Server::new().expect("Failed to create server");
+    let server_name = std::ffi::CString::new(server.name().clone()).unwrap();
+
+    let client_thread = std::thread::spawn(move || {
+        let client = unsafe { packet_ipc_client_new(server_name.as_ptr()) };
+        assert!(!client.is_null());
+
+        let mut packets = [PacketIpcPacket::default(); 4];
+        let mut buffer = [0u8; 3];
+        let mut received = 0;
+        let mut results = vec![];
+        loop {
+            let res = unsafe {
+                packet_ipc_client_recv(
+                    client,
+                    packets.as_mut_ptr(),
+                    packets.len(),
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut received,
+                )
+            };
+            if res != PACKET_IPC_OK {
+                assert_eq!(res, PACKET_IPC_CLOSED);
+                break;
+            }
+            for p in &packets[..received] {
+                results.push((
+                    p.ts_sec,
+                    buffer[p.offset..p.offset + p.len as usize].to_vec(),
+                ));
+            }
+        }
+        unsafe { packet_ipc_client_free(client) };
+        results
+    });
+
+    let mut server_tx = server.accept().expect("Failed to accept connection");
+    let ts = std::time::UNIX_EPOCH + std::time::Duration::from_secs(10);
+    server_tx
+        .send(&[
+            Packet::new(ts, vec![1u8, 2u8]),
+            Packet::new(ts, vec![3u8, 4u8]),
+            Packet::new(ts, vec![5u8]),
+        ])
+        .expect("Failed to send");
+    server_tx.close().expect("Failed to close");
+
+    let results = client_thread.join().expect("Failed to join");
+    assert_eq!(
+        results,
+        vec![(10, vec![1u8, 2u8]), (10, vec![3u8, 4u8]), (10, vec![5u8]),]
+    );
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_server_send() {
+    use packet_ipc::capi::*;
+
+    let _ = env_logger::try_init();
+
+    let server = packet_ipc_server_new();
+    assert!(!server.is_null());
+    let server_name = unsafe { std::ffi::CStr::from_ptr(packet_ipc_server_name(server)) }
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let client_thread = std::thread::spawn(move || {
+        Client::new(server_name).map(|mut cli| {
+            let packets: Result<Vec<_>, Error> = cli.items().filter_packets().collect();
+            packets
+        })
+    });
+
+    let connection = unsafe { packet_ipc_server_accept(server) };
+    assert!(!connection.is_null());
+
+    let data = [[1u8, 2u8], [3u8, 4u8]];
+    let packets: Vec<_> = data
+        .iter()
+        .map(|d| PacketIpcSendPacket {
+            ts_sec: 10,
+            ts_nsec: 5,
+            data: d.as_ptr(),
+            len: d.len(),
+        })
+        .collect();
+    let out_of_range = [
+        PacketIpcSendPacket {
+            ts_sec: u64::MAX,
+            ..packets[0]
+        },
+        PacketIpcSendPacket {
+            ts_nsec: 1_000_000_000,
+            ..packets[0]
+        },
+    ];
+    unsafe {
+        for packet in &out_of_range {
+            assert_eq!(
+                packet_ipc_send(connection, packet, 1),
+                PACKET_IPC_INVALID_ARGUMENT
+            );
+        }
+        assert_eq!(
+            packet_ipc_send(connection, packets.as_ptr(), packets.len()),
+            PACKET_IPC_OK
+        );
+        assert_eq!(packet_ipc_close(connection), PACKET_IPC_OK);
+        packet_ipc_connection_free(connection);
+    }
+
+    let batches = client_thread
+        .join()
+        .expect("Failed to join")
+        .expect("Failed to connect client")
+        .expect("Failed to receive");
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0][0].data(), &[1u8, 2u8]);
+    assert_eq!(batches[0][1].data(), &[3u8, 4u8]);
+    assert_eq!(
+        *batches[0][0].timestamp(),
+        std::time::UNIX_EPOCH + std::time::Duration::new(10, 5)
+    );
+}